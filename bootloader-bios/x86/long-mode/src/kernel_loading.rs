@@ -31,7 +31,8 @@ struct Elf64ProgramHeader {
 const PT_LOAD: u32 = 1;
 
 /// Manually parse and load an ELF64 binary from kbuf into memory.
-/// Returns the entry point virtual address, or None if the ELF is invalid.
+/// Segments go to their physical (load) address.
+/// Returns the entry point's physical address, or None if the ELF is invalid.
 pub unsafe fn load_elf64(kbuf: &[u8]) -> Option<u64> {
     if kbuf.len() < core::mem::size_of::<Elf64Header>() {
         return None;
@@ -63,6 +64,7 @@ pub unsafe fn load_elf64(kbuf: &[u8]) -> Option<u64> {
     let phentsize = ehdr.e_phentsize as usize;
     let phnum    = ehdr.e_phnum    as usize;
     let entry    = ehdr.e_entry;
+    let mut pentry = None;
 
     // Sanity check program header table is within kbuf
     let ph_table_end = phoff + phentsize * phnum;
@@ -81,6 +83,7 @@ pub unsafe fn load_elf64(kbuf: &[u8]) -> Option<u64> {
         let filesz = ph.p_filesz as usize;
         let memsz  = ph.p_memsz  as usize;
         let vaddr  = ph.p_vaddr  as usize;
+        let paddr  = ph.p_paddr  as usize;
         let offset = ph.p_offset as usize;
 
         if offset + filesz > kbuf.len() {
@@ -88,7 +91,11 @@ pub unsafe fn load_elf64(kbuf: &[u8]) -> Option<u64> {
         }
 
         let src  = base.add(offset);
-        let dest = vaddr as *mut u8;
+        let dest = paddr as *mut u8;
+
+        if entry as usize >= vaddr && (entry as usize) < vaddr + memsz {
+            pentry = Some((paddr + (entry as usize - vaddr)) as u64);
+        }
 
         if filesz > 0 {
             core::ptr::copy_nonoverlapping(src, dest, filesz);
@@ -100,5 +107,5 @@ pub unsafe fn load_elf64(kbuf: &[u8]) -> Option<u64> {
         }
     }
 
-    Some(entry)
+    pentry
 }
\ No newline at end of file
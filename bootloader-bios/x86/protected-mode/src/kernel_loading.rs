@@ -31,7 +31,8 @@ struct Elf32ProgramHeader {
 const PT_LOAD: u32 = 1;
 
 /// Manually parse and load an ELF32 binary from kbuf into memory.
-/// Returns the entry point virtual address, or None if the ELF is invalid.
+/// Segments go to their physical (load) address.
+/// Returns the entry point's physical address, or None if the ELF is invalid.
 pub unsafe fn load_elf32(kbuf: &[u8]) -> Option<u32> {
     // Need at least enough bytes for the ELF header
     if kbuf.len() < core::mem::size_of::<Elf32Header>() {
@@ -66,6 +67,7 @@ pub unsafe fn load_elf32(kbuf: &[u8]) -> Option<u32> {
     let phentsize = ehdr.e_phentsize as usize;
     let phnum = ehdr.e_phnum as usize;
     let entry = ehdr.e_entry;
+    let mut pentry = None;
 
     // Sanity check program header table is within kbuf
     let ph_table_end = phoff + phentsize * phnum;
@@ -85,6 +87,7 @@ pub unsafe fn load_elf32(kbuf: &[u8]) -> Option<u32> {
         let filesz = ph.p_filesz as usize;
         let memsz = ph.p_memsz as usize;
         let vaddr = ph.p_vaddr as usize;
+        let paddr = ph.p_paddr as usize;
         let offset = ph.p_offset as usize;
 
         // Sanity check: segment data must be within kbuf
@@ -93,7 +96,11 @@ pub unsafe fn load_elf32(kbuf: &[u8]) -> Option<u32> {
         }
 
         let src = base.add(offset);
-        let dest = vaddr as *mut u8;
+        let dest = paddr as *mut u8;
+
+        if entry as usize >= vaddr && (entry as usize) < vaddr + memsz {
+            pentry = Some((paddr + (entry as usize - vaddr)) as u32);
+        }
 
         // Copy initialized data from file
         if filesz > 0 {
@@ -106,5 +113,5 @@ pub unsafe fn load_elf32(kbuf: &[u8]) -> Option<u32> {
         }
     }
 
-    Some(entry)
+    pentry
 }
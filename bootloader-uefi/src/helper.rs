@@ -160,6 +160,66 @@ pub(crate) fn exit_boot_services() -> Result<(*mut MemoryDescriptor, usize, usiz
     }
 }
 
+/// Allocates `pages` pages of loader data anywhere in memory, starting at a multiple of `align` (a power of two, at
+/// least a page).
+pub(crate) fn allocate_aligned(pages: usize, align: u64) -> Result<efi::PhysicalAddress, Status> {
+    let boot_services = unsafe { &mut *(&mut *SYS_TBL.load(Ordering::Acquire)).boot_services };
+
+    // Ask for enough extra to be able to line up, then give the extra back.
+    let slack = (align / 0x1000 - 1) as usize;
+    let mut addr = 0;
+    unsafe {
+        let r = (boot_services.allocate_pages)(
+            efi::ALLOCATE_ANY_PAGES,
+            efi::LOADER_DATA,
+            pages + slack,
+            &mut addr,
+        );
+        if r.is_error() {
+            return Err(r);
+        }
+
+        let aligned = (addr + align - 1) & !(align - 1);
+        let head = ((aligned - addr) / 0x1000) as usize;
+        if head > 0 {
+            (boot_services.free_pages)(addr, head);
+        }
+        if slack > head {
+            (boot_services.free_pages)(aligned + pages as u64 * 0x1000, slack - head);
+        }
+        Ok(aligned)
+    }
+}
+
+/// Gets a random number from the firmware's RNG protocol, if it has one.
+#[cfg(feature = "kaslr")]
+pub(crate) fn random_u64() -> Option<u64> {
+    let boot_services = unsafe { &mut *(&mut *SYS_TBL.load(Ordering::Acquire)).boot_services };
+
+    let mut rng: *mut protocols::rng::Protocol = null_mut();
+    let mut value = 0u64;
+    unsafe {
+        let r = (boot_services.locate_protocol)(
+            &protocols::rng::PROTOCOL_GUID as *const _ as *mut _,
+            null_mut(),
+            &mut rng as *mut _ as *mut _,
+        );
+        if r.is_error() || rng.is_null() {
+            return None;
+        }
+        let r = ((&mut *rng).get_rng)(
+            rng,
+            null_mut(),
+            size_of::<u64>(),
+            &mut value as *mut _ as *mut u8,
+        );
+        if r.is_error() {
+            return None;
+        }
+    }
+    Some(value)
+}
+
 pub(crate) fn load_file(
     mut directory: *mut protocols::file::Protocol,
     path: *mut u16,
@@ -13,6 +13,9 @@ use r_efi::{
     protocols, system,
 };
 
+/// A PIE kernel is put on (and slid by) multiples of this, so it can map itself with 2 MiB pages.
+const KERNEL_ALIGN: u64 = 0x20_0000;
+
 /// UEFI Executable Entry Point
 #[unsafe(no_mangle)]
 pub extern "C" fn efi_main(h: Handle, st: *mut SystemTable) -> Status {
@@ -155,7 +158,52 @@ pub extern "C" fn efi_main(h: Handle, st: *mut SystemTable) -> Status {
         let elf_kernel = ElfBytes::<AnyEndian>::minimal_parse(kbuf).unwrap();
         let kernel_ventry = elf_kernel.ehdr.e_entry;
         let mut kernel_pentry = 0;
-        for ph in elf_kernel.segments().unwrap() {
+        let segments = elf_kernel.segments().unwrap();
+
+        // A PIE kernel goes wherever there's room; anything else has to go exactly where it asks to be.
+        let relocatable = elf_kernel.ehdr.e_type == elf::abi::ET_DYN
+            && elf_kernel.ehdr.e_machine == elf::abi::EM_X86_64;
+        let (kernel_vstart, kernel_vend) = segments
+            .iter()
+            .filter(|ph| ph.p_type == elf::abi::PT_LOAD)
+            .fold((u64::MAX, 0), |(start, end), ph| {
+                (
+                    start.min(ph.p_vaddr & !0xFFF),
+                    end.max(ph.p_vaddr + ph.p_memsz),
+                )
+            });
+        // Where the first page of a PIE kernel goes.
+        let mut kernel_pbase = 0;
+        if relocatable {
+            let kernel_size = kernel_vend - kernel_vstart;
+            kernel_pbase = match helper::allocate_aligned(
+                kernel_size.div_ceil(0x1000) as usize,
+                KERNEL_ALIGN,
+            ) {
+                Ok(addr) => addr,
+                Err(status) => {
+                    uefi_println!(
+                        "no room for the kernel: 0x{kernel_size:X} bytes on a 2 MiB boundary ({status:?})"
+                    );
+                    (boot_services.stall)(2_000_000);
+                    return status;
+                }
+            };
+        }
+        // Any 2 MiB step that keeps the kernel inside the 1 GiB it was linked in; the kernel maps no more.
+        #[cfg(feature = "kaslr")]
+        let slide = match helper::random_u64() {
+            Some(random) if relocatable => {
+                let window_end = (kernel_vstart | 0x3FFF_FFFF) + 1;
+                let steps = (window_end - kernel_vend) / KERNEL_ALIGN + 1;
+                random % steps * KERNEL_ALIGN
+            }
+            _ => 0,
+        };
+        #[cfg(not(feature = "kaslr"))]
+        let slide = 0u64;
+
+        for ph in segments {
             if ph.p_type == elf::abi::PT_LOAD {
                 let seg_va = ph.p_vaddr;
                 let seg_memsz = ph.p_memsz;
@@ -163,28 +211,37 @@ pub extern "C" fn efi_main(h: Handle, st: *mut SystemTable) -> Status {
                 let seg_offset = ph.p_offset;
                 let page_count = (seg_memsz + 0xFFF) / 0x1000;
 
-                let mut addr = seg_va as efi::PhysicalAddress;
-                let status = (boot_services.allocate_pages)(
-                    ALLOCATE_ADDRESS,
-                    LOADER_DATA,
-                    page_count as usize,
-                    &mut addr,
-                );
-                if status != Status::SUCCESS {
-                    uefi_println!("kernel put in memory failed 0x{:016X}", status.as_usize());
-                    uefi_println!(
-                        "This usually happens when the bootloader couldn't get the needed space for the kernel to load into."
+                let addr = if relocatable {
+                    kernel_pbase + seg_va - kernel_vstart
+                } else {
+                    // Load where the segment wants to live physically; the kernel maps itself higher up.
+                    let mut addr = ph.p_paddr as efi::PhysicalAddress;
+                    let status = (boot_services.allocate_pages)(
+                        ALLOCATE_ADDRESS,
+                        LOADER_DATA,
+                        page_count as usize,
+                        &mut addr,
                     );
-                    if addr & 0xFFF != 0 {
+                    if status != Status::SUCCESS {
                         uefi_println!(
-                            "Ohhhh just kidding.  The address is misaligned. 0x{addr:016X}"
+                            "kernel put in memory failed: {page_count} pages at 0x{:016X} ({status:?})",
+                            ph.p_paddr
                         );
-                        uefi_println!("Waiting longer...");
-                        (boot_services.stall)(8_000_000);
+                        uefi_println!(
+                            "The firmware already uses that memory.  A kernel built as a PIE can be loaded anywhere."
+                        );
+                        if addr & 0xFFF != 0 {
+                            uefi_println!(
+                                "Ohhhh just kidding.  The address is misaligned. 0x{addr:016X}"
+                            );
+                            uefi_println!("Waiting longer...");
+                            (boot_services.stall)(8_000_000);
+                        }
+                        (boot_services.stall)(2_000_000);
+                        return status;
                     }
-                    (boot_services.stall)(2_000_000);
-                    return status;
-                }
+                    addr
+                };
 
                 if kernel_ventry >= seg_va && kernel_ventry < seg_va + seg_memsz {
                     kernel_pentry = (addr + kernel_ventry - seg_va) as usize;
@@ -203,6 +260,30 @@ pub extern "C" fn efi_main(h: Handle, st: *mut SystemTable) -> Status {
             }
         }
 
+        // Everything holding an absolute address gets moved along with the kernel.
+        if relocatable {
+            let relas = elf_kernel
+                .section_headers()
+                .into_iter()
+                .flatten()
+                .filter(|shdr| shdr.sh_type == elf::abi::SHT_RELA);
+            for shdr in relas {
+                for rela in elf_kernel.section_data_as_relas(&shdr).unwrap() {
+                    if rela.r_type != elf::abi::R_X86_64_RELATIVE {
+                        uefi_println!("unsupported kernel relocation type {}", rela.r_type);
+                        (boot_services.stall)(2_000_000);
+                        return Status::LOAD_ERROR;
+                    }
+                    let target = (kernel_pbase + rela.r_offset - kernel_vstart) as *mut u64;
+                    target.write_unaligned((rela.r_addend as u64).wrapping_add(slide));
+                }
+            }
+            uefi_println!(
+                "kernel at 0x{kernel_pbase:016X}, running at 0x{:016X}",
+                kernel_vstart + slide
+            );
+        }
+
         let mut boot_info_ptr: *mut HTMOSBootInformation = null_mut();
         let status = (boot_services.allocate_pool)(
             LOADER_DATA,
@@ -1 +1 @@
-cargo build --release --target x86_64-unknown-none
\ No newline at end of file
+cargo build --release --target x86_64-unknown-none "$@"
\ No newline at end of file
@@ -0,0 +1,358 @@
+//! The native system calls, and running the programs that make them.
+//!
+//! `run` loads a flat binary (entered at its first byte) into memory of its own and runs it in ring 3 until it exits.
+//! From there, the kernel is only reachable through a system call:
+//!
+//! - x86_64: `syscall`, with the number in RAX and the arguments in RDI, RSI, RDX, R10 and R8.  RCX and R11 are
+//!   clobbered.
+//! - x86: `int 0x80`, with the number in EAX and the arguments in EBX, ECX, EDX, ESI and EDI.
+//!
+//! The result comes back in RAX/EAX, negative for an `Error`.  The calls are:
+//!
+//! | #   | Call    | Arguments   | Result                                                              |
+//! | --- | ------- | ----------- | ------------------------------------------------------------------- |
+//! | 0   | `write` | `buf`, `len` | Bytes written to the console                                       |
+//! | 1   | `read`  | `buf`, `len` | Bytes of typed UTF-8 read, 0 if nothing was typed (doesn't block)  |
+//! | 2   | `alloc` | `size`       | Address of `size` bytes (rounded up to pages) of zeroed memory     |
+//! | 3   | `exit`  | `status`     | Doesn't return; `run` returns `Exit::Status(status)`               |
+//! | 4   | `time`  | `out`        | 0, with the nanoseconds since boot written to `out` as a `u64`     |
+//!
+//! Every pointer a program passes has to be to memory the kernel gave it (its image, its stack, or `alloc`); anything
+//! else is `Error::BadPointer`, checked before the kernel touches it.  An exception in user code ends the program
+//! (`Exit::Fault`) instead of the kernel.
+//!
+//! Only one program runs at a time, on the BSP.  Nothing but the self-tests runs one yet, so `run` is only built with
+//! them.
+
+use crate::{arch, frame_alloc, print, sync::SpinLock};
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const WRITE: usize = 0;
+pub const READ: usize = 1;
+pub const ALLOC: usize = 2;
+pub const EXIT: usize = 3;
+pub const TIME: usize = 4;
+
+type Call = fn(&[usize; 5]) -> Result<usize, Error>;
+
+/// The calls, by number.
+const TABLE: [Call; 5] = {
+    let mut table: [Call; 5] = [write; 5];
+    table[WRITE] = write;
+    table[READ] = read;
+    table[ALLOC] = alloc;
+    table[EXIT] = exit;
+    table[TIME] = time;
+    table
+};
+
+/// Where programs' memory goes on x86_64: a PML4 slot of its own, clear of anything the kernel mapped low.
+#[cfg(all(feature = "selftest", target_arch = "x86_64"))]
+const USER_BASE: usize = 0x0000_4000_0000_0000;
+#[cfg(target_arch = "x86_64")]
+const USER_END: usize = 0x0000_8000_0000_0000;
+/// Without paging, a program's memory is wherever its frames are.
+#[cfg(all(feature = "selftest", target_arch = "x86"))]
+const USER_BASE: usize = 0;
+
+#[cfg(feature = "selftest")]
+const STACK_SIZE: usize = 64 * 1024;
+/// The most `write` prints at once.
+const MAX_WRITE: usize = 64 * 1024;
+
+/// Why a call failed.  Returned as its (negative) value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(isize)]
+pub enum Error {
+    NoSuchCall = -1,
+    BadPointer = -2,
+    InvalidArgument = -3,
+    NoMemory = -4,
+    Unsupported = -5,
+}
+impl Error {
+    pub const fn message(self) -> &'static str {
+        match self {
+            Self::NoSuchCall => "no such system call",
+            Self::BadPointer => "pointer outside the program's memory",
+            Self::InvalidArgument => "invalid argument",
+            Self::NoMemory => "out of memory",
+            Self::Unsupported => "not supported here",
+        }
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// How a program ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// It called `exit`.
+    Status(isize),
+    /// It took exception `vector` at `at`.
+    Fault { vector: u8, at: usize },
+}
+
+/// Memory the kernel gave the running program, whole pages.
+struct Region {
+    start: usize,
+    size: usize,
+}
+impl Region {
+    /// Maps `size` bytes of zeroed memory for ring 3, at `next` (which moves past it).
+    #[cfg(target_arch = "x86_64")]
+    fn new(next: &mut usize, size: usize) -> Result<Self, Error> {
+        use crate::vmm::{self, PageTableFlags as F};
+
+        let size = size.next_multiple_of(frame_alloc::FRAME_SIZE);
+        if size == 0 || USER_END - *next < size {
+            return Err(Error::NoMemory);
+        }
+        let mut region = Self {
+            start: *next,
+            size: 0,
+        };
+        while region.size < size {
+            let Some(frame) = frame_alloc::alloc_frame() else {
+                region.free();
+                return Err(Error::NoMemory);
+            };
+            // SAFETY: the frame was free, so nothing else is using it.
+            unsafe {
+                core::ptr::write_bytes(
+                    vmm::phys_to_virt(frame) as *mut u8,
+                    0,
+                    frame_alloc::FRAME_SIZE,
+                )
+            };
+            let flags = F::USER_ACCESSIBLE | F::WRITABLE | F::NO_EXECUTE;
+            if vmm::map(
+                region.start + region.size,
+                frame,
+                frame_alloc::FRAME_SIZE,
+                flags,
+            )
+            .is_err()
+            {
+                frame_alloc::free_frame(frame);
+                region.free();
+                return Err(Error::NoMemory);
+            }
+            region.size += frame_alloc::FRAME_SIZE;
+        }
+        *next += size;
+        Ok(region)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn free(self) {
+        use crate::vmm;
+
+        for page in (self.start..self.start + self.size).step_by(frame_alloc::FRAME_SIZE) {
+            if let Some(frame) = vmm::translate(page) {
+                let _ = vmm::unmap(page, frame_alloc::FRAME_SIZE);
+                frame_alloc::free_frame(frame);
+            }
+        }
+    }
+
+    /// Hands out `size` bytes of zeroed, contiguous frames.  Without paging, nothing stops ring 3 going anywhere, but
+    /// the system calls still only take pointers into these.
+    #[cfg(target_arch = "x86")]
+    fn new(_next: &mut usize, size: usize) -> Result<Self, Error> {
+        let count = size.div_ceil(frame_alloc::FRAME_SIZE);
+        if count == 0 {
+            return Err(Error::NoMemory);
+        }
+        let start =
+            frame_alloc::alloc_frames(count, frame_alloc::FRAME_SIZE).ok_or(Error::NoMemory)?;
+        let size = count * frame_alloc::FRAME_SIZE;
+        // SAFETY: the frames were free, so nothing else is using them.
+        unsafe { core::ptr::write_bytes(start as *mut u8, 0, size) };
+        Ok(Self { start, size })
+    }
+
+    #[cfg(all(feature = "selftest", target_arch = "x86"))]
+    fn free(self) {
+        frame_alloc::free_frames(self.start, self.size / frame_alloc::FRAME_SIZE);
+    }
+}
+
+/// The running program.
+struct Process {
+    regions: Vec<Region>,
+    /// Where the next region goes.
+    next: usize,
+    exit: Option<Exit>,
+}
+
+static PROCESS: SpinLock<Option<Process>> = SpinLock::new(None);
+/// A character `read` took but had no room for.
+static PENDING: SpinLock<Option<char>> = SpinLock::new(None);
+
+/// Gives the running program `size` more bytes of memory, returning where.
+fn map_region(size: usize) -> Result<usize, Error> {
+    let mut process = PROCESS.lock();
+    let process = process.as_mut().ok_or(Error::Unsupported)?;
+    let region = Region::new(&mut process.next, size)?;
+    let start = region.start;
+    process.regions.push(region);
+    Ok(start)
+}
+
+/// Checks that all of `addr..addr + len` is the running program's memory.
+fn check(addr: usize, len: usize) -> Result<(), Error> {
+    let end = addr.checked_add(len).ok_or(Error::BadPointer)?;
+    let process = PROCESS.lock();
+    let regions = &process.as_ref().ok_or(Error::BadPointer)?.regions;
+    if regions
+        .iter()
+        .any(|r| r.start <= addr && end <= r.start + r.size)
+    {
+        Ok(())
+    } else {
+        Err(Error::BadPointer)
+    }
+}
+
+/// Copies `dst.len()` bytes in from the running program's memory at `src`.
+pub fn copy_in(dst: &mut [u8], src: usize) -> Result<(), Error> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+    check(src, dst.len())?;
+    // SAFETY: `check` made sure it's all mapped, and the program can't be changing it while it's in a system call.
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+    Ok(())
+}
+
+/// Copies `src` out to the running program's memory at `dst`.
+pub fn copy_out(dst: usize, src: &[u8]) -> Result<(), Error> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    check(dst, src.len())?;
+    // SAFETY: as in `copy_in`.
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+    Ok(())
+}
+
+fn write(&[buf, len, ..]: &[usize; 5]) -> Result<usize, Error> {
+    let len = len.min(MAX_WRITE);
+    let mut bytes = alloc::vec![0; len];
+    copy_in(&mut bytes, buf)?;
+    for chunk in bytes.utf8_chunks() {
+        print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            print!("{}", char::REPLACEMENT_CHARACTER);
+        }
+    }
+    Ok(len)
+}
+
+fn read(&[buf, len, ..]: &[usize; 5]) -> Result<usize, Error> {
+    // Before taking anything typed, so a bad pointer doesn't lose it.
+    check(buf, len)?;
+    // Only locked to take the character or put it back: reading input and copying out both take locks of their own.
+    let mut pending = PENDING.lock().take();
+    let mut bytes = Vec::new();
+    while let Some(c) = pending.take().or_else(crate::kb_mouse::read_char) {
+        if bytes.len() + c.len_utf8() > len {
+            *PENDING.lock() = Some(c);
+            break;
+        }
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    copy_out(buf, &bytes)?;
+    Ok(bytes.len())
+}
+
+fn alloc(&[size, ..]: &[usize; 5]) -> Result<usize, Error> {
+    if size == 0 {
+        return Err(Error::InvalidArgument);
+    }
+    map_region(size)
+}
+
+fn exit(&[status, ..]: &[usize; 5]) -> Result<usize, Error> {
+    leave(Exit::Status(status as isize))
+}
+
+fn time(&[out, ..]: &[usize; 5]) -> Result<usize, Error> {
+    let ns = crate::time::now().as_nanos() as u64;
+    copy_out(out, &ns.to_ne_bytes())?;
+    Ok(0)
+}
+
+/// Carries out system call `number`.  For the architecture's system call entry.
+pub fn dispatch(number: usize, args: [usize; 5]) -> isize {
+    match TABLE.get(number) {
+        Some(call) => match call(&args) {
+            Ok(result) => result as isize,
+            Err(e) => e as isize,
+        },
+        None => Error::NoSuchCall as isize,
+    }
+}
+
+/// Ends the running program on exception `vector` at `at`.  For exception handlers, when the exception came from
+/// ring 3.
+pub fn fault(vector: u8, at: usize) -> ! {
+    leave(Exit::Fault { vector, at })
+}
+
+fn leave(exit: Exit) -> ! {
+    if let Some(process) = PROCESS.lock().as_mut() {
+        process.exit = Some(exit);
+    }
+    arch::leave_user()
+}
+
+/// Loads the program into memory of its own, returning its entry point and the top of its stack.
+#[cfg(feature = "selftest")]
+fn load(image: &[u8]) -> Result<(usize, usize), Error> {
+    let code = map_region(image.len())?;
+    copy_out(code, image)?;
+    // Its code is for running, not changing.
+    #[cfg(target_arch = "x86_64")]
+    crate::vmm::protect(
+        code,
+        image.len().next_multiple_of(frame_alloc::FRAME_SIZE),
+        crate::vmm::PageTableFlags::USER_ACCESSIBLE,
+    )
+    .map_err(|_| Error::NoMemory)?;
+    let stack = map_region(STACK_SIZE)?;
+    Ok((code, stack + STACK_SIZE))
+}
+
+/// Runs the flat binary `image` in ring 3 until it exits or faults.  BSP only.
+#[cfg(feature = "selftest")]
+pub fn run(image: &[u8]) -> Result<Exit, &'static str> {
+    {
+        let mut process = PROCESS.lock();
+        if process.is_some() {
+            return Err("a program is already running");
+        }
+        *process = Some(Process {
+            regions: Vec::new(),
+            next: USER_BASE,
+            exit: None,
+        });
+    }
+
+    let loaded = load(image);
+    if let Ok((entry, stack)) = loaded {
+        arch::enter_user(entry, stack);
+    }
+
+    let process = PROCESS.lock().take().ok_or("program vanished")?;
+    for region in process.regions {
+        region.free();
+    }
+    loaded.map_err(Error::message)?;
+    process.exit.ok_or("program left ring 3 without exiting")
+}
@@ -6,7 +6,10 @@
 
 // Don't ask me how API sets from other OS's this will work with.  That's part of the challange.
 
-pub mod htmos {}
+/// API set for HTMOS itself: system calls, and running the programs that make them.  Only x86 has a way into user
+/// mode so far.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod htmos;
 
 /// API set for the Windows Operating System.
 pub mod windows {}
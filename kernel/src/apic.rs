@@ -0,0 +1,620 @@
+//! **HyperText Markup APIC**
+//!
+//! The interrupt controllers, as the ACPI MADT describes them: the local APIC, every I/O APIC with the global system
+//! interrupts (GSIs) it serves, and the Interrupt Source Overrides saying where legacy (ISA) IRQs really end up.
+//! Nothing here assumes the addresses or wiring of any one machine.
+//!
+//! The local APIC runs in x2APIC mode (registers as MSRs, 32-bit IDs) wherever the CPU supports it, and in xAPIC mode
+//! (registers in MMIO, 8-bit IDs) otherwise.  `LocalApic` hides which.
+
+use crate::{println, sync::SpinLock, vmm};
+use alloc::vec::Vec;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use raw_acpi::madt::{
+    MADT, interrupt_source_override::InterruptSourceOverride, ioapic::IOAPIC,
+    local_api_address_override::LocalAPICAddressOverride, local_apic_nmi::LocalAPICNMI,
+    local_x2apic_nmi::Localx2APICNMI, proc_local_apic::ProcessorLocalAPIC,
+    processor_local_x2apic::ProcessorLocalx2APIC,
+};
+use spin::Once;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+#[cfg(target_arch = "x86_64")]
+const LAPIC_ICR_LOW: usize = 0x300;
+#[cfg(target_arch = "x86_64")]
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+/// What the local APIC timer divides the bus clock by.
+pub const TIMER_DIVISOR: u32 = 16;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+
+// Only `smp` sends IPIs, and there's no SMP on x86.
+#[cfg(target_arch = "x86_64")]
+const ICR_PENDING: u32 = 1 << 12;
+#[cfg(target_arch = "x86_64")]
+const ICR_ASSERT: u32 = 1 << 14;
+#[cfg(target_arch = "x86_64")]
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// x2APIC register MSRs start here; register `reg` of the MMIO layout is MSR `X2APIC_MSR_BASE + reg / 16`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+const REDIR_LEVEL: u32 = 1 << 15;
+const REDIR_ACTIVE_LOW: u32 = 1 << 13;
+const REDIR_MASKED: u32 = 1 << 16;
+
+/// A model-specific register.  Our own rather than the `x86_64` crate's, which only builds for x86_64.
+struct Msr(u32);
+impl Msr {
+    const fn new(msr: u32) -> Self {
+        Self(msr)
+    }
+
+    /// # Safety
+    /// The MSR has to exist.
+    unsafe fn read(&self) -> u64 {
+        let (low, high): (u32, u32);
+        // SAFETY: up to the caller.
+        unsafe {
+            core::arch::asm!(
+                "rdmsr",
+                in("ecx") self.0,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        (high as u64) << 32 | low as u64
+    }
+
+    /// # Safety
+    /// The MSR has to exist, and `value` mustn't break anything.
+    unsafe fn write(&mut self, value: u64) {
+        // SAFETY: up to the caller.
+        unsafe {
+            core::arch::asm!(
+                "wrmsr",
+                in("ecx") self.0,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Decodes MPS INTI flags, resolving "conforms to the bus" to what ISA does (active high, edge triggered).
+///
+/// Read by hand since `raw_acpi`'s decoder gets the trigger mode bits wrong.
+const fn inti_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (polarity, trigger)
+}
+
+/// A processor, by its local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+    pub acpi_uid: u32,
+    pub apic_id: u32,
+    /// Usable now; otherwise only if `online_capable`.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+/// A local APIC input (LINT0 or LINT1) wired to NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalNmi {
+    /// ACPI UID of the processor this is for; None for all of them.
+    pub acpi_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+}
+
+/// Where an ISA IRQ really arrives, and how it's signalled.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub phys: usize,
+    /// First GSI served by this I/O APIC.
+    pub gsi_base: u32,
+    /// Number of redirection entries, so this serves `gsi_base..gsi_base + inputs`.
+    pub inputs: u32,
+    /// Mapped register window.  Locked, since selecting a register and accessing it are two steps.
+    regs: SpinLock<usize>,
+}
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        let regs = self.regs.lock();
+        // SAFETY: IOREGSEL and IOWIN of a mapped I/O APIC.
+        unsafe {
+            (*regs as *mut u32).write_volatile(reg);
+            ((*regs + 0x10) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        let regs = self.regs.lock();
+        // SAFETY: same as above.
+        unsafe {
+            (*regs as *mut u32).write_volatile(reg);
+            ((*regs + 0x10) as *mut u32).write_volatile(value);
+        }
+    }
+
+    pub const fn serves(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    /// Writes the redirection entry for input `pin`, high half first so it's never live with a stale destination.
+    fn set_redirection(&self, pin: u32, low: u32, dest: u8) {
+        self.write(IOAPIC_REDTBL + pin * 2 + 1, (dest as u32) << 24);
+        self.write(IOAPIC_REDTBL + pin * 2, low);
+    }
+}
+
+/// How the local APICs are programmed.  Every CPU uses the same mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalApic {
+    /// Registers at the given (virtual) address, 8-bit IDs.
+    XApic(usize),
+    /// Registers as MSRs, 32-bit IDs.
+    X2Apic,
+}
+impl LocalApic {
+    /// Whether the CPU supports x2APIC mode.
+    pub fn x2apic_supported() -> bool {
+        __cpuid(1).ecx & (1 << 21) != 0
+    }
+
+    fn read(self, reg: usize) -> u32 {
+        match self {
+            // SAFETY: a register of the mapped local APIC.
+            Self::XApic(regs) => unsafe { ((regs + reg) as *const u32).read_volatile() },
+            // SAFETY: the x2APIC MSRs exist once x2APIC mode is on.
+            Self::X2Apic => unsafe { Msr::new(X2APIC_MSR_BASE + reg as u32 / 16).read() as u32 },
+        }
+    }
+
+    fn write(self, reg: usize, value: u32) {
+        match self {
+            // SAFETY: same as above.
+            Self::XApic(regs) => unsafe { ((regs + reg) as *mut u32).write_volatile(value) },
+            // SAFETY: same as above.
+            Self::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + reg as u32 / 16).write(value as u64)
+            },
+        }
+    }
+
+    /// ID of the local APIC of the CPU running this.
+    fn id(self) -> u32 {
+        match self {
+            Self::XApic(_) => self.read(LAPIC_ID) >> 24,
+            Self::X2Apic => self.read(LAPIC_ID),
+        }
+    }
+
+    /// Writes the interrupt command register and waits for the local APIC to take it.
+    #[cfg(target_arch = "x86_64")]
+    fn send(self, dest: u32, low: u32) {
+        match self {
+            Self::XApic(_) => {
+                self.write(LAPIC_ICR_HIGH, dest << 24);
+                self.write(LAPIC_ICR_LOW, low);
+                while self.read(LAPIC_ICR_LOW) & ICR_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+            // One 64-bit register, and nothing to wait for.
+            // SAFETY: same as above.
+            Self::X2Apic => unsafe {
+                Msr::new(X2APIC_MSR_BASE + LAPIC_ICR_LOW as u32 / 16)
+                    .write((dest as u64) << 32 | low as u64);
+            },
+        }
+    }
+
+    /// Switches the CPU running this to this mode.  The firmware leaves every CPU in xAPIC mode.
+    fn enable(self) {
+        if self == Self::X2Apic {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            // SAFETY: only done when the CPU supports x2APIC mode.
+            unsafe {
+                let value = base.read();
+                base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            }
+        }
+    }
+}
+
+/// Everything the MADT says about the interrupt controllers.
+pub struct InterruptModel {
+    /// Physical address of the local APIC (after any Local APIC Address Override).
+    pub lapic_phys: usize,
+    /// The machine also has the two legacy 8259 PICs, which must be masked.
+    pub pcat_compat: bool,
+    pub cpus: Vec<Cpu>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<SourceOverride>,
+    pub nmis: Vec<LocalNmi>,
+    pub lapic: LocalApic,
+}
+
+static MODEL: Once<InterruptModel> = Once::new();
+
+impl InterruptModel {
+    /// Reads the MADT at (virtual address) `madt`.  Nothing gets mapped or touched yet.
+    pub fn parse(madt: usize) -> Self {
+        // SAFETY: given a valid MADT.
+        let header = unsafe { (madt as *const MADT).read_unaligned() };
+        let flags = header.flags;
+        let mut model = Self {
+            lapic_phys: header.local_interrupt_controller_address as usize,
+            pcat_compat: flags.pcat_compat(),
+            cpus: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+            lapic: LocalApic::XApic(0),
+        };
+
+        let end = madt + header.header.length as usize;
+        let mut ptr = madt + size_of::<MADT>();
+        while ptr + 2 <= end {
+            // SAFETY: every entry starts with its type and length.
+            let (kind, length) =
+                unsafe { ((ptr as *const u8).read(), (ptr as *const u8).add(1).read()) };
+            if length < 2 || ptr + length as usize > end {
+                println!("MADT: bad entry (type {kind}, length {length}); ignoring the rest");
+                break;
+            }
+
+            // SAFETY: the length was checked against each structure's size.
+            match kind {
+                0x00 if length as usize >= size_of::<ProcessorLocalAPIC>() => {
+                    let info = unsafe { (ptr as *const ProcessorLocalAPIC).read_unaligned() };
+                    let flags = info.flags;
+                    model.cpus.push(Cpu {
+                        acpi_uid: info.acpi_processor_uid as u32,
+                        apic_id: info.acpi_id as u32,
+                        enabled: flags.enabled(),
+                        online_capable: flags.online_capable(),
+                    });
+                }
+                0x01 if length as usize >= size_of::<IOAPIC>() => {
+                    let info = unsafe { (ptr as *const IOAPIC).read_unaligned() };
+                    model.io_apics.push(IoApic {
+                        id: info.io_apic_id,
+                        phys: info.io_apic_address as usize,
+                        gsi_base: info.global_system_interrupt_base,
+                        inputs: 0,
+                        regs: SpinLock::new(0),
+                    });
+                }
+                0x02 if length as usize >= size_of::<InterruptSourceOverride>() => {
+                    let info = unsafe { (ptr as *const InterruptSourceOverride).read_unaligned() };
+                    let flags = unsafe { ((ptr + 8) as *const u16).read_unaligned() };
+                    let (polarity, trigger) = inti_flags(flags);
+                    model.overrides.push(SourceOverride {
+                        irq: info.source,
+                        gsi: info.global_system_interrupt,
+                        polarity,
+                        trigger,
+                    });
+                }
+                0x04 if length as usize >= size_of::<LocalAPICNMI>() => {
+                    let info = unsafe { (ptr as *const LocalAPICNMI).read_unaligned() };
+                    let flags = unsafe { ((ptr + 3) as *const u16).read_unaligned() };
+                    model.nmis.push(LocalNmi {
+                        acpi_uid: match info.acpi_processor_uid {
+                            0xFF => None,
+                            uid => Some(uid as u32),
+                        },
+                        lint: info.local_apic_lint_num,
+                        polarity: inti_flags(flags).0,
+                    });
+                }
+                0x05 if length as usize >= size_of::<LocalAPICAddressOverride>() => {
+                    let info = unsafe { (ptr as *const LocalAPICAddressOverride).read_unaligned() };
+                    model.lapic_phys = info.local_apic_address as usize;
+                }
+                0x09 if length as usize >= size_of::<ProcessorLocalx2APIC>() => {
+                    let info = unsafe { (ptr as *const ProcessorLocalx2APIC).read_unaligned() };
+                    let flags = info.flags;
+                    model.cpus.push(Cpu {
+                        acpi_uid: info.acpi_processor_uid,
+                        apic_id: info.x2apic_id,
+                        enabled: flags.enabled(),
+                        online_capable: flags.online_capable(),
+                    });
+                }
+                0x0A if length as usize >= size_of::<Localx2APICNMI>() => {
+                    let info = unsafe { (ptr as *const Localx2APICNMI).read_unaligned() };
+                    let flags = unsafe { ((ptr + 2) as *const u16).read_unaligned() };
+                    model.nmis.push(LocalNmi {
+                        acpi_uid: match info.acpi_processor_uid {
+                            u32::MAX => None,
+                            uid => Some(uid),
+                        },
+                        lint: info.local_x2apic_lint_num,
+                        polarity: inti_flags(flags).0,
+                    });
+                }
+                _ => {}
+            }
+
+            ptr += length as usize;
+        }
+
+        model
+    }
+
+    /// Where ISA `irq` arrives and how it's signalled.  Without an override, it's the GSI of the same number.
+    pub fn resolve_irq(&self, irq: u8) -> (u32, Polarity, Trigger) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger),
+            None => (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+        }
+    }
+
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|a| a.serves(gsi))
+    }
+
+    fn lapic_read(&self, reg: usize) -> u32 {
+        self.lapic.read(reg)
+    }
+
+    fn lapic_write(&self, reg: usize, value: u32) {
+        self.lapic.write(reg, value)
+    }
+}
+
+/// Whether the CPU has a local APIC at all (or the firmware turned it off for good).
+pub fn supported() -> bool {
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+/// Builds the interrupt model from the MADT at (virtual address) `madt`, maps every controller, masks every I/O APIC
+/// input and enables this CPU's local APIC (in x2APIC mode, if it can) with the given spurious vector.
+///
+/// The legacy PICs are left alone; masking them is up to the caller (see `InterruptModel::pcat_compat`).
+pub fn init(madt: usize, spurious_vector: u8) -> Result<&'static InterruptModel, &'static str> {
+    if MODEL.is_completed() {
+        return Err("interrupt controllers already set up");
+    }
+
+    let mut model = InterruptModel::parse(madt);
+    if model.io_apics.is_empty() {
+        return Err("MADT lists no I/O APIC");
+    }
+
+    model.lapic = if LocalApic::x2apic_supported() {
+        LocalApic::X2Apic
+    } else {
+        LocalApic::XApic(vmm::map_mmio(model.lapic_phys, 0x400)?)
+    };
+    for io_apic in &mut model.io_apics {
+        *io_apic.regs.lock() = vmm::map_mmio(io_apic.phys, 0x20)?;
+        io_apic.inputs = ((io_apic.read(IOAPIC_VER) >> 16) & 0xFF) + 1;
+        for pin in 0..io_apic.inputs {
+            io_apic.set_redirection(pin, REDIR_MASKED, 0);
+        }
+        println!(
+            "I/O APIC {}: GSIs {}-{}",
+            io_apic.id,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.inputs - 1
+        );
+    }
+
+    let model = MODEL.call_once(|| model);
+    init_local(spurious_vector);
+    Ok(model)
+}
+
+/// Enables the local APIC of the CPU running this with the given spurious vector, and wires up its NMI inputs.
+/// `init` does this for the BSP; every other CPU has to do it itself.
+pub fn init_local(spurious_vector: u8) {
+    let Some(m) = model() else {
+        return;
+    };
+    m.lapic.enable();
+    m.lapic_write(LAPIC_SVR, 0x100 | spurious_vector as u32);
+
+    let id = m.lapic.id();
+    let uid = m.cpus.iter().find(|c| c.apic_id == id).map(|c| c.acpi_uid);
+    for nmi in m
+        .nmis
+        .iter()
+        .filter(|n| n.acpi_uid.is_none() || n.acpi_uid == uid)
+    {
+        let mut lvt = LVT_NMI;
+        if nmi.polarity == Polarity::ActiveLow {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        if nmi.lint <= 1 {
+            m.lapic_write(LAPIC_LVT_LINT0 + nmi.lint as usize * 0x10, lvt);
+        }
+    }
+}
+
+/// The interrupt model, once `init` has run.
+pub fn model() -> Option<&'static InterruptModel> {
+    MODEL.get()
+}
+
+/// ID of the local APIC of the CPU running this.
+pub fn lapic_id() -> u32 {
+    model().map_or(0, |m| m.lapic.id())
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn eoi() {
+    if let Some(m) = model() {
+        m.lapic_write(LAPIC_EOI, 0);
+    }
+}
+
+/// Delivers `gsi` to the local APIC `dest` as `vector`.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger: Trigger,
+    dest: u8,
+) -> Result<(), &'static str> {
+    let model = model().ok_or("interrupt controllers not set up")?;
+    let io_apic = model
+        .io_apic_for(gsi)
+        .ok_or("no I/O APIC serves that GSI")?;
+
+    let mut low = vector as u32;
+    if polarity == Polarity::ActiveLow {
+        low |= REDIR_ACTIVE_LOW;
+    }
+    if trigger == Trigger::Level {
+        low |= REDIR_LEVEL;
+    }
+    io_apic.set_redirection(gsi - io_apic.gsi_base, low, dest);
+    Ok(())
+}
+
+/// Delivers ISA `irq` to this CPU as `vector`, wherever the overrides say it's wired to.
+///
+/// The redirection entry only holds an 8-bit destination, so a CPU with a larger x2APIC ID can't be routed to.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), &'static str> {
+    let model = model().ok_or("interrupt controllers not set up")?;
+    let dest = u8::try_from(lapic_id())
+        .map_err(|_| "this CPU's APIC ID doesn't fit a redirection entry")?;
+    let (gsi, polarity, trigger) = model.resolve_irq(irq);
+    route_gsi(gsi, vector, polarity, trigger, dest)
+}
+
+/// Masks `gsi` again.
+pub fn mask_gsi(gsi: u32) -> Result<(), &'static str> {
+    let model = model().ok_or("interrupt controllers not set up")?;
+    let io_apic = model
+        .io_apic_for(gsi)
+        .ok_or("no I/O APIC serves that GSI")?;
+    io_apic.set_redirection(gsi - io_apic.gsi_base, REDIR_MASKED, 0);
+    Ok(())
+}
+
+/// Starts this CPU's local APIC timer counting down from `count`, raising `vector` when it hits 0 (and starting over,
+/// if `periodic`).
+pub fn timer_start(vector: u8, count: u32, periodic: bool) {
+    if let Some(m) = model() {
+        m.lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        let mode = if periodic { TIMER_PERIODIC } else { 0 };
+        m.lapic_write(LAPIC_LVT_TIMER, mode | vector as u32);
+        m.lapic_write(LAPIC_TIMER_INITIAL, count);
+    }
+}
+
+/// Stops this CPU's local APIC timer.
+pub fn timer_stop() {
+    if let Some(m) = model() {
+        m.lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        m.lapic_write(LAPIC_TIMER_INITIAL, 0);
+    }
+}
+
+/// What this CPU's local APIC timer has left to count down.
+pub fn timer_count() -> u32 {
+    model().map_or(0, |m| m.lapic_read(LAPIC_TIMER_CURRENT))
+}
+
+/// An inter-processor interrupt.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ipi {
+    /// An ordinary interrupt on the given vector.
+    Fixed(u8),
+    Nmi,
+    /// Puts the target into wait-for-SIPI state.
+    Init,
+    /// Starts a CPU waiting for it in real mode at the given page (the vector is the page number).
+    Startup(u8),
+}
+#[cfg(target_arch = "x86_64")]
+impl Ipi {
+    const fn icr(self) -> u32 {
+        match self {
+            Self::Fixed(vector) => vector as u32,
+            Self::Nmi => 0b100 << 8 | ICR_ASSERT,
+            Self::Init => 0b101 << 8 | ICR_ASSERT,
+            Self::Startup(page) => 0b110 << 8 | ICR_ASSERT | page as u32,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn send(dest: u32, low: u32) {
+    let Some(m) = model() else {
+        return;
+    };
+    // In xAPIC mode, both halves have to go out together.
+    let irq = crate::sync::irq_save();
+    m.lapic.send(dest, low);
+    crate::sync::irq_restore(irq);
+}
+
+/// Sends `ipi` to the CPU whose local APIC ID is `apic_id`.
+#[cfg(target_arch = "x86_64")]
+pub fn send_ipi(apic_id: u32, ipi: Ipi) {
+    send(apic_id, ipi.icr());
+}
+
+/// Sends `ipi` to every CPU but this one.
+#[cfg(target_arch = "x86_64")]
+pub fn broadcast_ipi(ipi: Ipi) {
+    send(0, ipi.icr() | ICR_ALL_BUT_SELF);
+}
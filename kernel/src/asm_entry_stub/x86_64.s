@@ -1,10 +1,85 @@
 .global _start
 .extern htmkrnl
 .extern __stack_end
+.extern __kernel_offset
 
 .section .text._start, "ax"
 
+// The loader jumps here at the physical load address, still on its own identity mapping.
+// Before any Rust runs, build a throwaway PML4 that keeps the loader's lower half and maps
+// the kernel's physical pages to where it was linked (or slid to, if the loader relocated it),
+// then jump up there.  The kernel is position independent, and this runs before anything else
+// knows where it is, so nothing here may use an absolute address.
 _start:
+    mov r12, rdi
+
+    // r13 = where the kernel was loaded, r14 = where it will run.
+    lea r13, [rip + __kernel_start]
+    mov r14, [rip + __kernel_link]
+
+    mov rsi, cr3
+    and rsi, -4096
+    lea rdi, [rip + __boot_pml4]
+    mov rcx, 256
+    rep movsq
+
+    lea rax, [rip + __boot_pdpt]
+    or rax, 0x3
+    lea rdi, [rip + __boot_pml4]
+    mov rcx, r14
+    shr rcx, 39
+    and ecx, 511
+    mov [rdi + rcx * 8], rax
+
+    lea rax, [rip + __boot_pd]
+    or rax, 0x3
+    lea rdi, [rip + __boot_pdpt]
+    mov rcx, r14
+    shr rcx, 30
+    and ecx, 511
+    mov [rdi + rcx * 8], rax
+
+    // 2 MiB pages from the kernel's first to the end of its 1 GiB.
+    lea rdi, [rip + __boot_pd]
+    mov rax, r13
+    or rax, 0x83
+    mov rcx, r14
+    shr rcx, 21
+    and ecx, 511
+1:
+    mov [rdi + rcx * 8], rax
+    add rax, 0x200000
+    inc ecx
+    cmp ecx, 512
+    jne 1b
+
+    lea rax, [rip + __boot_pml4]
+    mov cr3, rax
+
+    sub r14, r13
+    lea rax, [rip + .Lhigher_half]
+    add rax, r14
+    jmp rax
+
+.Lhigher_half:
+    mov [rip + __kernel_offset], r14
     lea rsp, [rip + __stack_end]
     xor rbp, rbp
-    jmp htmkrnl
\ No newline at end of file
+    mov rdi, r12
+    jmp htmkrnl
+
+// Relocated by the loader along with everything else, unlike anything in .text.
+.section .data.boot, "aw"
+.balign 8
+__kernel_link:
+    .quad __kernel_start
+
+.section .bss.boot_tables, "aw", @nobits
+.balign 4096
+.global __boot_pml4
+__boot_pml4:
+    .skip 4096
+__boot_pdpt:
+    .skip 4096
+__boot_pd:
+    .skip 4096
@@ -0,0 +1,109 @@
+// Where application processors start.  The BSP copies this to a page below 1 MiB and points the startup IPI at it;
+// the AP then comes up in real mode at offset 0 of that page, with CS set to its segment.  From there it goes through
+// protected mode into long mode on the kernel's own page tables (the BSP maps the page at its physical address so
+// that turning paging on doesn't pull it away), and calls into the kernel.  The copy can land on any page, so nothing
+// here may use an absolute address; everything goes through ebx, the copy's linear address.
+
+.global ap_trampoline
+.global ap_trampoline_data
+.global ap_trampoline_end
+
+.section .rodata.ap_trampoline, "a"
+.balign 16
+ap_trampoline:
+.code16
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+
+    // Point the GDT pointer and both far jumps at this copy.
+    lea eax, [ebx + OFF_GDT]
+    mov [OFF_GDTR + 2], eax
+    lea eax, [ebx + OFF_PROTECTED]
+    mov [OFF_PROTECTED_PTR], eax
+    lea eax, [ebx + OFF_LONG]
+    mov [OFF_LONG_PTR], eax
+
+    lgdt [OFF_GDTR]
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    jmp fword ptr [OFF_PROTECTED_PTR]
+
+.code32
+ap_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // PAE, the kernel's PML4, then long mode and NX (the kernel's page tables use it) in EFER.
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, [ebx + OFF_DATA]
+    mov cr3, eax
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // Paging and write protection on; this is long mode (still running 32-bit code until the far jump).
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+    jmp fword ptr [ebx + OFF_LONG_PTR]
+
+.code64
+ap_long:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    // The upper half of rbx is undefined after the switch.
+    mov ebx, ebx
+
+    mov rsp, [rbx + OFF_DATA + 8]
+    mov rdi, [rbx + OFF_DATA + 24]
+    call [rbx + OFF_DATA + 16]
+1:
+    hlt
+    jmp 1b
+
+.balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF    // 0x08: 32-bit code
+    .quad 0x00CF92000000FFFF    // 0x10: data
+    .quad 0x00AF9A000000FFFF    // 0x18: 64-bit code
+ap_gdtr:
+    .word ap_gdtr - ap_gdt - 1
+    .long 0
+ap_protected_ptr:
+    .long 0
+    .word 0x08
+ap_long_ptr:
+    .long 0
+    .word 0x18
+
+// Filled in by the BSP for each AP (see `TrampolineData`).
+.balign 8
+ap_trampoline_data:
+    .quad 0     // PML4
+    .quad 0     // stack top
+    .quad 0     // entry point
+    .quad 0     // argument
+ap_trampoline_end:
+
+// Offsets into the trampoline, which is all real mode can address by, and all a copy at an unknown place can use.
+.set OFF_GDT, ap_gdt - ap_trampoline
+.set OFF_GDTR, ap_gdtr - ap_trampoline
+.set OFF_PROTECTED, ap_protected - ap_trampoline
+.set OFF_PROTECTED_PTR, ap_protected_ptr - ap_trampoline
+.set OFF_LONG, ap_long - ap_trampoline
+.set OFF_LONG_PTR, ap_long_ptr - ap_trampoline
+.set OFF_DATA, ap_trampoline_data - ap_trampoline
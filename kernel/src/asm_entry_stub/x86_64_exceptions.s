@@ -0,0 +1,169 @@
+// CPU exception entry points, one per vector.  Each is 16 bytes apart from the last, so the IDT can find
+// vector n's at `exception_stubs + 16 * n`.  Every stub leaves the same frame behind (see `TrapFrame`):
+// exceptions that don't push an error code get a 0 in its place.
+
+.global exception_stubs
+.extern exception_dispatch
+
+.section .text.exceptions, "ax"
+.balign 16
+exception_stubs:
+.balign 16
+    push 0
+    push 0
+    jmp exception_common
+.balign 16
+    push 0
+    push 1
+    jmp exception_common
+.balign 16
+    push 0
+    push 2
+    jmp exception_common
+.balign 16
+    push 0
+    push 3
+    jmp exception_common
+.balign 16
+    push 0
+    push 4
+    jmp exception_common
+.balign 16
+    push 0
+    push 5
+    jmp exception_common
+.balign 16
+    push 0
+    push 6
+    jmp exception_common
+.balign 16
+    push 0
+    push 7
+    jmp exception_common
+.balign 16
+    push 8
+    jmp exception_common
+.balign 16
+    push 0
+    push 9
+    jmp exception_common
+.balign 16
+    push 10
+    jmp exception_common
+.balign 16
+    push 11
+    jmp exception_common
+.balign 16
+    push 12
+    jmp exception_common
+.balign 16
+    push 13
+    jmp exception_common
+.balign 16
+    push 14
+    jmp exception_common
+.balign 16
+    push 0
+    push 15
+    jmp exception_common
+.balign 16
+    push 0
+    push 16
+    jmp exception_common
+.balign 16
+    push 17
+    jmp exception_common
+.balign 16
+    push 0
+    push 18
+    jmp exception_common
+.balign 16
+    push 0
+    push 19
+    jmp exception_common
+.balign 16
+    push 0
+    push 20
+    jmp exception_common
+.balign 16
+    push 21
+    jmp exception_common
+.balign 16
+    push 0
+    push 22
+    jmp exception_common
+.balign 16
+    push 0
+    push 23
+    jmp exception_common
+.balign 16
+    push 0
+    push 24
+    jmp exception_common
+.balign 16
+    push 0
+    push 25
+    jmp exception_common
+.balign 16
+    push 0
+    push 26
+    jmp exception_common
+.balign 16
+    push 0
+    push 27
+    jmp exception_common
+.balign 16
+    push 0
+    push 28
+    jmp exception_common
+.balign 16
+    push 29
+    jmp exception_common
+.balign 16
+    push 30
+    jmp exception_common
+.balign 16
+    push 0
+    push 31
+    jmp exception_common
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    // The frame is 176 bytes on top of the 16-byte aligned stack the CPU switched to, so RSP is still aligned.
+    mov rdi, rsp
+    cld
+    call exception_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // Vector and error code.
+    add rsp, 16
+    iretq
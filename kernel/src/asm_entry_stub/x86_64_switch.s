@@ -0,0 +1,28 @@
+// Switching kernel threads.  `context_switch` pushes what the System V ABI has a callee keep (and RFLAGS, so the
+// interrupt flag goes with the thread), saves the stack pointer to `[RDI]`, takes RSI as the next thread's, and pops
+// the same from there.  A thread that hasn't run yet starts from a stack `init_thread_stack` laid out the same way.
+
+.global context_switch
+
+.section .text.switch, "ax"
+
+// context_switch(old_sp: *mut usize, new_sp: usize)
+context_switch:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
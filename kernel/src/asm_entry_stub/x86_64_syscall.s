@@ -0,0 +1,99 @@
+// Ring 3 and back.  `user_enter` saves the kernel's registers and irets to user code; `user_leave` restores them and
+// returns from `user_enter`, wherever it's called from.  In between, `syscall` comes in through `syscall_entry`, which
+// leaves a `SyscallFrame` on the kernel stack for `syscall_dispatch` and sysrets to the caller with its result in RAX.
+
+.global syscall_entry
+.global user_enter
+.global user_leave
+.extern syscall_dispatch
+.extern syscall_kernel_rsp
+
+.section .bss.syscall, "aw", @nobits
+.balign 8
+syscall_user_rsp:
+    .zero 8
+
+.section .text.syscall, "ax"
+
+// RCX holds the return address, R11 RFLAGS, RAX the call number, and RDI, RSI, RDX, R10, R8 its arguments.
+// Interrupts are off (see SFMASK) until RSP is the kernel's.
+syscall_entry:
+    mov [rip + syscall_user_rsp], rsp
+    mov rsp, [rip + syscall_kernel_rsp]
+    push qword ptr [rip + syscall_user_rsp]
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+
+    // The frame is 80 bytes on the 16-byte aligned trap stack, so RSP is still aligned.
+    mov rdi, rsp
+    sti
+    call syscall_dispatch
+    cli
+
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+    // RCX is canonical; `syscall_dispatch` saw to that.
+    sysretq
+
+// user_enter(entry: RDI, stack: RSI, kernel_rsp: RDX, code: RCX, data: R8)
+user_enter:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdx], rsp
+
+    // What iretq pops: SS, RSP, RFLAGS (interrupts on), CS and RIP.
+    push r8
+    push rsi
+    push 0x202
+    push rcx
+    push rdi
+
+    // Nothing of the kernel's for user code to see.
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+// user_leave(kernel_rsp: RDI)
+user_leave:
+    mov rsp, rdi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
@@ -0,0 +1,233 @@
+// Interrupt entry points for vectors 0-47 (the CPU exceptions, then the 16 IRQs at `irq::PIC_BASE`), one per vector.
+// Each is 16 bytes apart from the last, so the IDT can find vector n's at `interrupt_stubs + 16 * n`.  Every stub
+// leaves the same frame behind (see `TrapFrame` in x86_stuff.rs): vectors that don't push an error code get a 0 in
+// its place.
+
+.global interrupt_stubs
+.global spurious_stub
+.global syscall_stub
+.extern interrupt_dispatch
+
+.section .text.interrupts, "ax"
+.balign 16
+interrupt_stubs:
+.balign 16
+    push 0
+    push 0
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 1
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 2
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 3
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 4
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 5
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 6
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 7
+    jmp interrupt_common
+.balign 16
+    push 8
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 9
+    jmp interrupt_common
+.balign 16
+    push 10
+    jmp interrupt_common
+.balign 16
+    push 11
+    jmp interrupt_common
+.balign 16
+    push 12
+    jmp interrupt_common
+.balign 16
+    push 13
+    jmp interrupt_common
+.balign 16
+    push 14
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 15
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 16
+    jmp interrupt_common
+.balign 16
+    push 17
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 18
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 19
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 20
+    jmp interrupt_common
+.balign 16
+    push 21
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 22
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 23
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 24
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 25
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 26
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 27
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 28
+    jmp interrupt_common
+.balign 16
+    push 29
+    jmp interrupt_common
+.balign 16
+    push 30
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 31
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 32
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 33
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 34
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 35
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 36
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 37
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 38
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 39
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 40
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 41
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 42
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 43
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 44
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 45
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 46
+    jmp interrupt_common
+.balign 16
+    push 0
+    push 47
+    jmp interrupt_common
+
+interrupt_common:
+    pushad
+    push ds
+    push es
+    // User code may have left anything in DS and ES; the kernel's data segment is 0x10.
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+
+    // The CPU only aligns the stack to 4 bytes; the frame pointer goes in EBX (which `interrupt_dispatch` keeps) so it
+    // can be put back after.
+    mov ebx, esp
+    and esp, -16
+    sub esp, 12
+    push ebx
+    cld
+    call interrupt_dispatch
+    mov esp, ebx
+
+    pop es
+    pop ds
+    popad
+    // Vector and error code.
+    add esp, 8
+    iretd
+
+// Spurious interrupts from the local APIC need no EOI, and nothing else.
+.balign 16
+spurious_stub:
+    iretd
+
+// System calls from user code (`int 0x80`).
+.balign 16
+syscall_stub:
+    push 0
+    push 0x80
+    jmp interrupt_common
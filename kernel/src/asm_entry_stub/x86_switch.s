@@ -0,0 +1,26 @@
+// Switching kernel threads.  `context_switch` pushes what cdecl has a callee keep (and EFLAGS, so the interrupt flag
+// goes with the thread), saves the stack pointer to `*old_sp`, takes `new_sp` as the next thread's, and pops the same
+// from there.  A thread that hasn't run yet starts from a stack `init_thread_stack` laid out the same way.
+
+.global context_switch
+
+.section .text.switch, "ax"
+
+// context_switch(old_sp: *mut usize, new_sp: usize), on the stack.
+context_switch:
+    mov eax, [esp + 4]
+    mov edx, [esp + 8]
+    pushfd
+    push ebp
+    push ebx
+    push esi
+    push edi
+    mov [eax], esp
+
+    mov esp, edx
+    pop edi
+    pop esi
+    pop ebx
+    pop ebp
+    popfd
+    ret
@@ -0,0 +1,55 @@
+// Ring 3 and back.  `user_enter` saves the kernel's registers and irets to user code; `user_leave` restores them and
+// returns from `user_enter`, wherever it's called from.  System calls come in through `syscall_stub` (see
+// x86_exceptions.s), like any other interrupt.
+
+.global user_enter
+.global user_leave
+
+.section .text.user, "ax"
+
+// user_enter(entry, stack, kernel_esp, code, data), all on the stack.
+user_enter:
+    push ebp
+    push ebx
+    push esi
+    push edi
+    pushfd
+
+    // 20 bytes pushed and the return address put the arguments from ESP + 24 on.
+    mov edx, [esp + 24]
+    mov ecx, [esp + 28]
+    mov esi, [esp + 32]
+    mov ebx, [esp + 36]
+    mov eax, [esp + 40]
+    mov [esi], esp
+
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    // What iretd pops when going to ring 3: EIP, CS, EFLAGS (interrupts on), ESP and SS.
+    push eax
+    push ecx
+    push 0x202
+    push ebx
+    push edx
+
+    // Nothing of the kernel's for user code to see.
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    iretd
+
+// user_leave(kernel_esp)
+user_leave:
+    mov esp, [esp + 4]
+    popfd
+    pop edi
+    pop esi
+    pop ebx
+    pop ebp
+    ret
@@ -8,8 +8,8 @@ use core::ptr::null;
 use htmos_boot_info::HTMOSBootInformation;
 
 static mut BOOT_INFO: *const HTMOSBootInformation = null();
-pub const fn boot_info() -> &'static HTMOSBootInformation {
-    unsafe { &*BOOT_INFO }
+pub fn boot_info() -> &'static HTMOSBootInformation {
+    unsafe { &*(crate::vmm::phys_to_virt(BOOT_INFO as usize) as *const HTMOSBootInformation) }
 }
 //pub fn boot_info_exists() -> bool {
 //    !unsafe { BOOT_INFO }.is_null()
@@ -0,0 +1,198 @@
+//! **HyperText Markup UEFI Runtime Services**
+//!
+//! The firmware's runtime services outlive boot services, but only work from where the firmware thinks they are.
+//! `init` moves them into the kernel's runtime window (see `vmm`) with `set_virtual_address_map`, after which the
+//! wrappers here can be used no matter what happens to the lower half.
+//!
+//! Calls into the firmware are never made from two places at once; UEFI runtime services aren't reentrant.
+//! Only the self-tests touch variables so far.
+
+#[cfg(target_arch = "x86_64")]
+use crate::reclaim;
+use crate::{boot_info::boot_info, sync::SpinLock, vmm::phys_to_virt};
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_arch = "x86_64")]
+use r_efi::efi::MemoryDescriptor;
+use r_efi::efi::{self, RuntimeServices, Status, SystemTable};
+#[cfg(feature = "selftest")]
+use widestring::U16CStr;
+
+/// Address of the `RuntimeServices` table, usable by the kernel (0 until `init` succeeds).  Held across every call
+/// into the firmware, which therefore runs with interrupts off.
+static RUNTIME: SpinLock<usize> = SpinLock::new(0);
+/// Set once the firmware has converted its pointers to virtual addresses.
+static VIRTUAL: AtomicBool = AtomicBool::new(false);
+
+/// Turns a pointer read out of a firmware table into one the kernel can use.
+///
+/// Before `init`, firmware tables hold physical addresses; after it, pointers into runtime memory were converted.
+pub fn fw_ptr(addr: usize) -> usize {
+    if VIRTUAL.load(Ordering::Relaxed) {
+        addr
+    } else {
+        phys_to_virt(addr)
+    }
+}
+
+/// Moves runtime services to their virtual addresses and makes the wrappers usable.
+///
+/// Needs the heap, and on x86_64 the page tables from `vmm::init`.  Only call this on UEFI boots, once.
+#[cfg(target_arch = "x86_64")]
+pub fn init() -> Result<(), &'static str> {
+    use crate::vmm::{self, RUNTIME_BASE};
+
+    let bi = boot_info();
+    if bi.boot_mode != 1 {
+        return Err("not booted through UEFI");
+    }
+
+    // The firmware wants the whole map back, with the runtime descriptors given a virtual address.
+    let size = bi.memory_map_size as usize;
+    let desc_size = bi.memory_desc_size as usize;
+    let mut map = alloc::vec![0u8; size];
+    // SAFETY: the loader handed over a map of exactly this size.
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(bi.memory_map_addr as usize) as *const u8,
+            map.as_mut_ptr(),
+            size,
+        );
+    }
+    for off in (0..size).step_by(desc_size) {
+        // SAFETY: each descriptor starts `desc_size` bytes after the last; they may not be aligned.
+        unsafe {
+            let desc = map.as_mut_ptr().add(off) as *mut MemoryDescriptor;
+            let mut d = desc.read_unaligned();
+            if d.attribute & efi::MEMORY_RUNTIME != 0 {
+                d.virtual_start = RUNTIME_BASE as u64 + d.physical_start;
+                desc.write_unaligned(d);
+            }
+        }
+    }
+
+    let st = unsafe { &*(phys_to_virt(bi.more_info as usize) as *const SystemTable) };
+    let rs_phys = st.runtime_services as usize;
+    let rs = unsafe { &*(phys_to_virt(rs_phys) as *const RuntimeServices) };
+
+    let mut runtime = RUNTIME.lock();
+    // SAFETY: the runtime regions are still identity-mapped (see `vmm::init`), which is what the firmware expects here.
+    let status = unsafe {
+        (rs.set_virtual_address_map)(
+            size,
+            desc_size,
+            efi::MEMORY_DESCRIPTOR_VERSION,
+            map.as_mut_ptr() as *mut MemoryDescriptor,
+        )
+    };
+    if status.is_error() {
+        return Err("set_virtual_address_map failed");
+    }
+    *runtime = RUNTIME_BASE + rs_phys;
+    VIRTUAL.store(true, Ordering::Relaxed);
+
+    // Nothing calls into the firmware at its physical addresses anymore.
+    let mut ret = Ok(());
+    reclaim::for_each_runtime_region(|r| {
+        if let Err(e) = vmm::unmap(r.phys, r.pages * vmm::PAGE_SIZE) {
+            ret = Err(e);
+        }
+    });
+    ret
+}
+
+/// Makes the wrappers usable.  Without paging of our own, the firmware's identity mapping is all there is.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn init() -> Result<(), &'static str> {
+    let bi = boot_info();
+    if bi.boot_mode != 1 {
+        return Err("not booted through UEFI");
+    }
+
+    let st = unsafe { &*(phys_to_virt(bi.more_info as usize) as *const SystemTable) };
+    *RUNTIME.lock() = phys_to_virt(st.runtime_services as usize);
+    Ok(())
+}
+
+/// Runs `f` with the runtime services table, while holding the lock.
+fn with_runtime<T>(f: impl FnOnce(&RuntimeServices) -> T) -> Result<T, Status> {
+    let runtime = RUNTIME.lock();
+    if *runtime == 0 {
+        return Err(Status::UNSUPPORTED);
+    }
+    // SAFETY: set by `init` to where the table is mapped.
+    Ok(f(unsafe { &*(*runtime as *const RuntimeServices) }))
+}
+
+/// Reads the current time from the firmware's real-time clock.
+pub fn get_time() -> Result<efi::Time, Status> {
+    let mut time = efi::Time::default();
+    let status = with_runtime(|rs| unsafe { (rs.get_time)(&mut time, core::ptr::null_mut()) })?;
+    if status.is_error() {
+        Err(status)
+    } else {
+        Ok(time)
+    }
+}
+
+/// Resets or shuts down the machine (`efi::RESET_*`).  Only returns if the firmware can't do it.
+pub fn reset_system(kind: efi::ResetType) {
+    let _ = with_runtime(|rs| unsafe {
+        (rs.reset_system)(kind, Status::SUCCESS, 0, core::ptr::null_mut())
+    });
+}
+
+/// Reads the variable `name` of vendor `guid` into `buf`.
+///
+/// Returns the size of the data and its attributes (`efi::VARIABLE_*`).  If `buf` is too small, the error is
+/// `BUFFER_TOO_SMALL`; call again with a bigger one.
+#[cfg(feature = "selftest")]
+pub fn get_variable(
+    name: &U16CStr,
+    guid: &efi::Guid,
+    buf: &mut [u8],
+) -> Result<(usize, u32), Status> {
+    let mut guid = *guid;
+    let mut attributes = 0;
+    let mut size = buf.len();
+    // SAFETY: the firmware only reads `name` and `guid`, and writes at most `size` bytes.
+    let status = with_runtime(|rs| unsafe {
+        (rs.get_variable)(
+            name.as_ptr() as *mut _,
+            &mut guid,
+            &mut attributes,
+            &mut size,
+            buf.as_mut_ptr() as *mut _,
+        )
+    })?;
+    if status.is_error() {
+        Err(status)
+    } else {
+        Ok((size, attributes))
+    }
+}
+
+/// Writes (or, with empty `data`, deletes) the variable `name` of vendor `guid`.
+#[cfg(feature = "selftest")]
+pub fn set_variable(
+    name: &U16CStr,
+    guid: &efi::Guid,
+    attributes: u32,
+    data: &[u8],
+) -> Result<(), Status> {
+    let mut guid = *guid;
+    // SAFETY: the firmware only reads from every pointer given.
+    let status = with_runtime(|rs| unsafe {
+        (rs.set_variable)(
+            name.as_ptr() as *mut _,
+            &mut guid,
+            attributes,
+            data.len(),
+            data.as_ptr() as *mut _,
+        )
+    })?;
+    if status.is_error() {
+        Err(status)
+    } else {
+        Ok(())
+    }
+}
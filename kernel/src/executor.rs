@@ -0,0 +1,288 @@
+//! **HyperText Markup Executor**
+//!
+//! Runs `async` code in the kernel, for drivers that spend most of their time waiting on interrupts.  Tasks (`spawn`)
+//! are polled on one scheduler thread, which blocks when none of them can make progress; with nothing else to run,
+//! the CPU halts in the idle thread rather than polling.  A task's `Waker` queues it to be polled again, and is safe
+//! to use from interrupt handlers.
+//!
+//! - `Stream` - a sequence of values that arrive over time; `scancodes`, `mouse_packets` and `serial_bytes` are the
+//!   input devices' streams, and `chars` is what's typed on the first and last.
+//! - `sleep` - a future that completes after a while, woken by a `time` timer.  Only the self-tests wait on one yet.
+//! - `Wakers` - the wakers waiting on one thing, for the interrupt handler that makes it happen to wake.
+
+#[cfg(feature = "selftest")]
+use crate::time::{self, TimerId};
+use crate::{
+    kb_mouse::{self, MouseEvent},
+    sched::{self, Priority, WaitQueue},
+    serial,
+    sync::SpinLock,
+};
+use alloc::{
+    boxed::Box, collections::BTreeMap, collections::VecDeque, sync::Arc, task::Wake, vec::Vec,
+};
+#[cfg(feature = "selftest")]
+use core::time::Duration;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use pc_keyboard::DecodedKey;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    waker: Waker,
+}
+
+/// Every task not finished, but for the one being polled.
+static TASKS: SpinLock<BTreeMap<TaskId, Task>> = SpinLock::new(BTreeMap::new());
+/// Tasks to poll, in order.  A task may be in here more than once; polling it again is harmless.
+static READY: SpinLock<VecDeque<TaskId>> = SpinLock::new(VecDeque::new());
+/// Where the executor thread waits while `READY` is empty.
+static WORK: WaitQueue = WaitQueue::new();
+static NEXT_ID: SpinLock<u64> = SpinLock::new(0);
+
+struct TaskWaker(TaskId);
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        make_ready(self.0);
+    }
+}
+
+/// Starts the thread the tasks run on.  Input goes through them, so it runs ahead of the other threads.
+pub fn init() -> Result<sched::ThreadId, &'static str> {
+    sched::spawn("executor", Priority::High, run)
+}
+
+/// Runs `future` as a task, until it completes.  Tasks spawned before `init` wait for it.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    let id = {
+        let mut next = NEXT_ID.lock();
+        *next += 1;
+        TaskId(*next)
+    };
+    let task = Task {
+        future: Box::pin(future),
+        waker: Waker::from(Arc::new(TaskWaker(id))),
+    };
+    TASKS.lock().insert(id, task);
+    make_ready(id);
+    id
+}
+
+fn make_ready(id: TaskId) {
+    READY.lock().push_back(id);
+    WORK.wake_all();
+}
+
+/// Polls whichever tasks are ready, forever.
+fn run() {
+    loop {
+        while let Some(id) = READY.lock().pop_front() {
+            // Out of `TASKS` while polled: it's a spinlock, and the task may well spawn others.
+            let Some(mut task) = TASKS.lock().remove(&id) else {
+                continue;
+            };
+            let mut cx = Context::from_waker(&task.waker);
+            if task.future.as_mut().poll(&mut cx).is_pending() {
+                TASKS.lock().insert(id, task);
+            }
+        }
+        WORK.wait_until(|| !READY.lock().is_empty());
+    }
+}
+
+/// The wakers of tasks waiting on the same thing.  Registering again before being woken is fine; each task is only
+/// kept once.
+pub struct Wakers {
+    wakers: SpinLock<Vec<Waker>>,
+}
+impl Wakers {
+    pub const fn new() -> Self {
+        Self {
+            wakers: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.lock());
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Tasks waiting on the keyboard, mouse and serial console; their interrupt handlers wake them.
+pub static KEYBOARD: Wakers = Wakers::new();
+pub static MOUSE: Wakers = Wakers::new();
+pub static SERIAL: Wakers = Wakers::new();
+
+/// Values that come one at a time, as they're ready; an async iterator.  Ends with `None`, if it ends.
+pub trait Stream {
+    type Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    /// The next value, once there is one.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin + Sized,
+    {
+        Next(self)
+    }
+}
+
+/// What `Stream::next` returns.
+pub struct Next<'a, S>(&'a mut S);
+impl<S: Stream + Unpin> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.0).poll_next(cx)
+    }
+}
+
+/// An input device's queue, as a stream that never ends.
+pub struct Device<T> {
+    read: fn() -> Option<T>,
+    wakers: &'static Wakers,
+}
+impl<T> Stream for Device<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(item) = (self.read)() {
+            return Poll::Ready(Some(item));
+        }
+        self.wakers.register(cx.waker());
+        // Something could have come in between the first look and registering, with nobody there to wake.
+        match (self.read)() {
+            Some(item) => Poll::Ready(Some(item)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Raw scancodes from the keyboard.  The same queue `kb_mouse::read_key` decodes, so only one of the two should be
+/// reading.
+pub fn scancodes() -> Device<u8> {
+    Device {
+        read: kb_mouse::read_scancode,
+        wakers: &KEYBOARD,
+    }
+}
+
+/// Packets from the mouse.
+pub fn mouse_packets() -> Device<MouseEvent> {
+    Device {
+        read: kb_mouse::poll_mouse,
+        wakers: &MOUSE,
+    }
+}
+
+/// Bytes received on the serial console.
+pub fn serial_bytes() -> Device<u8> {
+    Device {
+        read: serial::read_byte,
+        wakers: &SERIAL,
+    }
+}
+
+/// What `chars` returns.
+pub struct Chars {
+    scancodes: Device<u8>,
+    serial: Device<u8>,
+}
+impl Stream for Chars {
+    type Item = char;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<char>> {
+        // Most scancodes don't finish a character (key releases, modifiers, the first of several bytes); they're passed
+        // over.  Both streams are left registered when neither has one.
+        while let Poll::Ready(Some(byte)) = Pin::new(&mut self.scancodes).poll_next(cx) {
+            if let Some(DecodedKey::Unicode(c)) = kb_mouse::decode(byte).and_then(|k| k.key) {
+                return Poll::Ready(Some(c));
+            }
+        }
+        while let Poll::Ready(Some(byte)) = Pin::new(&mut self.serial).poll_next(cx) {
+            if let Some(c) = kb_mouse::serial_char(byte) {
+                return Poll::Ready(Some(c));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Characters typed on the keyboard or, as `kb_mouse::read_char` has it, the serial console.  Built on `scancodes` and
+/// `serial_bytes`, so it shouldn't be read alongside either, or `read_char`.
+pub fn chars() -> Chars {
+    Chars {
+        scancodes: scancodes(),
+        serial: serial_bytes(),
+    }
+}
+
+/// What `sleep` returns.
+#[cfg(feature = "selftest")]
+pub struct Sleep {
+    deadline: Duration,
+    /// The timer that wakes the task, and the waker it was set for.
+    timer: Option<(TimerId, Waker)>,
+}
+#[cfg(feature = "selftest")]
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = time::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+        if let Some((_, waker)) = &self.timer
+            && waker.will_wake(cx.waker())
+        {
+            return Poll::Pending;
+        }
+        // New, or polled from somewhere else since: the old waker may be no use.
+        if let Some((id, _)) = self.timer.take() {
+            time::cancel_timer(id);
+        }
+        let waker = cx.waker().clone();
+        let id = time::add_timer(self.deadline - now, None, {
+            let waker = waker.clone();
+            move || waker.wake_by_ref()
+        });
+        self.timer = Some((id, waker));
+        Poll::Pending
+    }
+}
+#[cfg(feature = "selftest")]
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.timer.take() {
+            time::cancel_timer(id);
+        }
+    }
+}
+
+/// Completes once at least `duration` has gone by.
+#[cfg(feature = "selftest")]
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: time::now() + duration,
+        timer: None,
+    }
+}
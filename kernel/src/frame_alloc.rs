@@ -0,0 +1,285 @@
+//! **HyperText Markup Frame Allocator**
+//!
+//! The single source of truth for physical memory.  Every 4 KiB frame in the normalized memory map
+//! gets one bit: 0 is free, 1 is taken.  The heap, page tables and drivers all claim their memory here.
+//!
+//! A second bitmap remembers which frames are memory at all, so a stray free can't hand out an MMIO or reserved hole.
+
+use crate::{sync::SpinLock, vmm::phys_to_virt};
+
+pub const FRAME_SIZE: usize = 4096;
+/// 2 MiB, the size of a huge page on x86_64.
+#[cfg(feature = "selftest")]
+pub const HUGE_FRAME_SIZE: usize = 512 * FRAME_SIZE;
+/// The end of what real mode can address.  One frame below it is set aside at `init` (see `alloc_low_frame`).
+pub const LOW_MEMORY: usize = 0x10_0000;
+
+const BITS: usize = u64::BITS as usize;
+
+pub struct FrameAllocator {
+    /// Physical address of the bitmap: one bit per frame, starting at physical address 0.
+    bitmap: usize,
+    /// Physical address of the usable bitmap, laid out the same: 1 for frames that are (or were) free memory.
+    usable: usize,
+    /// Number of frames the bitmap covers.
+    frames: usize,
+    /// Number of frames currently free.
+    free: usize,
+    /// Where the next single-frame search starts (frame index).
+    hint: usize,
+    /// A frame below `LOW_MEMORY`, kept out of the other allocations so there's still one when it's needed.
+    low: Option<usize>,
+}
+
+pub static FRAMES: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::empty());
+
+impl FrameAllocator {
+    pub const fn empty() -> Self {
+        Self {
+            bitmap: 0,
+            usable: 0,
+            frames: 0,
+            free: 0,
+            hint: 0,
+            low: None,
+        }
+    }
+
+    /// Builds the bitmap from the normalized memory map (see `get_mmap`).
+    ///
+    /// The bitmap covers at least up to `top`, so memory that is freed later (see `reclaim`) can be tracked too.
+    /// It is carved out, along with the usable bitmap, of the first free section big enough to hold both.
+    ///
+    /// **NOTE**: Partial frames at the edges of a section are never handed out.
+    pub fn init(&mut self, mmap: &([(usize, usize); 256], usize), top: usize) {
+        let (arr, sz) = mmap;
+        let regions = &arr[..*sz];
+
+        let top = regions.iter().map(|&(s, l)| s + l).fold(top, usize::max);
+        let frames = top / FRAME_SIZE;
+        let words = frames.div_ceil(BITS);
+        let bitmap_size = (2 * words * size_of::<u64>()).next_multiple_of(FRAME_SIZE);
+
+        let Some(bitmap_start) = regions.iter().find_map(|&(s, l)| {
+            let start = s.next_multiple_of(FRAME_SIZE);
+            if start + bitmap_size <= s + l {
+                Some(start)
+            } else {
+                None
+            }
+        }) else {
+            panic!("no memory section can hold the frame bitmap ({bitmap_size} bytes)");
+        };
+
+        self.bitmap = bitmap_start;
+        self.usable = bitmap_start + words * size_of::<u64>();
+        self.frames = frames;
+        self.free = 0;
+        self.hint = 0;
+
+        // Everything starts taken; only what the memory map says is free gets released.
+        // SAFETY: the range was just found inside a free section.
+        unsafe {
+            self.words().write_bytes(0xFF, words);
+            self.usable_words().write_bytes(0, words);
+        }
+        for &(s, l) in regions {
+            let first = s.div_ceil(FRAME_SIZE);
+            let last = (s + l) / FRAME_SIZE;
+            self.release(first * FRAME_SIZE, last.saturating_sub(first));
+        }
+        for f in bitmap_start / FRAME_SIZE..(bitmap_start + bitmap_size) / FRAME_SIZE {
+            self.set(f);
+        }
+        // Taken first thing; `alloc` starts from the bottom, so low memory is the first to go.
+        self.low = (0..(LOW_MEMORY / FRAME_SIZE).min(frames)).find(|&f| !self.is_set(f));
+        if let Some(f) = self.low {
+            self.set(f);
+        }
+    }
+
+    fn words(&self) -> *mut u64 {
+        phys_to_virt(self.bitmap) as *mut u64
+    }
+
+    fn usable_words(&self) -> *mut u64 {
+        phys_to_virt(self.usable) as *mut u64
+    }
+
+    fn is_usable(&self, frame: usize) -> bool {
+        // SAFETY: same as `is_set`.
+        unsafe { self.usable_words().add(frame / BITS).read() & (1 << (frame % BITS)) != 0 }
+    }
+
+    fn is_set(&self, frame: usize) -> bool {
+        // SAFETY: callers keep `frame` below `self.frames`.
+        unsafe { self.words().add(frame / BITS).read() & (1 << (frame % BITS)) != 0 }
+    }
+
+    fn set(&mut self, frame: usize) {
+        if !self.is_set(frame) {
+            // SAFETY: same as `is_set`.
+            unsafe { *self.words().add(frame / BITS) |= 1 << (frame % BITS) };
+            self.free -= 1;
+        }
+    }
+
+    fn clear(&mut self, frame: usize) {
+        if self.is_set(frame) {
+            // SAFETY: same as `is_set`.
+            unsafe { *self.words().add(frame / BITS) &= !(1 << (frame % BITS)) };
+            self.free += 1;
+        }
+    }
+
+    /// Total number of frames tracked (free or not).
+    pub const fn total_frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Number of frames currently free.
+    pub const fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Returns the physical address of a free 4 KiB frame.  Returns None if memory is exhausted.
+    pub fn alloc(&mut self) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+
+        // Skip whole words that are completely taken.
+        let words = self.frames.div_ceil(BITS);
+        let start = self.hint / BITS;
+        for w in (start..words).chain(0..start) {
+            // SAFETY: `w` is inside the bitmap.
+            let word = unsafe { self.words().add(w).read() };
+            if word == u64::MAX {
+                continue;
+            }
+            let frame = w * BITS + word.trailing_ones() as usize;
+            if frame >= self.frames {
+                continue;
+            }
+            self.set(frame);
+            self.hint = frame + 1;
+            return Some(frame * FRAME_SIZE);
+        }
+        None
+    }
+
+    /// Returns the physical address of `count` contiguous free frames, the first one aligned to `align` bytes.
+    ///
+    /// `align` must be a power of two; anything below `FRAME_SIZE` is treated as `FRAME_SIZE`.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+        debug_assert!(align.is_power_of_two());
+        let step = (align / FRAME_SIZE).max(1);
+
+        let mut first = 0;
+        while first + count <= self.frames {
+            // Find the last taken frame inside the candidate run, if any.
+            match (first..first + count).rev().find(|&f| self.is_set(f)) {
+                Some(taken) => first = (taken + 1).next_multiple_of(step),
+                None => {
+                    for f in first..first + count {
+                        self.set(f);
+                    }
+                    return Some(first * FRAME_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the physical address of a free 2 MiB run, aligned to 2 MiB.
+    #[cfg(feature = "selftest")]
+    pub fn alloc_huge(&mut self) -> Option<usize> {
+        self.alloc_contiguous(HUGE_FRAME_SIZE / FRAME_SIZE, HUGE_FRAME_SIZE)
+    }
+
+    /// Hands out the frame below `LOW_MEMORY` set aside at `init`, once.  None if there was none, or it's been taken.
+    /// Freeing it puts it back with the others, not aside again.
+    #[cfg(target_arch = "x86_64")]
+    pub fn alloc_low(&mut self) -> Option<usize> {
+        self.low.take().map(|f| f * FRAME_SIZE)
+    }
+
+    /// Gives back `count` frames starting at `addr`.  Addresses outside of tracked memory are ignored, and so are
+    /// frames that were never usable memory (see `release` for memory that becomes usable later).
+    pub fn free_contiguous(&mut self, addr: usize, count: usize) {
+        debug_assert!(
+            addr.is_multiple_of(FRAME_SIZE),
+            "freeing misaligned frame 0x{addr:X}"
+        );
+        let first = addr / FRAME_SIZE;
+        for f in first..(first + count).min(self.frames) {
+            debug_assert!(
+                self.is_usable(f),
+                "freeing frame 0x{:X}, which was never usable memory",
+                f * FRAME_SIZE
+            );
+            if self.is_usable(f) {
+                self.clear(f);
+            }
+        }
+        self.hint = self.hint.min(first);
+    }
+
+    /// Adds `count` frames starting at `addr` to usable memory, and frees them.  For memory the memory map didn't
+    /// give as free at `init` (see `reclaim`).  Addresses outside of tracked memory are ignored.
+    pub fn release(&mut self, addr: usize, count: usize) {
+        let first = addr / FRAME_SIZE;
+        for f in first..(first + count).min(self.frames) {
+            // SAFETY: same as `is_set`.
+            unsafe { *self.usable_words().add(f / BITS) |= 1 << (f % BITS) };
+        }
+        self.free_contiguous(addr, count);
+    }
+
+    /// Marks every frame touching the given range as taken, whether or not it was free.
+    #[cfg(feature = "selftest")]
+    pub fn reserve(&mut self, start: usize, size: usize) {
+        let first = start / FRAME_SIZE;
+        let last = (start + size).div_ceil(FRAME_SIZE).min(self.frames);
+        for f in first..last {
+            self.set(f);
+        }
+    }
+}
+
+/// Seeds the global frame allocator.  Must be called once, before the heap is set up.
+pub fn init(mmap: &([(usize, usize); 256], usize), top: usize) {
+    FRAMES.lock().init(mmap, top);
+}
+
+pub fn alloc_frame() -> Option<usize> {
+    FRAMES.lock().alloc()
+}
+
+pub fn alloc_frames(count: usize, align: usize) -> Option<usize> {
+    FRAMES.lock().alloc_contiguous(count, align)
+}
+
+/// For the AP trampoline (see `smp`).
+#[cfg(target_arch = "x86_64")]
+pub fn alloc_low_frame() -> Option<usize> {
+    FRAMES.lock().alloc_low()
+}
+
+#[cfg(feature = "selftest")]
+pub fn alloc_huge_frame() -> Option<usize> {
+    FRAMES.lock().alloc_huge()
+}
+
+/// Nothing gives back single frames on 32-bit x86, where there's no paging.
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+pub fn free_frame(addr: usize) {
+    FRAMES.lock().free_contiguous(addr, 1);
+}
+
+pub fn free_frames(addr: usize, count: usize) {
+    FRAMES.lock().free_contiguous(addr, count);
+}
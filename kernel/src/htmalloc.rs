@@ -1,389 +1,484 @@
 //! **HyperText Markup Allocation System**
+//!
+//! Small objects (up to `MAX_SLAB_SIZE` bytes) come out of size-class slabs: each slab is a single frame cut into
+//! equal power-of-two blocks, so every block is naturally aligned to its own size.  Anything bigger, or anything
+//! wanting more alignment than the biggest class, goes straight to the frame allocator as a contiguous run.
+//!
+//! Counters for the heap are always kept (see `HTMAlloc::stats`).  Tracing is off by default; once turned on with
+//! `HTMAlloc::set_tracing`, every alloc, free and realloc is recorded with its callers, along with a table of the
+//! allocations that are still live, so leaks can be dumped with `HTMAlloc::dump_live`.
+//!
+//! The callers come from walking the frame pointers (the kernel is built with them forced on, see
+//! `.cargo/config.toml`).  The allocator is only ever called through liballoc's shims, so the first one or two are
+//! those; whoever actually allocated is further up.
 
-extern crate alloc;
-
-use alloc::vec::Vec;
+use crate::{
+    frame_alloc::{self, FRAME_SIZE},
+    sync::{SpinLock, TicketLock},
+    vmm::{phys_to_virt, virt_to_phys},
+};
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::{Cell, UnsafeCell},
+    fmt::{self, Write},
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-//const ARENA_SIZE: usize = 128 * 1024;
-//const MAX_SUPPORTED_ALIGN: usize = 4096;
-
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum MemoryPattern {
-    /// No overlapping
-    Separate,
-    /// Size changes, start point remains to original section
-    StartBranch,
-    /// Size changes, start point changes to given section
-    EndBranch,
-    /// Original section overlaps entire given section
-    NoChange,
-    /// Given section overlaps entire original section
-    Overwrite,
+/// Smallest block handed out (has to fit a free-list link).
+const MIN_SLAB_SIZE: usize = size_of::<usize>();
+/// Biggest block handed out by a slab; everything above is page-backed.
+pub const MAX_SLAB_SIZE: usize = 2048;
+/// Number of size classes: 8, 16, 32, ..., 2048 on 64-bit.
+const CLASS_COUNT: usize =
+    (MAX_SLAB_SIZE.trailing_zeros() - MIN_SLAB_SIZE.trailing_zeros() + 1) as usize;
+
+/// A free block inside a slab.
+struct FreeBlock {
+    next: *mut FreeBlock,
 }
 
-const fn end(start: usize, size: usize) -> usize {
-    start + size
+/// Where a layout is served from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class {
+    /// Index into the slab free lists.
+    Slab(usize),
+    /// Number of frames.
+    Pages(usize),
 }
-const fn endt(tuple: (usize, usize)) -> usize {
-    tuple.0 + tuple.1
+impl Class {
+    const fn of(layout: Layout) -> Self {
+        // A block of size N is always N-aligned, so the class has to cover both size and alignment.
+        let mut want = if layout.size() > layout.align() {
+            layout.size()
+        } else {
+            layout.align()
+        };
+        if want <= MAX_SLAB_SIZE {
+            if want < MIN_SLAB_SIZE {
+                want = MIN_SLAB_SIZE;
+            }
+            let want = want.next_power_of_two();
+            Class::Slab((want.trailing_zeros() - MIN_SLAB_SIZE.trailing_zeros()) as usize)
+        } else {
+            let pages = layout.size().div_ceil(FRAME_SIZE);
+            Class::Pages(if pages == 0 { 1 } else { pages })
+        }
+    }
+
+    const fn block_size(idx: usize) -> usize {
+        MIN_SLAB_SIZE << idx
+    }
 }
 
-/// Start branch means first (original) set starts first.
-const fn cmp_mem_sct(
-    original_start: usize,
-    original_size: usize,
-    given_start: usize,
-    given_size: usize,
-) -> MemoryPattern {
-    if original_start == given_start && original_size == given_size {
-        return MemoryPattern::NoChange;
+/// A snapshot of the heap counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    /// Bytes handed out and not yet freed, as requested by the callers.
+    pub in_use: usize,
+    /// Highest `in_use` seen so far.
+    pub peak: usize,
+    /// Bytes taken from the frame allocator, whether handed out or sitting in a slab.
+    pub reserved: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub reallocs: usize,
+}
+impl HeapStats {
+    /// Number of allocations still live.
+    pub const fn live(&self) -> usize {
+        self.allocs - self.frees
     }
 
-    let original_end = end(original_start, original_size);
-    let given_end = end(given_start, given_size);
-
-    if original_end < given_start || given_end < original_start {
-        MemoryPattern::Separate
-    } else if given_start == original_end {
-        MemoryPattern::StartBranch
-    } else if original_start == given_end {
-        MemoryPattern::EndBranch
-    } else if given_start >= original_start {
-        if given_end > original_end {
-            if original_start == given_start {
-                MemoryPattern::Overwrite
-            } else {
-                MemoryPattern::StartBranch
-            }
-        } else {
-            MemoryPattern::NoChange
+    /// Share of reserved memory not in use (rounding to block size, free slab blocks), in percent.
+    pub fn fragmentation(&self) -> usize {
+        (self.reserved.saturating_sub(self.in_use) * 100)
+            .checked_div(self.reserved)
+            .unwrap_or(0)
+    }
+}
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HEAP: {} bytes in use (peak {}), {} reserved, {}% fragmented, {} live, {} allocs / {} frees / {} reallocs",
+            self.in_use,
+            self.peak,
+            self.reserved,
+            self.fragmentation(),
+            self.live(),
+            self.allocs,
+            self.frees,
+            self.reallocs
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceKind {
+    Alloc,
+    Free,
+    /// `old` holds the pointer before the move (same as `ptr` if it didn't move).
+    Realloc {
+        old: usize,
+    },
+}
+
+/// One recorded heap operation.
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    pub ptr: usize,
+    pub size: usize,
+    /// Return addresses of the allocator call and its callers, innermost first.  0 past the end of the chain.
+    pub callers: Callers,
+}
+
+/// How many return addresses are kept per heap operation.
+const CALLER_DEPTH: usize = 4;
+pub type Callers = [usize; CALLER_DEPTH];
+
+/// Walks the frame pointers from the function this is inlined into, and returns where each frame returns to.
+///
+/// Stops at a null, misaligned or downward frame pointer, or one too far up to be on the same stack (a system call's
+/// frame points to the user's).
+#[inline(always)]
+fn callers() -> Callers {
+    /// Furthest apart two frames on one stack are taken to be.
+    const MAX_FRAME: usize = 64 * 1024;
+
+    let mut ret = [0; CALLER_DEPTH];
+    let mut fp: usize;
+    // SAFETY: only reads the frame pointer register.
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+        #[cfg(target_arch = "x86")]
+        core::arch::asm!("mov {}, ebp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    for slot in &mut ret {
+        if fp == 0 || !fp.is_multiple_of(align_of::<usize>()) {
+            break;
         }
-    } else if given_end <= original_end {
-        if original_end == given_end {
-            MemoryPattern::Overwrite
-        } else {
-            MemoryPattern::EndBranch
+        // SAFETY: every frame starts with the caller's frame pointer, followed by the return address; `fp` was
+        // checked to still be on this stack.
+        let (next, ra) = unsafe { (*(fp as *const usize), *(fp as *const usize).add(1)) };
+        *slot = ra;
+        if next <= fp || next - fp > MAX_FRAME {
+            break;
         }
-    } else {
-        MemoryPattern::Overwrite
+        fp = next;
     }
+    ret
 }
 
-/// The struct in place for global allocations for HTMOS.
-pub struct HTMAlloc {
-    mmap: Cell<([(usize, usize); 256], usize)>,
-    /// This holds any taken memory in the mmap.
-    ///
-    /// The first element is always an "Apocolyptic Expression" - an allocated chunk for this Vec itself.
-    taken: UnsafeCell<Vec<(usize, usize)>>,
+/// Writes `callers` the way the dumps show them: innermost first, separated by `<`.
+fn write_callers(out: &mut dyn Write, callers: &Callers) -> fmt::Result {
+    for (i, &c) in callers.iter().take_while(|&&c| c != 0).enumerate() {
+        if i > 0 {
+            out.write_str(" <")?;
+        }
+        write!(out, " 0x{c:016X}")?;
+    }
+    Ok(())
 }
-impl HTMAlloc {
-    pub const fn ginit() -> Self {
+
+/// How many of the last heap operations are kept.
+const TRACE_LEN: usize = 128;
+/// How many live allocations can be tracked at once while tracing.
+const LIVE_LEN: usize = 512;
+
+#[derive(Clone, Copy)]
+struct LiveEntry {
+    ptr: usize,
+    size: usize,
+    callers: Callers,
+}
+
+/// Everything recorded while tracing.  Fixed size, since the heap can't be used to trace itself.
+struct Trace {
+    events: [Option<TraceEvent>; TRACE_LEN],
+    /// Next slot in `events` to overwrite.
+    next: usize,
+    live: [LiveEntry; LIVE_LEN],
+    live_count: usize,
+    /// Allocations that didn't fit into `live`.
+    dropped: usize,
+}
+impl Trace {
+    const fn new() -> Self {
         Self {
-            mmap: Cell::new(([(0, 0); 256], 0)),
-            taken: UnsafeCell::new(Vec::new()), // I am so glad this won't allocate.
+            events: [None; TRACE_LEN],
+            next: 0,
+            live: [LiveEntry {
+                ptr: 0,
+                size: 0,
+                callers: [0; CALLER_DEPTH],
+            }; LIVE_LEN],
+            live_count: 0,
+            dropped: 0,
         }
     }
-    /// This will perform real-time action after the boot information is handled correctly.
-    ///
-    /// This will go through each memory chunk, parse all config tables given, and mark free as much as possible.
-    pub fn update(&self, mmap: ([(usize, usize); 256], usize)) {
-        self.mmap.set(mmap);
-
-        // That should be it for reserved memory.
-        // This will be an initialization of the 'taken' member of the HTMAlloc struct...scary stuff.
-        {
-            let (mmap, sz) = self.mmap.get();
-            for i in 0..sz {
-                if mmap[i].1 >= size_of::<(usize, usize)>() * 4096 {
-                    let ptr = mmap[i].0 as *mut (usize, usize);
-                    // SAFETY: writing on free memory.
-                    unsafe {
-                        ptr.write((mmap[i].0, size_of::<(usize, usize)>() * 4096));
-                    }
 
-                    self.set_taken(mmap[i].0, 1, 4096);
+    fn record(&mut self, event: TraceEvent) {
+        self.events[self.next] = Some(event);
+        self.next = (self.next + 1) % TRACE_LEN;
 
-                    break;
-                }
+        if let TraceKind::Free | TraceKind::Realloc { .. } = event.kind {
+            let old = match event.kind {
+                TraceKind::Realloc { old } => old,
+                _ => event.ptr,
+            };
+            // Allocations made before tracing started aren't in the table; that's fine.
+            if let Some(i) = self.live[..self.live_count]
+                .iter()
+                .position(|e| e.ptr == old)
+            {
+                self.live_count -= 1;
+                self.live[i] = self.live[self.live_count];
             }
         }
-
-        /*
-        let mut cfg_ptr = unsafe { &mut *(bi.more_info as *mut SystemTable) }.configuration_table;
-        let cfg_count = unsafe { &mut *(bi.more_info as *mut SystemTable) }.number_of_table_entries;
-        for i in 0..cfg_count {
-            let cfg = unsafe { &*cfg_ptr };
-            println!(
-                "{}  0x{:16X}",
-                str::from_utf8(&crate::cfg_tbl::guid_utf8_upper(cfg.vendor_guid)).unwrap(),
-                cfg.vendor_table as usize
-            );
-
-            if let Ok(v) = FirmwareTable::parse(cfg.vendor_guid, cfg.vendor_table) {
-                match v {
-                    FirmwareTable::LZMACustomDecompress(lzma) => {
-                        println!(
-                            "{}",
-                            str::from_utf8(&crate::cfg_tbl::guid_utf8_upper(lzma.guid)).unwrap()
-                        );
-                        println!("0x{:016X}", lzma.compressed_data.len());
-                    }
-                }
+        if let TraceKind::Alloc | TraceKind::Realloc { .. } = event.kind {
+            if self.live_count < LIVE_LEN {
+                self.live[self.live_count] = LiveEntry {
+                    ptr: event.ptr,
+                    size: event.size,
+                    callers: event.callers,
+                };
+                self.live_count += 1;
+            } else {
+                self.dropped += 1;
             }
-
-            cfg_ptr = unsafe { (cfg_ptr as *const u8).add(size_of::<ConfigurationTable>()) }
-                as *mut ConfigurationTable;
         }
-        */
     }
+}
 
-    fn get_taken_mut(&self) -> &mut Vec<(usize, usize)> {
-        // SAFETY: pointer is always good.
-        unsafe { &mut *self.taken.get() }
-    }
+/// Free list heads, one per size class.
+struct Slabs([*mut FreeBlock; CLASS_COUNT]);
+// SAFETY: the blocks are only reached through the lock around this.
+unsafe impl Send for Slabs {}
 
-    fn get_taken(&self) -> &Vec<(usize, usize)> {
-        // SAFETY: pointer is always good.
-        unsafe { &*self.taken.get() }
+/// The struct in place for global allocations for HTMOS.
+pub struct HTMAlloc {
+    slabs: TicketLock<Slabs>,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    reserved: AtomicUsize,
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+    reallocs: AtomicUsize,
+    tracing: AtomicBool,
+    trace: SpinLock<Trace>,
+}
+impl HTMAlloc {
+    pub const fn ginit() -> Self {
+        Self {
+            slabs: TicketLock::new(Slabs([null_mut(); CLASS_COUNT])),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(0),
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            reallocs: AtomicUsize::new(0),
+            tracing: AtomicBool::new(false),
+            trace: SpinLock::new(Trace::new()),
+        }
     }
 
-    // WILL IGNORE IF LENGTH AND/OR CAPACITY ARE 0.
-    fn set_taken(&self, ptr: usize, length: usize, capacity: usize) {
-        if length == 0 || capacity == 0 {
-            return;
-        }
-        // SAFETY: pointer is always good.
-        unsafe {
-            self.taken
-                .get()
-                .write(Vec::from_raw_parts(ptr as *mut _, length, capacity));
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            reserved: self.reserved.load(Ordering::Relaxed),
+            allocs: self.allocs.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            reallocs: self.reallocs.load(Ordering::Relaxed),
         }
     }
 
-    fn valid_memory_check(&self, ptr: usize, size: usize) -> bool {
-        let (mmap, sz) = self.mmap.get();
-        for i in 0..sz {
-            match cmp_mem_sct(mmap[i].0, mmap[i].1, ptr, size) {
-                MemoryPattern::StartBranch => return false,
-                MemoryPattern::EndBranch => return false,
-                MemoryPattern::Overwrite => return false,
-                MemoryPattern::NoChange => return true,
-                MemoryPattern::Separate => {}
-            }
+    pub fn tracing(&self) -> bool {
+        self.tracing.load(Ordering::Relaxed)
+    }
+
+    /// Turns tracing on or off.  Turning it on starts from an empty trace.
+    pub fn set_tracing(&self, on: bool) {
+        if on {
+            *self.trace.lock() = Trace::new();
         }
-        false // All were seperate in this case
+        self.tracing.store(on, Ordering::Relaxed);
     }
 
-    /// Returns a pointer to the next valid available section of memory, given that the returned pointer must have an equal or higher value than the given pointer and the given size fits.
-    ///
-    /// If the given successfully checked pointer is in the middle of a memory section, the return value is the given pointer.
-    fn next_valid_memory_ptr(&self, from_ptr: usize, with_size: usize) -> usize {
-        if with_size == 0 {
-            return 0;
+    /// Writes every allocation made while tracing that hasn't been freed yet.
+    pub fn dump_live(&self, out: &mut dyn Write) -> fmt::Result {
+        let trace = self.trace.lock();
+        writeln!(out, "LIVE ALLOCATIONS: {}", trace.live_count)?;
+        for e in &trace.live[..trace.live_count] {
+            write!(out, "  0x{:016X} {:>8} bytes from", e.ptr, e.size)?;
+            write_callers(out, &e.callers)?;
+            writeln!(out)?;
         }
-        let (mmap, sz) = self.mmap.get();
-        for i in 0..sz {
-            if from_ptr >= mmap[i].0 && with_size <= mmap[i].1 {
-                match cmp_mem_sct(mmap[i].0, mmap[i].1, from_ptr, with_size) {
-                    MemoryPattern::NoChange => return from_ptr,
-                    MemoryPattern::Separate => return mmap[i].0,
-                    _ => {}
-                }
-            }
+        if trace.dropped > 0 {
+            writeln!(out, "  ({} more not tracked, table full)", trace.dropped)?;
         }
-        0
+        Ok(())
     }
 
-    /// Returns the pointer to the next available section of memory not taken, given the size requirement.
-    ///
-    /// Returns 0 if size == 0 or the update function hasn't been called yet.
-    fn next_available_slot(&self, size: usize) -> usize {
-        let v = self.get_taken();
-        if size == 0 || v.len() == 0 {
-            return 0;
-        } else if v.len() > 2 {
-            self.taken_organize();
-        }
-        for i in 0..v.len() {
-            let pstart = endt(v[i]);
-            if i + 1 < v.len() {
-                if pstart + size <= v[i + 1].0 && self.valid_memory_check(pstart, size) {
-                    return pstart;
-                }
-            } else {
-                if self.valid_memory_check(pstart, size) {
-                    return pstart;
-                } else {
-                    return self.next_valid_memory_ptr(pstart, size);
+    /// Writes the last recorded heap operations, oldest first.
+    pub fn dump_trace(&self, out: &mut dyn Write) -> fmt::Result {
+        let trace = self.trace.lock();
+        let (newer, older) = trace.events.split_at(trace.next);
+        for e in older.iter().chain(newer).flatten() {
+            match e.kind {
+                TraceKind::Alloc => write!(out, "  ALLOC   0x{:016X}", e.ptr)?,
+                TraceKind::Free => write!(out, "  FREE    0x{:016X}", e.ptr)?,
+                TraceKind::Realloc { old } => {
+                    write!(out, "  REALLOC 0x{old:016X} -> 0x{:016X}", e.ptr)?
                 }
             }
+            write!(out, " {:>8} bytes from", e.size)?;
+            write_callers(out, &e.callers)?;
+            writeln!(out)?;
         }
-        unreachable!(
-            "next_available_slot: illegal length mathematics (vec length: {})",
-            v.len()
-        );
+        Ok(())
     }
 
-    /// Returns an updated value to fit inside the fixed memory map.  Returns (0, 0) if no overlaps are present.
-    ///
-    /// **NOTE**: If the given section spreads over multiple memory map sections, it will go through with the first instance it finds.
-    fn reduce_size_fit_next_available_slot(&self, start: usize, size: usize) -> (usize, usize) {
-        let (mmap, sz) = self.mmap.get();
-        for i in 0..sz {
-            match cmp_mem_sct(mmap[i].0, mmap[i].1, start, size) {
-                MemoryPattern::StartBranch => return (start, endt(mmap[i]) - start),
-                MemoryPattern::EndBranch => return (mmap[i].0, end(start, size) - mmap[i].0),
-                MemoryPattern::Overwrite => return (mmap[i].0, mmap[i].1),
-                MemoryPattern::NoChange => return (start, size),
-                MemoryPattern::Separate => {}
-            }
+    /// Records a heap operation if tracing is on.  Inlined, so `callers` starts at the allocator method.
+    #[inline(always)]
+    fn trace(&self, kind: TraceKind, ptr: *mut u8, size: usize) {
+        if self.tracing.load(Ordering::Relaxed) {
+            self.trace.lock().record(TraceEvent {
+                kind,
+                ptr: ptr as usize,
+                size,
+                callers: callers(),
+            });
         }
-        (0, 0)
     }
 
-    /// Fixes the order in 'taken'.  Returns true if changes happened.
-    fn taken_organize(&self) -> bool {
-        let v = self.get_taken_mut();
-        if !v[1..].is_sorted_by(|(v0, _), (v1, _)| v0 < v1) {
-            v[1..].sort_unstable_by(|(v0, _), (v1, _)| v0.cmp(v1));
-            true
-        } else {
-            false
-        }
+    fn add_in_use(&self, size: usize) {
+        let now = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(now, Ordering::Relaxed);
     }
 
-    /// Returns the index of the given tuple (start and size must match exactly).  Returns None if not found.
-    fn taken_match(&self, start: usize, size: usize) -> Option<usize> {
-        self.taken_organize();
-        let v = self.get_taken();
-        if let Ok(i) = v[1..].binary_search_by(|(v, _)| v.cmp(&start)) {
-            if v[i].1 == size { Some(i) } else { None }
-        } else {
-            None
+    /// Cuts a fresh frame into blocks for the given class.  Returns false if no frame was available.
+    fn refill(&self, slabs: &mut Slabs, idx: usize) -> bool {
+        let Some(frame) = frame_alloc::alloc_frame() else {
+            return false;
+        };
+        self.reserved.fetch_add(FRAME_SIZE, Ordering::Relaxed);
+
+        let size = Class::block_size(idx);
+        let head = &mut slabs.0[idx];
+        // Thread the blocks back to front so the lowest address is handed out first.
+        for off in (0..FRAME_SIZE).step_by(size).rev() {
+            let block = phys_to_virt(frame + off) as *mut FreeBlock;
+            // SAFETY: the frame was just handed to us.
+            unsafe { block.write(FreeBlock { next: *head }) };
+            *head = block;
         }
+        true
     }
 
-    /// Returns the index of the given start ptr.  Returns None if not found.
-    fn taken_match_start(&self, start: usize) -> Option<usize> {
-        self.taken_organize();
-        let v = self.get_taken();
-        if let Ok(i) = v[1..].binary_search_by(|(v, _)| v.cmp(&start)) {
-            Some(i)
-        } else {
-            None
+    fn slab_alloc(&self, idx: usize) -> *mut u8 {
+        let mut slabs = self.slabs.lock();
+        if slabs.0[idx].is_null() && !self.refill(&mut slabs, idx) {
+            return null_mut();
         }
+        let block = slabs.0[idx];
+        // SAFETY: blocks on a free list are always valid.
+        slabs.0[idx] = unsafe { (*block).next };
+        block as *mut u8
     }
 
-    /// Returns the number of additional bytes available for reallocation.
-    fn taken_more_size_available(&self, mstart: usize, msize: usize) -> usize {
-        self.taken_organize();
-        if let Some(i) = self.taken_match(mstart, msize) {
-            let v = self.get_taken();
-            let pstart = end(mstart, msize);
-            let psz = v[i + 1].0 - pstart;
-            if psz > 0 {
-                let (good_start, good_size) = self.reduce_size_fit_next_available_slot(pstart, psz);
-                if pstart == good_start { good_size } else { 0 }
-            } else {
-                0
+    fn slab_dealloc(&self, idx: usize, ptr: *mut u8) {
+        let mut slabs = self.slabs.lock();
+        let block = ptr as *mut FreeBlock;
+        // SAFETY: the block belonged to this class and is now unused.
+        unsafe { block.write(FreeBlock { next: slabs.0[idx] }) };
+        slabs.0[idx] = block;
+    }
+
+    fn raw_alloc(&self, layout: Layout) -> *mut u8 {
+        match Class::of(layout) {
+            Class::Slab(idx) => self.slab_alloc(idx),
+            Class::Pages(count) => {
+                match frame_alloc::alloc_frames(count, layout.align().max(FRAME_SIZE)) {
+                    Some(addr) => {
+                        self.reserved
+                            .fetch_add(count * FRAME_SIZE, Ordering::Relaxed);
+                        phys_to_virt(addr) as *mut u8
+                    }
+                    None => null_mut(),
+                }
             }
-        } else {
-            0
         }
     }
 
-    /// Returns the size of the allocated pointer.  Returns None if not found.
-    fn get_size(&self, start: usize) -> Option<usize> {
-        let idx = self.taken_match_start(start)?;
-        Some(self.get_taken()[idx].1)
+    fn raw_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Class::of(layout) {
+            Class::Slab(idx) => self.slab_dealloc(idx, ptr),
+            Class::Pages(count) => {
+                frame_alloc::free_frames(virt_to_phys(ptr as usize), count);
+                self.reserved
+                    .fetch_sub(count * FRAME_SIZE, Ordering::Relaxed);
+            }
+        }
     }
 
-    /// Deallocates memory with just the given location.  The default alignment for the layout is 8.
-    pub unsafe fn dealloc_ptr_only(&self, ptr: usize) {
-        unsafe {
-            let layout = Layout::from_size_align_unchecked(self.get_size(ptr).unwrap(), 8);
-            self.dealloc(ptr as _, layout);
+    fn raw_realloc(&self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> *mut u8 {
+        match (Class::of(layout), Class::of(new_layout)) {
+            // Same block, nothing to do.
+            (Class::Slab(a), Class::Slab(b)) if a == b => return ptr,
+            // Shrinking a page run: hand the tail back.
+            (Class::Pages(a), Class::Pages(b)) if b <= a => {
+                if b < a {
+                    frame_alloc::free_frames(virt_to_phys(ptr as usize) + b * FRAME_SIZE, a - b);
+                    self.reserved
+                        .fetch_sub((a - b) * FRAME_SIZE, Ordering::Relaxed);
+                }
+                return ptr;
+            }
+            _ => {}
+        }
+
+        let nptr = self.raw_alloc(new_layout);
+        if !nptr.is_null() {
+            // SAFETY: both blocks are live and at least this big.
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, nptr, layout.size().min(new_layout.size()))
+            };
+            self.raw_dealloc(ptr, layout);
         }
+        nptr
     }
 }
 
-// SAFETY: frick you.
-unsafe impl Sync for HTMAlloc {}
 unsafe impl GlobalAlloc for HTMAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.pad_to_align().size();
-        //crate::println!("alloc call size: {size}");
-        let ptr = self.next_available_slot(size);
-        if ptr == 0 {
-            return 0 as *mut _;
-        }
-        self.get_taken_mut().push((ptr, size));
-        self.taken_organize();
-        ptr as *mut u8
-    }
-    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        let ptr = unsafe { self.alloc(layout) };
-        // SAFETY: writing 0's to processing memory.
-        unsafe {
-            ptr.write_bytes(0, layout.pad_to_align().size());
+        let ptr = self.raw_alloc(layout);
+        if !ptr.is_null() {
+            self.allocs.fetch_add(1, Ordering::Relaxed);
+            self.add_in_use(layout.size());
+            self.trace(TraceKind::Alloc, ptr, layout.size());
         }
         ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (start, size) = (ptr as usize, layout.pad_to_align().size());
-        let v = self.get_taken_mut();
-        if let Some(i) = self.taken_match(start, size) {
-            v.remove(i);
-        }
+        self.raw_dealloc(ptr, layout);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.trace(TraceKind::Free, ptr, layout.size());
     }
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let old_size = layout.pad_to_align().size();
-        let new_size = Layout::from_size_align(new_size, layout.align())
-            .unwrap()
-            .pad_to_align()
-            .size();
-
-        //crate::println!("from {old_size} to {new_size}");
-        if old_size >= new_size {
-            if let Some(i) = self.taken_match_start(ptr as usize) {
-                self.get_taken_mut()[i] = (ptr as usize, new_size);
-            }
-            return ptr;
-        }
-        if unsafe { &*self.taken.get() }.as_ptr() as usize == ptr as usize {
-            // The 'taken' member called.  This will be some sort of "fakeout" or something.
-            let add_size = self.taken_more_size_available(ptr as usize, new_size);
-            if old_size + add_size >= new_size {
-                self.get_taken_mut()[0] = (ptr as usize, new_size);
-                ptr
-            } else {
-                let &last = self.get_taken().last().unwrap();
-                let good = self.next_valid_memory_ptr(endt(last), new_size);
-                self.get_taken_mut()[0] = (good, new_size);
-                good as *mut u8
-            }
-        } else {
-            unsafe {
-                // So, this is not recommended to do, but until I run into actual issues with this as the cause, I will keep this as is.
-                let add_size = self.taken_more_size_available(ptr as usize, new_size);
-                if old_size + add_size >= new_size {
-                    if let Some(i) = self.taken_match_start(ptr as usize) {
-                        self.get_taken_mut()[i] = (ptr as usize, new_size);
-                    }
-                    ptr
-                } else {
-                    let nptr = self
-                        .alloc_zeroed(Layout::from_size_align(new_size, layout.align()).unwrap());
-                    core::ptr::copy_nonoverlapping(ptr as *const _, nptr, old_size);
-                    self.dealloc(ptr, layout);
-                    nptr
-                }
-            }
+        // SAFETY: the caller guarantees new_size rounded up to align does not overflow.
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+
+        let nptr = self.raw_realloc(ptr, layout, new_layout);
+        if !nptr.is_null() {
+            self.reallocs.fetch_add(1, Ordering::Relaxed);
+            self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+            self.add_in_use(new_size);
+            self.trace(TraceKind::Realloc { old: ptr as usize }, nptr, new_size);
         }
+        nptr
     }
 }
@@ -0,0 +1,217 @@
+//! **HyperText Markup IRQ**
+//!
+//! Legacy (ISA) IRQs, whichever interrupt controllers deliver them.  `init` uses the APICs where there are any, and
+//! falls back to the 8259 PICs where there aren't: no local APIC (CPUID says), no MADT, or a MADT without an I/O APIC
+//! on a PC/AT-compatible machine.  Drivers only see `route`, `mask` and `eoi`, which work the same either way.
+//!
+//! The same goes for the architectures: both x86 flavors lay out their hardware vectors as below, hand
+//! `init_devices` the MADT, and send every IRQ vector to `handle`.
+
+use crate::{
+    apic::{self, Polarity, Trigger},
+    pic, println,
+};
+use core::fmt;
+use spin::Once;
+
+/// Where the PICs put IRQ 0.  Every IRQ is routed to `PIC_BASE + irq` with the APICs too, so each vector means the
+/// same IRQ with either controller.
+pub const PIC_BASE: u8 = 32;
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+/// Where the local APIC sends spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 255;
+
+/// The vector IRQ `irq` arrives on.
+pub const fn vector(irq: u8) -> u8 {
+    PIC_BASE + irq
+}
+
+/// What delivers IRQs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Controller {
+    /// The I/O APICs, to the local APICs.
+    Apic,
+    /// The 8259 PICs, for the given reason.
+    Pic(&'static str),
+}
+
+static CONTROLLER: Once<Controller> = Once::new();
+
+/// Sets up the interrupt controllers, from the MADT at (virtual address) `madt` if there is one.  The PICs (which are
+/// still there on a PC/AT-compatible machine, APICs or not) are remapped to `PIC_BASE`.
+pub fn init(madt: Option<usize>) -> Result<Controller, &'static str> {
+    if CONTROLLER.is_completed() {
+        return Err("interrupt controllers already set up");
+    }
+
+    let controller = match madt {
+        None => Controller::Pic("no MADT"),
+        Some(_) if !apic::supported() => Controller::Pic("no local APIC"),
+        Some(madt) => match apic::init(madt, SPURIOUS_VECTOR) {
+            Ok(model) => {
+                if model.pcat_compat {
+                    pic::init(PIC_BASE)?;
+                }
+                Controller::Apic
+            }
+            // Only a PC/AT-compatible machine is sure to have the PICs to fall back on.
+            Err(e) if apic::InterruptModel::parse(madt).pcat_compat => Controller::Pic(e),
+            Err(e) => return Err(e),
+        },
+    };
+    if controller != Controller::Apic {
+        pic::init(PIC_BASE)?;
+    }
+    Ok(*CONTROLLER.call_once(|| controller))
+}
+
+/// Where `route` sent an IRQ.
+#[derive(Clone, Copy, Debug)]
+pub struct Route {
+    pub irq: u8,
+    pub vector: u8,
+    /// Where the I/O APIC gets it, and how; None with the PICs.
+    pub gsi: Option<(u32, Polarity, Trigger)>,
+}
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gsi {
+            Some((gsi, polarity, trigger)) => write!(
+                f,
+                "IRQ {} -> GSI {gsi} ({polarity:?}, {trigger:?}), vector {}",
+                self.irq, self.vector
+            ),
+            None => write!(f, "IRQ {} (8259), vector {}", self.irq, self.vector),
+        }
+    }
+}
+
+/// Delivers ISA `irq` to this CPU as `vector`.  The PICs can't pick vectors one by one, so with them, `vector` has to
+/// be the one `irq` was remapped to.
+pub fn route(irq: u8, vector: u8) -> Result<Route, &'static str> {
+    match CONTROLLER.get().ok_or("interrupt controllers not set up")? {
+        Controller::Apic => {
+            apic::route_irq(irq, vector)?;
+            Ok(Route {
+                irq,
+                vector,
+                gsi: apic::model().map(|m| m.resolve_irq(irq)),
+            })
+        }
+        Controller::Pic(_) => {
+            if pic::base().map(|base| base + irq) != Some(vector) {
+                return Err("the PICs can only deliver an IRQ on its own vector");
+            }
+            pic::unmask(irq)?;
+            Ok(Route {
+                irq,
+                vector,
+                gsi: None,
+            })
+        }
+    }
+}
+
+/// Stops `irq` getting through again.
+pub fn mask(irq: u8) -> Result<(), &'static str> {
+    match CONTROLLER.get().ok_or("interrupt controllers not set up")? {
+        Controller::Apic => {
+            let model = apic::model().ok_or("interrupt controllers not set up")?;
+            apic::mask_gsi(model.resolve_irq(irq).0)
+        }
+        Controller::Pic(_) => pic::mask(irq),
+    }
+}
+
+/// Signals the end of `irq`.  For the end of its interrupt handler.
+pub fn eoi(irq: u8) {
+    match CONTROLLER.get() {
+        Some(Controller::Apic) => apic::eoi(),
+        Some(Controller::Pic(_)) => pic::eoi(irq),
+        None => {}
+    }
+}
+
+/// Sets up the interrupt controllers (see `init`) and the PS/2 keyboard and mouse, and routes their and the serial
+/// console's IRQs.  Turning interrupts on is up to the caller.
+pub fn init_devices(madt: Option<usize>) -> Result<(), &'static str> {
+    match init(madt)? {
+        Controller::Apic => {
+            let model = apic::model().ok_or("interrupt controllers not set up")?;
+            // Could be plugged in later; not started.
+            let hot_pluggable = model.cpus.iter().filter(|c| !c.enabled && c.online_capable);
+            println!(
+                "APIC: local APIC at 0x{:08X} ({} mode), {} I/O APIC(s), {} CPU(s) (+{} hot-pluggable), {} override(s)",
+                model.lapic_phys,
+                match model.lapic {
+                    apic::LocalApic::X2Apic => "x2APIC",
+                    apic::LocalApic::XApic(_) => "xAPIC",
+                },
+                model.io_apics.len(),
+                model.cpus.iter().filter(|c| c.enabled).count(),
+                hot_pluggable.count(),
+                model.overrides.len()
+            );
+        }
+        Controller::Pic(why) => {
+            println!(
+                "PIC: 8259s at vectors {}-{} ({why})",
+                PIC_BASE,
+                PIC_BASE + 15
+            );
+        }
+    }
+
+    // Plenty of machines have no PS/2 controller at all; that's no reason not to boot.
+    let ps2 = crate::kb_mouse::init().unwrap_or_else(|e| {
+        println!("PS/2: {e}");
+        crate::kb_mouse::Ps2 {
+            keyboard: false,
+            mouse: None,
+        }
+    });
+    if ps2.keyboard {
+        println!("KEYBOARD: {}", route(KEYBOARD_IRQ, vector(KEYBOARD_IRQ))?);
+    }
+    if let Some(kind) = ps2.mouse {
+        println!("MOUSE: {kind:?}, {}", route(MOUSE_IRQ, vector(MOUSE_IRQ))?);
+    }
+    if let Some(uart) = crate::serial::console() {
+        let irq = uart.port.irq();
+        let route = route(irq, vector(irq))?;
+        crate::serial::enable_rx_interrupt();
+        println!("SERIAL: {route}");
+    }
+    Ok(())
+}
+
+/// Handles IRQ `irq`, EOI included.  For the interrupt handler of `vector(irq)`, whatever the architecture.
+pub fn handle(irq: u8) {
+    let serial = crate::serial::console().map(|uart| uart.port.irq());
+    match irq {
+        // Nothing is routed to IRQ 7 or 15, so only the PICs raise them: spurious ones, which they do masked or not.
+        7 | 15 if pic::spurious(irq) => return,
+        TIMER_IRQ => crate::time::tick(),
+        KEYBOARD_IRQ => {
+            crate::kb_mouse::keyboard_byte();
+            crate::executor::KEYBOARD.wake_all();
+        }
+        MOUSE_IRQ => {
+            crate::kb_mouse::mouse_byte();
+            crate::executor::MOUSE.wake_all();
+        }
+        _ if Some(irq) == serial => {
+            crate::serial::interrupt();
+            crate::executor::SERIAL.wake_all();
+        }
+        // Nothing handles it, so nothing will quiet it; a level-triggered one would come straight back.
+        _ => {
+            let _ = mask(irq);
+        }
+    }
+    eoi(irq);
+    // Only once the EOI is out: the next thread may not return here for a while.
+    crate::sched::preempt();
+}
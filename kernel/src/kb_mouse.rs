@@ -0,0 +1,599 @@
+//! **HyperText Markup Keyboard & Mouse**
+//!
+//! Driver for the i8042 PS/2 controller: the keyboard on its first port and the mouse on its second.  `init` tests
+//! and enables both ports, resets both devices, and finds out what kind of mouse is there (plain, with a wheel, or
+//! with a wheel and two extra buttons, as the IntelliMouse extensions go).  After that, the interrupt handlers feed the
+//! bytes coming in through `keyboard_byte` and `mouse_byte`; key presses come out of `read_key` and mouse packets
+//! out of `poll_mouse`, as events.  For plain text, `read_char` takes the serial console as a keyboard too.
+//!
+//! The keyboard's interrupt handler only queues raw scancodes; they're decoded when read, with whichever layout
+//! (`set_layout`) and scancode set (`set_scancode_set`) are selected.  The keyboard itself always sends set 2; for set
+//! 1, the controller translates.  Ctrl+Alt+F1 to F10 pick a layout, in the order `Layout` lists them, and Ctrl+Alt+F11
+//! and F12 scancode set 1 and 2.  Ctrl+Alt+T traces the heap (see `trace_heap`) and Ctrl+Alt+Del restarts the machine.
+
+use crate::{
+    port_io::{inb, outb},
+    sync::{ByteRing, SpinLock},
+};
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{
+    DecodedKey, HandleControl, KeyCode, KeyState, Modifiers, PS2Keyboard, ScancodeSet1,
+    ScancodeSet2,
+    layouts::{self, AnyLayout},
+};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+const CMD_WRITE_PORT2: u8 = 0xD4;
+
+const CONFIG_IRQ1: u8 = 1 << 0;
+const CONFIG_IRQ12: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_OFF: u8 = 1 << 5;
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const DEV_RESET: u8 = 0xFF;
+const DEV_SET_DEFAULTS: u8 = 0xF6;
+const DEV_ENABLE: u8 = 0xF4;
+const DEV_SET_SAMPLE_RATE: u8 = 0xF3;
+const DEV_GET_ID: u8 = 0xF2;
+const DEV_ACK: u8 = 0xFA;
+const DEV_SELF_TEST_OK: u8 = 0xAA;
+
+/// How long to wait for the controller, in status polls (roughly a microsecond each).  Devices can take most of a
+/// second to reset.
+const TIMEOUT: usize = 1_000_000;
+
+/// Waits until the controller has a byte for us, and reads it.
+fn read() -> Result<u8, &'static str> {
+    for _ in 0..TIMEOUT {
+        if inb(STATUS) & STATUS_OUTPUT_FULL != 0 {
+            return Ok(inb(DATA));
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller timed out")
+}
+
+/// Waits until the controller can take a byte, and writes it to `port`.
+fn write(port: u16, value: u8) -> Result<(), &'static str> {
+    for _ in 0..TIMEOUT {
+        if inb(STATUS) & STATUS_INPUT_FULL == 0 {
+            outb(port, value);
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller timed out")
+}
+
+fn command(cmd: u8) -> Result<(), &'static str> {
+    write(COMMAND, cmd)
+}
+
+/// Throws away whatever the controller still holds.
+fn flush() {
+    while inb(STATUS) & STATUS_OUTPUT_FULL != 0 {
+        inb(DATA);
+    }
+}
+
+/// Sends a byte to the device on `port` (1 or 2) and waits for it to acknowledge.
+fn send(port: u8, byte: u8) -> Result<(), &'static str> {
+    if port == 2 {
+        command(CMD_WRITE_PORT2)?;
+    }
+    write(DATA, byte)?;
+    match read()? {
+        DEV_ACK => Ok(()),
+        _ => Err("PS/2 device didn't acknowledge"),
+    }
+}
+
+/// Resets the device on `port`.
+fn reset(port: u8) -> Result<(), &'static str> {
+    send(port, DEV_RESET)?;
+    match read()? {
+        DEV_SELF_TEST_OK => Ok(()),
+        _ => Err("PS/2 device failed its self test"),
+    }
+}
+
+fn mouse_id() -> Result<u8, &'static str> {
+    send(2, DEV_GET_ID)?;
+    read()
+}
+
+/// Sets the mouse's sample rate to each of `rates` in turn (the IntelliMouse knock sequences) and reads its ID.
+fn knock(rates: [u8; 3]) -> Result<u8, &'static str> {
+    for rate in rates {
+        send(2, DEV_SET_SAMPLE_RATE)?;
+        send(2, rate)?;
+    }
+    mouse_id()
+}
+
+/// What kind of mouse is plugged in, by the ID it reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// ID 0: three buttons, 3-byte packets.
+    Standard,
+    /// ID 3: a wheel too, 4-byte packets.
+    Wheel,
+    /// ID 4: a wheel and buttons 4 and 5, 4-byte packets.
+    FiveButton,
+}
+impl MouseKind {
+    const fn packet_len(self) -> usize {
+        match self {
+            Self::Standard => 3,
+            Self::Wheel | Self::FiveButton => 4,
+        }
+    }
+}
+
+/// Keyboard layouts `read_key` can decode with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Azerty,
+    Colemak,
+    Dvorak104,
+    DvorakProgrammer104,
+    Jis109,
+    No105,
+    FiSe105,
+}
+impl Layout {
+    const fn any(self) -> AnyLayout {
+        match self {
+            Self::Us104 => AnyLayout::Us104Key(layouts::Us104Key),
+            Self::Uk105 => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Self::De105 => AnyLayout::De105Key(layouts::De105Key),
+            Self::Azerty => AnyLayout::Azerty(layouts::Azerty),
+            Self::Colemak => AnyLayout::Colemak(layouts::Colemak),
+            Self::Dvorak104 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+            Self::DvorakProgrammer104 => AnyLayout::DVP104Key(layouts::DVP104Key),
+            Self::Jis109 => AnyLayout::Jis109Key(layouts::Jis109Key),
+            Self::No105 => AnyLayout::No105Key(layouts::No105Key),
+            Self::FiSe105 => AnyLayout::FiSe105Key(layouts::FiSe105Key),
+        }
+    }
+}
+
+/// Which scancodes `read_key` expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// What the controller makes of set 2 when translating; the default.
+    Set1,
+    /// What the keyboard actually sends.
+    Set2,
+}
+
+/// A key going down or up.
+#[derive(Debug, Clone)]
+pub struct KeyInput {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// What the key means in the current layout.  Only for key-downs.
+    pub key: Option<DecodedKey>,
+    /// Modifiers and toggles, with this event taken into account.
+    pub modifiers: Modifiers,
+}
+
+/// The scancode decoder, for either set.
+enum Decoder {
+    Set1(PS2Keyboard<AnyLayout, ScancodeSet1>),
+    Set2(PS2Keyboard<AnyLayout, ScancodeSet2>),
+}
+impl Decoder {
+    const fn new(set: ScancodeSet, layout: Layout) -> Self {
+        match set {
+            ScancodeSet::Set1 => Self::Set1(PS2Keyboard::new(
+                ScancodeSet1::new(),
+                layout.any(),
+                HandleControl::Ignore,
+            )),
+            ScancodeSet::Set2 => Self::Set2(PS2Keyboard::new(
+                ScancodeSet2::new(),
+                layout.any(),
+                HandleControl::Ignore,
+            )),
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<KeyInput> {
+        match self {
+            Self::Set1(keyboard) => Self::decode_with(keyboard, byte),
+            Self::Set2(keyboard) => Self::decode_with(keyboard, byte),
+        }
+    }
+
+    fn decode_with<S: pc_keyboard::ScancodeSet>(
+        keyboard: &mut PS2Keyboard<AnyLayout, S>,
+        byte: u8,
+    ) -> Option<KeyInput> {
+        let event = keyboard.add_byte(byte).ok()??;
+        let (code, state) = (event.code, event.state);
+        let key = keyboard
+            .process_keyevent(event)
+            .filter(|_| state != KeyState::Up);
+        Some(KeyInput {
+            code,
+            state,
+            key,
+            modifiers: keyboard.get_modifiers().clone(),
+        })
+    }
+}
+
+struct Keyboard {
+    set: ScancodeSet,
+    layout: Layout,
+    decoder: Decoder,
+}
+
+/// Scancodes from the interrupt handler, not yet decoded.
+static SCANCODES: ByteRing<256> = ByteRing::new();
+/// Also makes sure only one reader pops from `SCANCODES` at a time.
+static KEYBOARD: SpinLock<Keyboard> = SpinLock::new(Keyboard {
+    set: ScancodeSet::Set1,
+    layout: Layout::Us104,
+    decoder: Decoder::new(ScancodeSet::Set1, Layout::Us104),
+});
+/// The controller configuration `init` settled on.
+static CONFIG: AtomicU8 = AtomicU8::new(0);
+
+/// What `init` found.
+#[derive(Debug, Clone, Copy)]
+pub struct Ps2 {
+    pub keyboard: bool,
+    pub mouse: Option<MouseKind>,
+}
+
+/// Mouse buttons, one bit each.
+pub mod buttons {
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+    pub const BUTTON4: u8 = 1 << 3;
+    pub const BUTTON5: u8 = 1 << 4;
+}
+
+/// One mouse packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    /// Movement to the right.
+    pub dx: i16,
+    /// Movement up (PS/2 counts up as positive, unlike the screen).
+    pub dy: i16,
+    /// Wheel clicks; positive is towards the user.
+    pub wheel: i8,
+    /// Buttons held down (see `buttons`).
+    pub buttons: u8,
+}
+
+/// A fixed-size queue an interrupt handler can push to without allocating.  When full, the oldest entry goes.
+pub struct EventQueue<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+}
+
+struct Mouse {
+    kind: Option<MouseKind>,
+    packet: [u8; 4],
+    len: usize,
+    events: EventQueue<MouseEvent, 64>,
+}
+
+static MOUSE: SpinLock<Mouse> = SpinLock::new(Mouse {
+    kind: None,
+    packet: [0; 4],
+    len: 0,
+    events: EventQueue::new(),
+});
+
+/// Sets up the controller and both devices.  Interrupts from the controller are enabled, but routing them is up to the
+/// caller (IRQ 1 for the keyboard, IRQ 12 for the mouse).
+pub fn init() -> Result<Ps2, &'static str> {
+    command(CMD_DISABLE_PORT1)?;
+    command(CMD_DISABLE_PORT2)?;
+    flush();
+
+    command(CMD_READ_CONFIG)?;
+    let mut config = read()?;
+    config &= !(CONFIG_IRQ1 | CONFIG_IRQ12);
+    config |= CONFIG_TRANSLATE;
+    command(CMD_WRITE_CONFIG)?;
+    write(DATA, config)?;
+
+    command(CMD_SELF_TEST)?;
+    if read()? != 0x55 {
+        return Err("PS/2 controller failed its self test");
+    }
+    // The self test resets the configuration on some controllers.
+    command(CMD_WRITE_CONFIG)?;
+    write(DATA, config)?;
+
+    // Only a controller with a second port starts its clock when told to enable it.
+    command(CMD_ENABLE_PORT2)?;
+    command(CMD_READ_CONFIG)?;
+    let dual = read()? & CONFIG_PORT2_CLOCK_OFF == 0;
+    command(CMD_DISABLE_PORT2)?;
+
+    command(CMD_TEST_PORT1)?;
+    let port1 = read()? == 0;
+    let port2 = dual && {
+        command(CMD_TEST_PORT2)?;
+        read()? == 0
+    };
+    if !port1 && !port2 {
+        return Err("no working PS/2 port");
+    }
+
+    if port1 {
+        command(CMD_ENABLE_PORT1)?;
+        config |= CONFIG_IRQ1;
+    }
+    if port2 {
+        command(CMD_ENABLE_PORT2)?;
+        config |= CONFIG_IRQ12;
+    }
+
+    let keyboard = port1 && reset(1).is_ok() && send(1, DEV_ENABLE).is_ok();
+    let mouse = if port2 { init_mouse().ok() } else { None };
+    flush();
+
+    MOUSE.lock().kind = mouse;
+    command(CMD_WRITE_CONFIG)?;
+    write(DATA, config)?;
+    CONFIG.store(config, Ordering::Relaxed);
+    Ok(Ps2 { keyboard, mouse })
+}
+
+fn init_mouse() -> Result<MouseKind, &'static str> {
+    reset(2)?;
+    // After its self test, a mouse sends its ID.
+    if !matches!(read()?, 0 | 3 | 4) {
+        return Err("device on the second PS/2 port isn't a mouse");
+    }
+    let mut kind = MouseKind::Standard;
+    if knock([200, 100, 80])? == 3 {
+        kind = MouseKind::Wheel;
+        if knock([200, 200, 80])? == 4 {
+            kind = MouseKind::FiveButton;
+        }
+    }
+    send(2, DEV_SET_DEFAULTS)?;
+    send(2, DEV_ENABLE)?;
+    Ok(kind)
+}
+
+/// Reads the byte the keyboard sent, and queues it for `read_key`.  For the keyboard's interrupt handler.
+pub fn keyboard_byte() {
+    // If nobody reads the keyboard, the newest presses are the ones to lose.
+    SCANCODES.push(inb(DATA));
+}
+
+/// The oldest key event not yet taken.  The hotkeys are acted on instead.
+pub fn read_key() -> Option<KeyInput> {
+    while let Some(byte) = read_scancode() {
+        if let Some(input) = decode(byte) {
+            return Some(input);
+        }
+    }
+    None
+}
+
+/// The oldest scancode not yet taken, undecoded.  It comes out of the same queue as `read_key`'s, so only one of the
+/// two should be reading.
+pub fn read_scancode() -> Option<u8> {
+    let _keyboard = KEYBOARD.lock();
+    SCANCODES.pop()
+}
+
+/// Decodes the next byte `read_scancode` gave, with the selected layout and scancode set.  None until a byte finishes a
+/// key event, and for the hotkeys, which are acted on instead.
+pub fn decode(scancode: u8) -> Option<KeyInput> {
+    let input = KEYBOARD.lock().decoder.decode(scancode)?;
+    (!hotkey(&input)).then_some(input)
+}
+
+/// What Ctrl+Alt and each function key select.
+const LAYOUT_KEYS: [(KeyCode, Layout); 10] = [
+    (KeyCode::F1, Layout::Us104),
+    (KeyCode::F2, Layout::Uk105),
+    (KeyCode::F3, Layout::De105),
+    (KeyCode::F4, Layout::Azerty),
+    (KeyCode::F5, Layout::Colemak),
+    (KeyCode::F6, Layout::Dvorak104),
+    (KeyCode::F7, Layout::DvorakProgrammer104),
+    (KeyCode::F8, Layout::Jis109),
+    (KeyCode::F9, Layout::No105),
+    (KeyCode::F10, Layout::FiSe105),
+];
+
+/// Acts on `input` if it's one of the hotkeys.  Returns whether it was.
+fn hotkey(input: &KeyInput) -> bool {
+    if input.state != KeyState::Down || !input.modifiers.is_ctrl() || !input.modifiers.is_alt() {
+        return false;
+    }
+    if let Some(&(_, layout)) = LAYOUT_KEYS.iter().find(|(code, _)| *code == input.code) {
+        set_layout(layout);
+        return true;
+    }
+    let set = match input.code {
+        KeyCode::F11 => ScancodeSet::Set1,
+        KeyCode::F12 => ScancodeSet::Set2,
+        KeyCode::T => {
+            trace_heap();
+            return true;
+        }
+        KeyCode::Delete => crate::reboot(),
+        _ => return false,
+    };
+    // The keyboard goes on working with the set it had.
+    let _ = set_scancode_set(set);
+    true
+}
+
+/// Starts tracing the heap or, if it already is, stops and dumps what was recorded to the console.
+fn trace_heap() {
+    let heap = &crate::HTMAS;
+    if !heap.tracing() {
+        heap.set_tracing(true);
+        return;
+    }
+    heap.set_tracing(false);
+    let _ = heap.dump_trace(&mut crate::kiss::Console);
+    let _ = heap.dump_live(&mut crate::kiss::Console);
+}
+
+/// What a byte from the serial console is, typed.  Terminals send CR for Enter and DEL for Backspace, where the
+/// keyboard gives LF and BS.
+pub fn serial_char(byte: u8) -> Option<char> {
+    match byte {
+        b'\r' => Some('\n'),
+        0x7F => Some('\x08'),
+        // Only ASCII; the pieces of anything else would come out as the wrong characters.
+        0..0x80 => Some(byte as char),
+        _ => None,
+    }
+}
+
+/// The next character typed, on the keyboard or, standing in for it, on the serial console.
+pub fn read_char() -> Option<char> {
+    while let Some(input) = read_key() {
+        if let Some(DecodedKey::Unicode(c)) = input.key {
+            return Some(c);
+        }
+    }
+    while let Some(byte) = crate::serial::read_byte() {
+        if let Some(c) = serial_char(byte) {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// Switches the layout keys are decoded with.  Modifiers and toggles start over.
+pub fn set_layout(layout: Layout) {
+    let mut keyboard = KEYBOARD.lock();
+    keyboard.layout = layout;
+    keyboard.decoder = Decoder::new(keyboard.set, layout);
+}
+
+/// Switches the scancode set, by turning the controller's translation on or off.  Scancodes still queued are dropped,
+/// and modifiers and toggles start over.
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), &'static str> {
+    let mut keyboard = KEYBOARD.lock();
+    let config = match set {
+        ScancodeSet::Set1 => CONFIG.load(Ordering::Relaxed) | CONFIG_TRANSLATE,
+        ScancodeSet::Set2 => CONFIG.load(Ordering::Relaxed) & !CONFIG_TRANSLATE,
+    };
+    command(CMD_WRITE_CONFIG)?;
+    write(DATA, config)?;
+    CONFIG.store(config, Ordering::Relaxed);
+
+    while SCANCODES.pop().is_some() {}
+    keyboard.set = set;
+    keyboard.decoder = Decoder::new(set, keyboard.layout);
+    Ok(())
+}
+
+/// Reads the byte the mouse sent, and queues an event once a packet is complete.  For the mouse's interrupt handler.
+pub fn mouse_byte() {
+    let byte = inb(DATA);
+    let mut mouse = MOUSE.lock();
+    let Some(kind) = mouse.kind else {
+        return;
+    };
+
+    // Bit 3 of the first byte is always set; without it, we're out of step with the mouse.
+    if mouse.len == 0 && byte & (1 << 3) == 0 {
+        return;
+    }
+    let len = mouse.len;
+    mouse.packet[len] = byte;
+    mouse.len += 1;
+    if mouse.len < kind.packet_len() {
+        return;
+    }
+    mouse.len = 0;
+
+    let [flags, x, y, extra] = mouse.packet;
+    // Overflowed movement is garbage.
+    if flags & 0xC0 != 0 {
+        return;
+    }
+    let mut event = MouseEvent {
+        dx: x as i16 - (((flags as i16) << 4) & 0x100),
+        dy: y as i16 - (((flags as i16) << 3) & 0x100),
+        wheel: 0,
+        buttons: flags & 0b111,
+    };
+    match kind {
+        MouseKind::Standard => {}
+        MouseKind::Wheel => event.wheel = extra as i8,
+        MouseKind::FiveButton => {
+            // A 4-bit signed wheel count, then buttons 4 and 5.
+            event.wheel = ((extra << 4) as i8) >> 4;
+            if extra & (1 << 4) != 0 {
+                event.buttons |= buttons::BUTTON4;
+            }
+            if extra & (1 << 5) != 0 {
+                event.buttons |= buttons::BUTTON5;
+            }
+        }
+    }
+    mouse.events.push(event);
+}
+
+/// The oldest mouse event not yet taken.
+pub fn poll_mouse() -> Option<MouseEvent> {
+    MOUSE.lock().events.pop()
+}
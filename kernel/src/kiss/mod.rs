@@ -1,14 +1,24 @@
 pub mod draw;
 
-use crate::boot_info::boot_info;
+use crate::{boot_info::boot_info, sync::SpinLock};
 use core::{
-    cell::UnsafeCell,
     fmt::{Arguments, Write},
     panic::PanicInfo,
+    sync::atomic::{AtomicU8, Ordering},
 };
 
+/// Never waits on a lock and never allocates: whatever held one (or the heap) when the panic hit isn't going to let
+/// go.  The console is the only lock taken, and it's made free first; the sinks take none (see `add_sink`).
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+    crate::sync::irq_save();
+    #[cfg(target_arch = "x86_64")]
+    crate::smp::halt_others();
+    // Nothing else runs from here on, so once it's free, taking the console never waits.
+    if GBL_CONSOLE.try_lock().is_none() {
+        // SAFETY: whoever held the console isn't coming back.
+        unsafe { GBL_CONSOLE.force_unlock() };
+    }
     clear_screen();
     fill_screen(0xFF, 0, 0);
     set_console_background_color(RGB::red());
@@ -21,9 +31,6 @@ fn panic_handler(info: &PanicInfo) -> ! {
     );
     crate::println!("KERNEL ERROR CODE: 0x{:02X}", get_krnl_err());
     crate::println!();
-    //oop {}
-    //let msg_fmt = alloc::format!("{info}").replace("\n", "\r\n");
-    //crate::println!("[PANIC]: {msg_fmt}");
     crate::println!("[PANIC]: {}", info);
 
     loop {
@@ -89,11 +96,11 @@ impl RGB {
 macro_rules! pixel {
     ($format:expr, $fb:expr, $pitch:expr, $x:expr, $y:expr, $color:expr) => {{
         unsafe {
-            use core::ops::Add;
+            let fb = $crate::vmm::phys_to_virt($fb as usize);
             match $format {
                 0 => {
                     // ARGB - UEFI Format
-                    ($fb as *mut u32)
+                    (fb as *mut u32)
                         .add(($y * $pitch + $x) as usize)
                         .write_volatile(
                             (($color.b as u32) << 16)
@@ -103,7 +110,7 @@ macro_rules! pixel {
                 }
                 1 => {
                     // ABGR - UEFI Format
-                    ($fb as *mut u32)
+                    (fb as *mut u32)
                         .add(($y * $pitch + $x) as usize)
                         .write_volatile(
                             (($color.r as u32) << 16)
@@ -112,11 +119,7 @@ macro_rules! pixel {
                         );
                 }
                 32 => {
-                    #[cfg(target_arch = "x86_64")]
-                    type S = u64;
-                    #[cfg(target_arch = "x86")]
-                    type S = u32;
-                    ($fb.add(($y * $pitch + $x * $format / 8) as S) as *mut u32).write_volatile(
+                    ((fb + ($y * $pitch + $x * $format / 8) as usize) as *mut u32).write_volatile(
                         (($color.r as u32) << 16) | (($color.g as u32) << 8) | ($color.b as u32),
                     );
                 }
@@ -179,10 +182,9 @@ pub fn clear_screen() {
         }
     }
 
-    unsafe {
-        (&mut *GBL_CONSOLE.inner.get()).px = 0;
-        (&mut *GBL_CONSOLE.inner.get()).py = 0;
-    }
+    let mut console = GBL_CONSOLE.lock();
+    console.px = 0;
+    console.py = 0;
 }
 
 pub fn _clear_line(line: u8) {
@@ -2364,6 +2366,8 @@ pub struct KissConsole {
     py: u32,
     fc: RGB,
     bc: RGB,
+    /// Where else output goes (see `add_sink`).
+    sinks: [Option<fn(&str)>; 4],
 }
 impl KissConsole {
     pub const fn new() -> Self {
@@ -2372,6 +2376,7 @@ impl KissConsole {
             py: 0,
             fc: RGB::white(),
             bc: RGB::black(),
+            sinks: [None; 4],
         }
     }
 
@@ -2406,6 +2411,10 @@ impl KissConsole {
 }
 impl Write for KissConsole {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Sinks first, so they still get it if the framebuffer doesn't work out.
+        for sink in self.sinks.iter().flatten() {
+            sink(s);
+        }
         if s.is_ascii() {
             self.print_ascii_str(s);
         } else {
@@ -2417,19 +2426,27 @@ impl Write for KissConsole {
 }
 
 pub fn set_console_foreground_color(color: RGB) {
-    unsafe { &mut *GBL_CONSOLE.inner.get() }.fc = color;
+    GBL_CONSOLE.lock().fc = color;
 }
 pub fn set_console_background_color(color: RGB) {
-    unsafe { &mut *GBL_CONSOLE.inner.get() }.bc = color;
+    GBL_CONSOLE.lock().bc = color;
 }
 
-struct StaticCell<T> {
-    inner: UnsafeCell<T>,
+/// Copies everything printed from now on (the panic screen included) to `sink` as well, e.g. a serial port.  Sinks are
+/// called with the console locked, so output from different CPUs doesn't get mixed up.  Since the panic screen goes
+/// to them too, they mustn't take locks or allocate.
+pub fn add_sink(sink: fn(&str)) -> Result<(), &'static str> {
+    let mut console = GBL_CONSOLE.lock();
+    let slot = console
+        .sinks
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or("too many console sinks")?;
+    *slot = Some(sink);
+    Ok(())
 }
-unsafe impl<T> Sync for StaticCell<T> {}
-static GBL_CONSOLE: StaticCell<KissConsole> = StaticCell {
-    inner: UnsafeCell::new(KissConsole::new()),
-};
+
+static GBL_CONSOLE: SpinLock<KissConsole> = SpinLock::new(KissConsole::new());
 /**
  * 0x00 - Success
  * 0x01 - Boot Error (unless fault on BIOS/UEFI code, most likely no boot info given)
@@ -2437,38 +2454,47 @@ static GBL_CONSOLE: StaticCell<KissConsole> = StaticCell {
  * ...
  * 0x10 - ACPI Parsing Error (General/Unknown)
  * 0x11 - ACPI Parsing Error (Invalid Signature)
+ * 0x12 - Interrupt Controller Error (MADT)
+ * 0x13 - Timer Error (clock calibration)
+ * 0x14 - Scheduler Error
+ * ...
+ * 0x20-0x3F - CPU Exception (0x20 + vector)
  * ...
  * 0x70 - AML Error (General/Unknown)
  * 0x71 - AML Error (DSDT parsing error)
  * 0x72 - AML Error (SSDT/PSDT parsing error)
  */
-static KRNL_ERR: StaticCell<u8> = StaticCell {
-    inner: UnsafeCell::new(0x01),
-};
+static KRNL_ERR: AtomicU8 = AtomicU8::new(0x01);
 pub fn get_krnl_err() -> u8 {
-    unsafe { *KRNL_ERR.inner.get() }
+    KRNL_ERR.load(Ordering::Relaxed)
 }
 pub fn set_krnl_err(v: u8) {
-    *unsafe { &mut *KRNL_ERR.inner.get() } = v;
+    KRNL_ERR.store(v, Ordering::Relaxed);
+}
+
+/// A `Write` handle to the global console, for things that write to any output.
+pub struct Console;
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        GBL_CONSOLE.lock().write_str(s)
+    }
 }
 
 pub fn print_helper(args: Arguments) {
-    unsafe { &mut *GBL_CONSOLE.inner.get() }
-        .write_fmt(args)
-        .unwrap();
+    GBL_CONSOLE.lock().write_fmt(args).unwrap();
 }
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        crate::kiss::print_helper(format_args!($($arg)*))
+        $crate::kiss::print_helper(format_args!($($arg)*))
     };
 }
 #[macro_export]
 macro_rules! println {
     () => {
-        crate::print!("\r\n");
+        $crate::print!("\r\n");
     };
     ($($arg:tt)*) => {
-        crate::kiss::print_helper(format_args!("{}{}", format_args!($($arg)*), "\r\n"))
+        $crate::kiss::print_helper(format_args!("{}{}", format_args!($($arg)*), "\r\n"))
     };
 }
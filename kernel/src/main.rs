@@ -1,6 +1,6 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
+#![cfg_attr(target_arch = "x86_64", feature(abi_x86_interrupt))]
 
 // SAFETY: given from linker.
 unsafe extern "C" {
@@ -23,84 +23,62 @@ global_asm!(include_str!("./asm_entry_stub/riscv64.s")); // UNTESTED
 #[cfg(target_arch = "riscv32")]
 global_asm!(include_str!("./asm_entry_stub/riscv32.s")); // UNTESTED
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum MemoryPattern {
-    /// No overlapping
-    Separate,
-    /// Size changes, start point remains to original section
-    StartBranch,
-    /// Size changes, start point changes to given section
-    EndBranch,
-    /// Original section overlaps entire given section
-    NoChange,
-    /// Given section overlaps entire original section
-    Overwrite,
-}
-
-pub const fn end(start: usize, size: usize) -> usize {
-    start + size
-}
-pub const fn endt(tuple: (usize, usize)) -> usize {
-    tuple.0 + tuple.1
-}
-
-/// Start branch means first (original) set starts first.
-pub const fn cmp_mem_sct(
-    original_start: usize,
-    original_size: usize,
-    given_start: usize,
-    given_size: usize,
-) -> MemoryPattern {
-    if original_start == given_start && original_size == given_size {
-        return MemoryPattern::NoChange;
-    }
-
-    let original_end = end(original_start, original_size);
-    let given_end = end(given_start, given_size);
-
-    if original_end < given_start || given_end < original_start {
-        MemoryPattern::Separate
-    } else if given_start == original_end {
-        MemoryPattern::StartBranch
-    } else if original_start == given_end {
-        MemoryPattern::EndBranch
-    } else if given_start >= original_start {
-        if given_end > original_end {
-            if original_start == given_start {
-                MemoryPattern::Overwrite
-            } else {
-                MemoryPattern::StartBranch
-            }
-        } else {
-            MemoryPattern::NoChange
-        }
-    } else if given_end <= original_end {
-        if original_end == given_end {
-            MemoryPattern::Overwrite
-        } else {
-            MemoryPattern::EndBranch
-        }
-    } else {
-        MemoryPattern::Overwrite
-    }
-}
-
 extern crate alloc;
 
 mod api;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod apic;
 mod boot_info;
 mod cfg_tbl;
+mod efi_rt;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod executor;
+mod frame_alloc;
 mod htmalloc;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod irq;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod kb_mouse;
 mod kiss;
+mod memmap;
+#[cfg(target_arch = "x86_64")]
+mod pci;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pic;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod port_io;
+mod reclaim;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod sched;
+#[cfg(all(feature = "selftest", any(target_arch = "x86", target_arch = "x86_64")))]
+mod selftest;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod serial;
+#[cfg(target_arch = "x86_64")]
+mod smp;
+mod sync;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod time;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod trap;
+mod vmm;
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64_stuff;
+#[cfg(target_arch = "x86")]
+mod x86_stuff;
 
-use crate::{boot_info::boot_info, htmalloc::HTMAlloc};
+// The GDT, IDT and interrupt setup of whichever x86 this is.
+#[cfg(target_arch = "x86_64")]
+use x86_64_stuff as arch;
+#[cfg(target_arch = "x86")]
+use x86_stuff as arch;
+
+use crate::{boot_info::boot_info, htmalloc::HTMAlloc, memmap::RegionKind};
 use core::arch::global_asm;
 use htmos_boot_info::HTMOSBootInformation;
-use r_efi::efi::{self, ConfigurationTable, MemoryDescriptor, RuntimeServices, SystemTable};
+use htmos_mem_region::MemoryGlue;
+use r_efi::efi::{self, ConfigurationTable, RuntimeServices, SystemTable};
 use raw_acpi::fadt::FixedACPIDescriptionTable;
 
 #[inline]
@@ -120,286 +98,9 @@ pub fn halt() {
 #[global_allocator]
 static HTMAS: HTMAlloc = HTMAlloc::ginit();
 
-trait MemoryGlue {
-    fn glue_section(&mut self, start: usize, size: usize);
-    fn rip_section(&mut self, start: usize, size: usize);
-    fn organize(&mut self) -> bool;
-}
-impl<const T: usize> MemoryGlue for ([(usize, usize); T], usize) {
-    fn glue_section(&mut self, start: usize, size: usize) {
-        if size == 0 {
-            return;
-        }
-
-        let &mut (mut arr, mut sz) = self;
-        if arr[..sz].is_sorted_by(|(v1, _), (v2, _)| v1 <= v2) {
-            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-        }
-
-        if sz == 0 {
-            arr[0] = (start, size);
-            sz = 1;
-        } else if sz == 1 {
-            let astart = arr[0].0;
-            let asize = arr[0].1;
-            match cmp_mem_sct(astart, asize, start, size) {
-                MemoryPattern::NoChange => {}
-                MemoryPattern::Overwrite => arr[0] = (start, size),
-                MemoryPattern::Separate => {
-                    arr[1] = (start, size);
-                    arr[..2].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                    sz = 2;
-                }
-                MemoryPattern::StartBranch => arr[0] = (astart, start - astart + size),
-                MemoryPattern::EndBranch => arr[0] = (start, astart - start + asize),
-            }
-        } else {
-            if !arr[..sz].contains(&(start, size)) {
-                arr[sz] = (start, size);
-                sz += 1;
-                arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-            }
-
-            loop {
-                let mut b = true;
-
-                let mut i = 0;
-                for window in arr[..sz].windows(2) {
-                    match cmp_mem_sct(window[0].0, window[0].1, window[1].0, window[1].1) {
-                        MemoryPattern::NoChange => {
-                            arr[i + 1] = (0, 0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                            sz -= 1;
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                            b = false;
-                            break;
-                        }
-                        MemoryPattern::Overwrite => {
-                            arr[i] = (0, 0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                            sz -= 1;
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                            b = false;
-                            break;
-                        }
-                        MemoryPattern::Separate => {}
-                        MemoryPattern::StartBranch => {
-                            arr[i] = (
-                                window[0].0,
-                                (window[1].0 - window[0].0) / 4096 + window[1].1,
-                            );
-                            arr[i + 1] = (0, 0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                            sz -= 1;
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                            b = false;
-                            break;
-                        }
-                        MemoryPattern::EndBranch => {
-                            arr[i] = (
-                                window[1].0,
-                                (window[0].0 - window[1].0) / 4096 + window[0].1,
-                            );
-                            arr[i + 1] = (0, 0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                            sz -= 1;
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                            b = false;
-                            break;
-                        }
-                    }
-
-                    i += 1;
-                }
-
-                if b {
-                    break;
-                }
-            }
-        }
-
-        *self = (arr, sz);
-    }
-    fn rip_section(&mut self, start: usize, size: usize) {
-        let &mut (mut arr, mut sz) = self;
-
-        // Check if it's even marked available.
-        if arr
-            .iter()
-            .any(|(v1, v2)| cmp_mem_sct(start, size, *v1, *v2) != MemoryPattern::Separate)
-        {
-            let mut edit = false;
-            let mut i = 0;
-            while i < sz {
-                match cmp_mem_sct(arr[i].0, arr[i].1, start, size) {
-                    MemoryPattern::StartBranch => {
-                        edit = true;
-                        arr[i].1 = start - arr[i].0;
-                    }
-                    MemoryPattern::EndBranch => {
-                        arr[i] = (end(start, size), endt(arr[i]) - end(start, size));
-                        // Original section goes past the given: no need to move on.
-                        break;
-                    }
-                    MemoryPattern::NoChange => {
-                        if edit == true {
-                            // Memory map is janked up?
-                            if self.organize() {
-                                // Yup.  Restart.
-                                i = 0;
-                                continue;
-                            } else {
-                                unreachable!(
-                                    "NoChange memory comparison when an edit in remove comparisons happened previously."
-                                );
-                            }
-                        }
-
-                        // Four scenarios:
-                        if arr[i].1 == size {
-                            // Remove complete section
-                            arr[i] = (0, 0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                            sz -= 1;
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                        } else if arr[i].0 == start {
-                            arr[i] = (end(start, size), arr[i].1 - size);
-                        } else if endt(arr[i]) == end(start, size) {
-                            arr[i] = (arr[i].0, arr[i].1 - size);
-                        } else {
-                            // Josh is the best (Elaina said so).
-
-                            // This gets complicated: we have to split the section into two sections.
-                            // Since this involves reoganizing the map, we'll call that and directly return the function.
-                            arr[sz] = (end(start, size), endt(arr[i]) - end(start, size));
-                            sz += 1;
-                            arr[i] = (arr[i].0, start - arr[i].0);
-                            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                            return;
-                        }
-
-                        // This is a special "one and done" case.  Exit the loop.
-                        break;
-                    }
-                    MemoryPattern::Overwrite => {
-                        // Explanation at end of this branch
-                        edit = true;
-                        let t = arr[i];
-
-                        // Completely overwrites the section: remove complete section
-                        arr[i] = (0, 0);
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                        sz -= 1;
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-
-                        // If the calculated end for both sections are equal, no need to move on: end of given section.
-                        // Otherwise, given section continues pass current section: move on.
-                        if start + size == t.0 + t.1 {
-                            break;
-                        }
-                    }
-                    MemoryPattern::Separate => {
-                        // If an edit occured, there is no reason to move on: we passed the given section completely.
-                        if edit {
-                            break;
-                        }
-                    }
-                }
-
-                i += 1;
-            }
-        }
-
-        *self = (arr, sz);
-    }
-    fn organize(&mut self) -> bool {
-        let &mut (mut arr, mut sz) = self;
-        if arr[..sz].is_sorted_by(|(v1, _), (v2, _)| v1 <= v2) {
-            arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-        }
-
-        let mut ret = false;
-        loop {
-            let mut b = true;
-
-            let mut i = 0;
-            for window in arr[..sz].windows(2) {
-                match cmp_mem_sct(window[0].0, window[0].1, window[1].0, window[1].1) {
-                    MemoryPattern::NoChange => {
-                        arr[i + 1] = (0, 0);
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                        sz -= 1;
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                        b = false;
-                        ret = true;
-                        break;
-                    }
-                    MemoryPattern::Overwrite => {
-                        arr[i] = (0, 0);
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                        sz -= 1;
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                        b = false;
-                        ret = true;
-                        break;
-                    }
-                    MemoryPattern::Separate => {}
-                    MemoryPattern::StartBranch => {
-                        arr[i] = (window[0].0, window[1].0 - window[0].0 + window[1].1);
-                        arr[i + 1] = (0, 0);
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                        sz -= 1;
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                        b = false;
-                        ret = true;
-                        break;
-                    }
-                    MemoryPattern::EndBranch => {
-                        arr[i] = (window[1].0, window[0].0 - window[1].0 + window[0].1);
-                        arr[i + 1] = (0, 0);
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v2.cmp(v1));
-                        sz -= 1;
-                        arr[..sz].sort_unstable_by(|(v1, _), (v2, _)| v1.cmp(v2));
-                        b = false;
-                        ret = true;
-                        break;
-                    }
-                }
-
-                i += 1;
-            }
-
-            if b {
-                break;
-            }
-        }
-
-        *self = (arr, sz);
-        ret
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(u32)]
-pub enum E820EntryType {
-    Free = 1,
-    Reserved,
-    ACPIReclaim,
-    ACPINVS,
-    Unusable,
-    Disabled,
-}
-
-#[derive(Debug, Clone, Copy)]
-#[repr(C)]
-pub struct E820Entry {
-    pub base: u64,
-    pub length: u64,
-    pub entry_type: E820EntryType,
-    pub attrs: u32,
-}
-
-/// Gives a table of available memory, only scanning Loader, Boot Service, and Conventional sections.
+/// Gives a table of available memory: the usable, boot services and ACPI reclaim regions of `memmap`.
+///
+/// Some of it isn't free yet; `reclaim::init` takes those parts out.
 pub(crate) fn get_mmap() -> ([(usize, usize); 256], usize) {
     let bi = boot_info();
 
@@ -409,54 +110,35 @@ pub(crate) fn get_mmap() -> ([(usize, usize); 256], usize) {
     // At this point, the following are valid free memory (except for the kernel itself):
     // Loader Code
     // Loader Data
+    // Conventional
+
+    // Free once the kernel is done with them (see `reclaim`):
     // Boot Services Code
     // Boot Services Data
-    // Conventional
+    // ACPI Reclaim
 
     // Need to look into:
     // Reserved
-    // ACPI Reclaim
     // Persistent
     // Unaccepted
 
-    // I'll think about the Runtime Services Code and Data, but the rest will never be touched.
+    // Runtime Services Code and Data stay with the firmware (also tracked by `reclaim`); the rest will never be
+    // touched.
 
     let mut mmap = ([(0, 0); 256], 0);
 
-    // SAFETY: given the bootloader does its job; otherwise, not safe.
-    if bi.boot_mode == 0 {
-        let mut ptr = bi.memory_map_addr as *const E820Entry;
-        let count = (bi.memory_map_size / bi.memory_desc_size) as usize;
-        for _ in 0..count {
-            let entry = unsafe { &*ptr };
-
-            if entry.entry_type == E820EntryType::Free {
-                mmap.glue_section(entry.base as usize, entry.length as usize);
-            }
-
-            ptr =
-                unsafe { (ptr as *const u8).add(bi.memory_desc_size as usize) } as *const E820Entry;
-        }
-    } else {
-        let mut ptr = bi.memory_map_addr as *const MemoryDescriptor;
-        let count = (bi.memory_map_size / bi.memory_desc_size) as usize;
-        for _ in 0..count {
-            let desc = unsafe { &*ptr };
-
-            if desc.r#type == efi::LOADER_CODE
-                || desc.r#type == efi::LOADER_DATA
-                || desc.r#type == efi::BOOT_SERVICES_CODE
-                || desc.r#type == efi::BOOT_SERVICES_DATA
-                || desc.r#type == efi::CONVENTIONAL_MEMORY
-            {
-                mmap.glue_section(
-                    desc.physical_start as usize,
-                    desc.number_of_pages as usize * 4096,
-                );
-            }
-
-            ptr = unsafe { (ptr as *const u8).add(bi.memory_desc_size as usize) }
-                as *const MemoryDescriptor;
+    // The kernel, the boot info, the framebuffer and the memory map are already set apart by `memmap`.
+    for r in memmap::regions().iter().filter(|r| {
+        matches!(
+            r.kind,
+            RegionKind::Usable | RegionKind::BootServices | RegionKind::AcpiReclaim
+        )
+    }) {
+        if !mmap.glue_section(r.start, r.size) {
+            println!(
+                "MEM MAP FULL: dropped 0x{:016X} (0x{:X} bytes)",
+                r.start, r.size
+            );
         }
     }
 
@@ -490,48 +172,23 @@ pub(crate) fn get_mmap() -> ([(usize, usize); 256], usize) {
 
     /*
      * Ranges to remove for good:
-     * - The Kernel itself
-     * - Outstanding pointers (og memory map, boot info)
      * - reserved section given by the pointer value in boot info (raw config from BIOS, SystemTable from UEFI)
      */
 
-    // Kernel
-    {
-        let kernel_start = unsafe { &crate::__kernel_start as *const u8 as usize };
-        let kernel_size = unsafe { &crate::__kernel_end as *const u8 as usize } - kernel_start;
-        mmap.rip_section(kernel_start, kernel_size);
-    }
-    // Linker-defined Stack
-    {
-        let stack_start = unsafe { &crate::__stack_start as *const u8 as usize };
-        let stack_size = unsafe { &crate::__stack_end as *const u8 as usize } - stack_start;
-        mmap.rip_section(stack_start, stack_size);
-    }
-    // Boot Info
-    {
-        let bi_start = bi as *const _ as usize;
-        let bi_size = size_of::<HTMOSBootInformation>();
-        mmap.rip_section(bi_start, bi_size);
-    }
-    // Framebuffer
-    if bi.framebuffer_addr > 0 {
-        mmap.rip_section(bi.framebuffer_addr as usize, bi.framebuffer_size as usize);
-    }
-    // Memory Map
-    mmap.rip_section(bi.memory_map_addr as usize, bi.memory_map_size as usize);
     // More Info pointer
     if bi.boot_mode == 1 {
         // SAFETY: UEFI turns in boot_mode as 1: more_info is the pointer to the SystemTable struct.
-        let st = bi.more_info as *mut SystemTable;
+        let st = vmm::phys_to_virt(bi.more_info as usize) as *mut SystemTable;
 
         // System Table itself
-        mmap.rip_section(st as usize, size_of::<SystemTable>());
+        mmap.rip_section(bi.more_info as usize, size_of::<SystemTable>());
 
         let (firmware_vendor, firmware_vendor_len) = {
             let st = unsafe { &mut *st };
             let mut l = 0;
             // SAFETY: literall given.
-            while unsafe { st.firmware_vendor.add(l).read() } != 0 {
+            let fv = vmm::phys_to_virt(st.firmware_vendor as usize) as *const u16;
+            while unsafe { fv.add(l).read() } != 0 {
                 l += 1;
             }
             (st.firmware_vendor as usize, l + 1) // The +1 is for the null terminated C string
@@ -547,8 +204,10 @@ pub(crate) fn get_mmap() -> ([(usize, usize); 256], usize) {
         println!(
             "FIRMWARE VENDER: {}",
             // SAFETY: UEFI firmware_vender is 16-bit-wide string.
-            unsafe { widestring::U16CStr::from_ptr_str(firmware_vendor as *mut u16 as *const u16) }
-                .display()
+            unsafe {
+                widestring::U16CStr::from_ptr_str(vmm::phys_to_virt(firmware_vendor) as *const u16)
+            }
+            .display()
         );
 
         // Config Table
@@ -559,8 +218,9 @@ pub(crate) fn get_mmap() -> ([(usize, usize); 256], usize) {
 
         // Runtime Services
         // SAFETY: same with SystemTable.
-        let rs = unsafe { &mut *st }.runtime_services;
-        mmap.rip_section(rs as usize, size_of::<RuntimeServices>());
+        let rs_phys = unsafe { &mut *st }.runtime_services as usize;
+        mmap.rip_section(rs_phys, size_of::<RuntimeServices>());
+        let rs = vmm::phys_to_virt(rs_phys) as *const RuntimeServices;
 
         // Here, we go through each pointer of any kind,
         // get the size of the type of pointer,
@@ -637,25 +297,28 @@ fn shutdown() {
     if bi.boot_mode == 0 {
         todo!("Shutdown function not implemented for BIOS yet.");
     } else {
-        unsafe {
-            ((&mut *(&mut *(bi.more_info as *mut SystemTable)).runtime_services).reset_system)(
-                efi::RESET_SHUTDOWN,
-                efi::Status::SUCCESS,
-                0,
-                core::ptr::null_mut(),
-            );
+        efi_rt::reset_system(efi::RESET_SHUTDOWN);
+        loop {
+            halt();
         }
     }
 }
 
+/// Restarts the machine, through the firmware if it will, or else with a triple fault.
+pub fn reboot() -> ! {
+    efi_rt::reset_system(efi::RESET_COLD);
+    triple_fault()
+}
+
 // SAFETY: actual items from UEFI firmware, assuming it doesn't give wrong information.
 /// # ONLY USE IN UEFI MODE!
-const fn sliced_uefi_cfg_table() -> &'static [ConfigurationTable] {
+fn sliced_uefi_cfg_table() -> &'static [ConfigurationTable] {
     let bi = boot_info();
     unsafe {
+        let st = &*(vmm::phys_to_virt(bi.more_info as usize) as *const SystemTable);
         core::slice::from_raw_parts(
-            (&mut *(bi.more_info as *mut SystemTable)).configuration_table,
-            (&mut *(bi.more_info as *mut SystemTable)).number_of_table_entries,
+            efi_rt::fw_ptr(st.configuration_table as usize) as *const ConfigurationTable,
+            st.number_of_table_entries,
         )
     }
 }
@@ -694,42 +357,36 @@ pub fn triple_fault() -> ! {
     }
 }
 
-fn alloc_test() {
-    let mut v = alloc::vec::Vec::<u32>::new();
-    assert!(v.len() == 0 && v.capacity() == 0);
-
-    v.push(0xAA55AA55);
-    assert!(v[0] == 0xAA55AA55);
-
-    v.push(0x12345678);
-    assert!(v[0] == 0xAA55AA55);
-    assert!(v[1] == 0x12345678);
-
-    v.push(0x2468ABCD);
-    assert!(v[0] == 0xAA55AA55);
-    assert!(v[1] == 0x12345678);
-    assert!(v[2] == 0x2468ABCD);
-
-    v.remove(0);
-    assert!(v[0] == 0x12345678);
-    assert!(v[1] == 0x2468ABCD);
-
-    v.reverse();
-    assert!(v[0] == 0x2468ABCD);
-    assert!(v[1] == 0x12345678);
-
-    for _ in 0..100 {
-        v.push(1);
-    }
-    assert!(v.len() == 102);
-
-    v.remove(0);
-    v.remove(0);
-    for i in 0..100 {
-        assert!(v[i] == 1);
-    }
-
-    println!("vec test passed");
+/// Finds the ACPI table with the given signature through the RSDT (ACPI 1.0) or XSDT, and returns where it's mapped.
+///
+/// Only until the ACPI tables are reclaimed (see `entry`); they could be anything after that.
+fn find_acpi_table(
+    rsdp: &raw_acpi::rsdp::RootSystemDescriptionPointer,
+    sign: &[u8; 4],
+) -> Option<usize> {
+    assert!(
+        !reclaim::reclaimed(reclaim::Stage::Acpi),
+        "ACPI tables looked up after they were reclaimed"
+    );
+    let (sdt, entry_size) = if rsdp.revision == 0 {
+        (rsdp.rsdt_address as usize, 4)
+    } else {
+        (rsdp.xsdt_address as usize, 8)
+    };
+    let sdt = vmm::phys_to_virt(sdt);
+    // SAFETY: the RSDP was validated, so this is an RSDT or XSDT; the entries aren't necessarily aligned.
+    let length = unsafe { ((sdt + 4) as *const u32).read_unaligned() } as usize;
+    (raw_acpi::SDT_HEADER_SIZE..length)
+        .step_by(entry_size)
+        .map(|off| unsafe {
+            if entry_size == 4 {
+                ((sdt + off) as *const u32).read_unaligned() as usize
+            } else {
+                ((sdt + off) as *const u64).read_unaligned() as usize
+            }
+        })
+        .map(vmm::phys_to_virt)
+        .find(|&table| unsafe { core::slice::from_raw_parts(table as *const u8, 4) } == sign)
 }
 
 // SAFETY: assembly stub calls this by name directly; don't change the name.
@@ -755,6 +412,13 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
 
     kiss::set_krnl_err(0x00);
 
+    // Before anything else prints, so a serial log has everything.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let serial = serial::init(serial::ComPort::Com1, 115_200)
+        // Some machines only have the second port.
+        .or_else(|_| serial::init(serial::ComPort::Com2, 115_200))
+        .and_then(|uart| kiss::add_sink(serial::write_str).map(|()| uart));
+
     if unsafe { &*info }.more_info == 0 {
         //unsafe { &*info }.more_info != 0 {
         unsafe { &mut *(info as *mut HTMOSBootInformation) }.more_info = unsafe {
@@ -797,18 +461,56 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
     kiss::fill_screen(0, 0, 0);
 
     kiss::set_krnl_err(0x02);
-    HTMAS.update(get_mmap());
+    let mut mmap = get_mmap();
+    reclaim::init(&mut mmap);
+    frame_alloc::init(&mmap, reclaim::top());
+    #[cfg(target_arch = "x86_64")]
+    vmm::init();
+    // The loader's page tables live in boot-services memory; they're unused now.
+    let reclaimed = reclaim::reclaim(reclaim::Stage::BootServices);
     kiss::set_krnl_err(0x00);
 
     kiss::clear_screen();
 
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    match serial {
+        Ok(uart) => println!("SERIAL: console on {uart}"),
+        Err(e) => println!("SERIAL: {e}"),
+    }
+    memmap::report();
     {
-        x86_64_stuff::init();
-        println!("INTERRUPTS INITIALIZED");
+        let frames = frame_alloc::FRAMES.lock();
+        println!(
+            "FRAMES: {} free of {} ({} MiB free)",
+            frames.free_frames(),
+            frames.total_frames(),
+            frames.free_frames() * frame_alloc::FRAME_SIZE / (1024 * 1024)
+        );
+        println!(
+            "RECLAIMED: {} KiB of boot services memory",
+            reclaimed / 1024
+        );
+    }
+    println!("{}", HTMAS.stats());
+
+    if bi.boot_mode == 1 {
+        match efi_rt::init() {
+            Ok(()) => match efi_rt::get_time() {
+                Ok(t) => println!(
+                    "RUNTIME SERVICES OK: {:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    t.year, t.month, t.day, t.hour, t.minute, t.second
+                ),
+                Err(e) => println!("RUNTIME SERVICES OK (no clock: {e:?})"),
+            },
+            Err(e) => println!("RUNTIME SERVICES UNAVAILABLE: {e}"),
+        }
     }
 
-    //alloc_test();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        arch::init();
+        println!("EXCEPTION HANDLERS INSTALLED");
+    }
 
     logo();
 
@@ -833,7 +535,10 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
     kiss::set_krnl_err(0x10);
     let rsdp = if bi.boot_mode == 0 {
         println!("BIOS MODE");
-        unsafe { &*(bi.more_info as *const raw_acpi::rsdp::RootSystemDescriptionPointer) }
+        unsafe {
+            &*(vmm::phys_to_virt(bi.more_info as usize)
+                as *const raw_acpi::rsdp::RootSystemDescriptionPointer)
+        }
     } else {
         println!("UEFI MODE");
         let cfg_table = sliced_uefi_cfg_table();
@@ -899,7 +604,7 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
         if ret == 0 {
             panic!("ACPI not found");
         }
-        unsafe { &*(ret as *const raw_acpi::rsdp::RootSystemDescriptionPointer) }
+        unsafe { &*(vmm::phys_to_virt(ret) as *const raw_acpi::rsdp::RootSystemDescriptionPointer) }
     };
 
     kiss::set_krnl_err(0x11);
@@ -922,7 +627,8 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
             "RSDT/XSDT Signature Invalid; expected either \"RSDT\" or \"XSDT\", found \"{}\"",
             unsafe {
                 str::from_utf8_unchecked(
-                    &(&*(rsdp.xsdt_address as *const raw_acpi::rsdt::RootSystemDescriptionTable))
+                    &(&*(vmm::phys_to_virt(rsdp.xsdt_address as usize)
+                        as *const raw_acpi::rsdt::RootSystemDescriptionTable))
                         .header
                         .signature,
                 )
@@ -947,6 +653,111 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
         kiss::set_console_foreground_color(kiss::RGB::white());
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        kiss::set_krnl_err(0x12);
+        if let Err(e) = arch::init_interrupts(find_acpi_table(rsdp, b"APIC")) {
+            panic!("interrupt controller setup failed: {e}");
+        }
+        kiss::set_krnl_err(0x00);
+        println!("INTERRUPTS ENABLED");
+    }
+
+    #[cfg(target_arch = "x86")]
+    {
+        kiss::set_krnl_err(0x13);
+        if let Err(e) = x86_stuff::init_timer() {
+            panic!("clock setup failed: {e}");
+        }
+        kiss::set_krnl_err(0x00);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        kiss::set_krnl_err(0x13);
+        if let Err(e) = x86_64_stuff::init_timer(find_acpi_table(rsdp, b"HPET")) {
+            panic!("clock setup failed: {e}");
+        }
+        kiss::set_krnl_err(0x00);
+
+        if let Err(e) = x86_64_stuff::init_smp() {
+            kiss::set_console_foreground_color(kiss::RGB::rgb(0xC0, 0xC0, 0x00));
+            println!("SMP: only the BSP is running: {e}");
+            kiss::set_console_foreground_color(kiss::RGB::white());
+        }
+
+        match pci::init(find_acpi_table(rsdp, b"MCFG")) {
+            Ok(devices) => {
+                println!(
+                    "PCI: {} function(s), configuration through {}",
+                    devices.len(),
+                    if pci::uses_ecam() { "ECAM" } else { "ports" }
+                );
+                for dev in devices {
+                    println!("  {dev}");
+                }
+            }
+            Err(e) => println!("PCI: {e}"),
+        }
+    }
+
+    // Whatever read ACPI tables (the MADT, HPET and MCFG above) has copied what it needs out of them by now.  Nothing
+    // may look at them, or the RSDT/XSDT, from here on; `find_acpi_table` checks.
+    let reclaimed = reclaim::reclaim(reclaim::Stage::Acpi);
+    println!("RECLAIMED: {} KiB of ACPI memory", reclaimed / 1024);
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        kiss::set_krnl_err(0x14);
+        if let Err(e) = sched::init() {
+            panic!("scheduler setup failed: {e}");
+        }
+        kiss::set_krnl_err(0x00);
+        println!(
+            "SCHEDULER: round-robin, {} Hz tick, on {}",
+            time::TICK_HZ,
+            sched::current_name().unwrap_or("?")
+        );
+        if let Err(e) = executor::init() {
+            panic!("executor setup failed: {e}");
+        }
+        #[cfg(feature = "selftest")]
+        selftest::run();
+
+        // Until there's anything else to read the keyboard, typing goes to the screen.
+        executor::spawn(async {
+            use executor::Stream;
+            let mut chars = executor::chars();
+            while let Some(c) = chars.next().await {
+                print!("{c}");
+            }
+        });
+        // Nor the mouse; its clicks are reported.
+        executor::spawn(async {
+            use executor::Stream;
+            use kb_mouse::buttons;
+            const NAMES: [(u8, &str); 5] = [
+                (buttons::LEFT, "left"),
+                (buttons::RIGHT, "right"),
+                (buttons::MIDDLE, "middle"),
+                (buttons::BUTTON4, "fourth"),
+                (buttons::BUTTON5, "fifth"),
+            ];
+            let mut packets = executor::mouse_packets();
+            let mut held = 0;
+            while let Some(packet) = packets.next().await {
+                for (button, name) in NAMES {
+                    if packet.buttons & !held & button != 0 {
+                        println!("MOUSE: {name} button");
+                    }
+                }
+                held = packet.buttons;
+            }
+        });
+        // Nothing left for the boot thread; the others (or the idle thread) take over.
+        sched::exit();
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
     loop {
         halt();
     }
@@ -954,6 +765,7 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
     // The way I'm gonna do this kind of branch is have this only exist in the code if 32-bit.
     // And if it is 32-bit but revision is 0, the first branch will not run; the second will.
     // The reality of it is, the first branch should never run.  Ever.  That's why I mention extreme caution with the warning.
+    // NOTE: this reads the RSDT/XSDT and the tables it lists, so it has to move to before they're reclaimed.
     let mut aml_data = (0, 0, alloc::vec![]);
     kiss::set_krnl_err(0x70);
     unsafe {
@@ -974,12 +786,12 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
             __i = true;
 
             let sz = if rsdp.revision == 0 {
-                (&*(rsdp.rsdt_address as usize
+                (&*(vmm::phys_to_virt(rsdp.rsdt_address as usize)
                     as *const raw_acpi::rsdt::RootSystemDescriptionTable))
                     .entry()
                     .len()
             } else {
-                (&*(rsdp.xsdt_address as usize
+                (&*(vmm::phys_to_virt(rsdp.xsdt_address as usize)
                     as *const raw_acpi::xsdt::ExtendedSystemDescriptionTable))
                     .entry()
                     .len()
@@ -987,14 +799,15 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
 
             for i in 0..sz {
                 let ptr = if rsdp.revision == 0 {
-                    (&*(rsdp.rsdt_address as usize
+                    (&*(vmm::phys_to_virt(rsdp.rsdt_address as usize)
                         as *const raw_acpi::rsdt::RootSystemDescriptionTable))
                         .entry()[i] as usize
                 } else {
-                    (&*(rsdp.xsdt_address as usize
+                    (&*(vmm::phys_to_virt(rsdp.xsdt_address as usize)
                         as *const raw_acpi::xsdt::ExtendedSystemDescriptionTable))
                         .entry()[i] as usize
                 };
+                let ptr = vmm::phys_to_virt(ptr);
 
                 let sign =
                     str::from_utf8_unchecked(core::slice::from_raw_parts(ptr as *const u8, 4));
@@ -1040,40 +853,37 @@ fn entry(info: *const HTMOSBootInformation) -> ! {
                     "FACP" => {
                         if (&*(ptr as *const raw_acpi::fadt::FixedACPIDescriptionTable)).x_dsdt != 0
                         {
-                            aml_data.0 = (&*((&*(ptr
-                                as *const raw_acpi::fadt::FixedACPIDescriptionTable))
-                                .x_dsdt as usize
+                            aml_data.0 = (&*(vmm::phys_to_virt(
+                                (&*(ptr as *const raw_acpi::fadt::FixedACPIDescriptionTable)).x_dsdt
+                                    as usize,
+                            )
                                 as *const raw_acpi::dsdt::DifferentiatedSystemDescriptionTable))
                                 .def_block()
                                 .as_ptr() as usize;
-                            aml_data.1 = (&*((&*(ptr
-                                as *const raw_acpi::fadt::FixedACPIDescriptionTable))
-                                .x_dsdt as usize
+                            aml_data.1 = (&*(vmm::phys_to_virt(
+                                (&*(ptr as *const raw_acpi::fadt::FixedACPIDescriptionTable)).x_dsdt
+                                    as usize,
+                            )
                                 as *const raw_acpi::dsdt::DifferentiatedSystemDescriptionTable))
                                 .def_block()
                                 .len();
                         } else {
-                            aml_data.0 = (&*((&*(ptr
-                                as *const raw_acpi::fadt::FixedACPIDescriptionTable))
-                                .dsdt as usize
+                            aml_data.0 = (&*(vmm::phys_to_virt(
+                                (&*(ptr as *const raw_acpi::fadt::FixedACPIDescriptionTable)).dsdt
+                                    as usize,
+                            )
                                 as *const raw_acpi::dsdt::DifferentiatedSystemDescriptionTable))
                                 .def_block()
                                 .as_ptr() as usize;
-                            aml_data.1 = (&*((&*(ptr
-                                as *const raw_acpi::fadt::FixedACPIDescriptionTable))
-                                .dsdt as usize
+                            aml_data.1 = (&*(vmm::phys_to_virt(
+                                (&*(ptr as *const raw_acpi::fadt::FixedACPIDescriptionTable)).dsdt
+                                    as usize,
+                            )
                                 as *const raw_acpi::dsdt::DifferentiatedSystemDescriptionTable))
                                 .def_block()
                                 .len();
                         }
                     }
-                    "APIC" => {
-                        //#[cfg(target_arch = "x86_64")]
-                        //{
-                        //    x86_64_stuff::init_madt(ptr);
-                        //    println!("INTERRUPTS INITIALIZED");
-                        //}
-                    }
                     "FADT" => {
                         let info = *(ptr as *const FixedACPIDescriptionTable);
                     }
@@ -0,0 +1,458 @@
+//! **HyperText Markup Memory Map**
+//!
+//! One typed view of physical memory, whether the loader handed over an E820 map (BIOS) or UEFI memory descriptors.
+//! The kernel image, the framebuffer and what the loader left for the kernel are laid over the firmware's map, so
+//! every byte has exactly one kind.  Regions are sorted by address; neighbours of the same kind and attributes are
+//! merged.
+
+use crate::{boot_info::boot_info, println, vmm};
+use core::fmt::{self, Write};
+use htmos_boot_info::HTMOSBootInformation;
+use lazy_static::lazy_static;
+use r_efi::efi::{self, MemoryDescriptor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum E820EntryType {
+    Free = 1,
+    Reserved,
+    AcpiReclaim,
+    AcpiNvs,
+    Unusable,
+    Disabled,
+}
+impl E820EntryType {
+    pub const fn from_raw(v: u32) -> Option<Self> {
+        Some(match v {
+            1 => Self::Free,
+            2 => Self::Reserved,
+            3 => Self::AcpiReclaim,
+            4 => Self::AcpiNvs,
+            5 => Self::Unusable,
+            6 => Self::Disabled,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct E820Entry {
+    pub base: u64,
+    pub length: u64,
+    /// Raw, since firmware may use types `E820EntryType` doesn't know about.
+    pub entry_type: u32,
+    pub attrs: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    /// Free RAM.
+    Usable,
+    /// Firmware boot-services code and data; free once the kernel is done with the loader's page tables.
+    BootServices,
+    /// Holds ACPI tables; free once they've been parsed.
+    AcpiReclaim,
+    /// Firmware keeps using it, even across sleep states.
+    AcpiNvs,
+    /// Device memory.
+    Mmio,
+    RuntimeCode,
+    RuntimeData,
+    /// What the loader handed over and the kernel still reads (boot info, the memory map itself).
+    Bootloader,
+    /// The kernel image and boot stack.
+    Kernel,
+    Framebuffer,
+    /// Anything else; never touched.
+    Reserved,
+}
+impl RegionKind {
+    const ALL: [Self; 11] = [
+        Self::Usable,
+        Self::BootServices,
+        Self::AcpiReclaim,
+        Self::AcpiNvs,
+        Self::Mmio,
+        Self::RuntimeCode,
+        Self::RuntimeData,
+        Self::Bootloader,
+        Self::Kernel,
+        Self::Framebuffer,
+        Self::Reserved,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Usable => "USABLE",
+            Self::BootServices => "BOOT SERVICES",
+            Self::AcpiReclaim => "ACPI RECLAIM",
+            Self::AcpiNvs => "ACPI NVS",
+            Self::Mmio => "MMIO",
+            Self::RuntimeCode => "RUNTIME CODE",
+            Self::RuntimeData => "RUNTIME DATA",
+            Self::Bootloader => "BOOTLOADER",
+            Self::Kernel => "KERNEL",
+            Self::Framebuffer => "FRAMEBUFFER",
+            Self::Reserved => "RESERVED",
+        }
+    }
+}
+
+/// Caching and protection attributes of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegionAttrs(u8);
+impl RegionAttrs {
+    /// Write-back cacheable.
+    pub const WB: Self = Self(1 << 0);
+    /// Uncacheable.
+    pub const UC: Self = Self(1 << 1);
+    /// Write-combining.
+    pub const WC: Self = Self(1 << 2);
+    /// Write-through.
+    pub const WT: Self = Self(1 << 3);
+    /// Not executable.
+    pub const NX: Self = Self(1 << 4);
+    /// Read-only.
+    pub const RO: Self = Self(1 << 5);
+    /// Used by UEFI runtime services.
+    pub const RUNTIME: Self = Self(1 << 6);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Converts UEFI `MEMORY_*` attribute bits.
+    pub const fn from_efi(attribute: u64) -> Self {
+        let mut ret = Self::empty();
+        let bits = [
+            (efi::MEMORY_WB, Self::WB),
+            (efi::MEMORY_UC, Self::UC),
+            (efi::MEMORY_WC, Self::WC),
+            (efi::MEMORY_WT, Self::WT),
+            (efi::MEMORY_XP, Self::NX),
+            (efi::MEMORY_RO, Self::RO),
+            (efi::MEMORY_RUNTIME, Self::RUNTIME),
+        ];
+        let mut i = 0;
+        while i < bits.len() {
+            if attribute & bits[i].0 != 0 {
+                ret = ret.union(bits[i].1);
+            }
+            i += 1;
+        }
+        ret
+    }
+}
+impl fmt::Display for RegionAttrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::WB, "WB"),
+            (Self::UC, "UC"),
+            (Self::WC, "WC"),
+            (Self::WT, "WT"),
+            (Self::NX, "NX"),
+            (Self::RO, "RO"),
+            (Self::RUNTIME, "RT"),
+        ];
+        let mut first = true;
+        for (attr, name) in names {
+            if self.contains(attr) {
+                if !first {
+                    f.write_char('|')?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        if first { f.write_char('-') } else { Ok(()) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub size: usize,
+    pub kind: RegionKind,
+    pub attrs: RegionAttrs,
+}
+impl MemoryRegion {
+    pub const fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+/// Most regions a map can hold, after merging.
+const MAX_REGIONS: usize = 512;
+
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_REGIONS],
+    len: usize,
+}
+
+lazy_static! {
+    static ref MEMORY_MAP: MemoryMap = MemoryMap::from_boot_info(boot_info());
+}
+
+/// Every region, sorted by address.
+pub fn regions() -> &'static [MemoryRegion] {
+    MEMORY_MAP.regions()
+}
+
+/// Every region of the given kind, sorted by address.
+pub fn regions_of(kind: RegionKind) -> impl Iterator<Item = &'static MemoryRegion> {
+    regions().iter().filter(move |r| r.kind == kind)
+}
+
+impl MemoryMap {
+    const fn new() -> Self {
+        Self {
+            regions: [MemoryRegion {
+                start: 0,
+                size: 0,
+                kind: RegionKind::Reserved,
+                attrs: RegionAttrs::empty(),
+            }; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Builds the map from what the loader handed over.
+    pub fn from_boot_info(bi: &HTMOSBootInformation) -> Self {
+        let mut map = Self::new();
+
+        let count = (bi.memory_map_size / bi.memory_desc_size) as usize;
+        for i in 0..count {
+            let ptr =
+                vmm::phys_to_virt(bi.memory_map_addr as usize + i * bi.memory_desc_size as usize);
+            let region = if bi.boot_mode == 0 {
+                // SAFETY: given the bootloader does its job.
+                Self::from_e820(unsafe { &*(ptr as *const E820Entry) })
+            } else {
+                // SAFETY: same as above.
+                Self::from_efi(unsafe { &*(ptr as *const MemoryDescriptor) })
+            };
+            if region.size > 0 {
+                map.push(region);
+            }
+        }
+        map.regions[..map.len].sort_unstable_by_key(|r| r.start);
+
+        // Laid over the firmware's map; these are what the kernel itself knows about.
+        // SAFETY: given from linker.
+        let (kernel_start, kernel_end, stack_start, stack_end) = unsafe {
+            (
+                vmm::kernel_phys(&crate::__kernel_start),
+                vmm::kernel_phys(&crate::__kernel_end),
+                vmm::kernel_phys(&crate::__stack_start),
+                vmm::kernel_phys(&crate::__stack_end),
+            )
+        };
+        map.overlay(kernel_start, kernel_end - kernel_start, RegionKind::Kernel);
+        map.overlay(stack_start, stack_end - stack_start, RegionKind::Kernel);
+        map.overlay(
+            vmm::virt_to_phys(bi as *const _ as usize),
+            size_of::<HTMOSBootInformation>(),
+            RegionKind::Bootloader,
+        );
+        map.overlay(
+            bi.memory_map_addr as usize,
+            bi.memory_map_size as usize,
+            RegionKind::Bootloader,
+        );
+        if bi.framebuffer_addr > 0 {
+            map.overlay(
+                bi.framebuffer_addr as usize,
+                bi.framebuffer_size as usize,
+                RegionKind::Framebuffer,
+            );
+        }
+
+        map.merge();
+        map
+    }
+
+    fn from_e820(entry: &E820Entry) -> MemoryRegion {
+        let kind = match E820EntryType::from_raw(entry.entry_type) {
+            Some(E820EntryType::Free) => RegionKind::Usable,
+            Some(E820EntryType::AcpiReclaim) => RegionKind::AcpiReclaim,
+            Some(E820EntryType::AcpiNvs) => RegionKind::AcpiNvs,
+            _ => RegionKind::Reserved,
+        };
+        // E820 says nothing about caching; RAM is write-back.
+        let attrs = match kind {
+            RegionKind::Reserved => RegionAttrs::empty(),
+            _ => RegionAttrs::WB,
+        };
+        MemoryRegion {
+            start: entry.base as usize,
+            size: entry.length as usize,
+            kind,
+            attrs,
+        }
+    }
+
+    fn from_efi(desc: &MemoryDescriptor) -> MemoryRegion {
+        let kind = match desc.r#type {
+            efi::LOADER_CODE | efi::LOADER_DATA | efi::CONVENTIONAL_MEMORY => RegionKind::Usable,
+            efi::BOOT_SERVICES_CODE | efi::BOOT_SERVICES_DATA => RegionKind::BootServices,
+            efi::ACPI_RECLAIM_MEMORY => RegionKind::AcpiReclaim,
+            efi::ACPI_MEMORY_NVS => RegionKind::AcpiNvs,
+            efi::MEMORY_MAPPED_IO | efi::MEMORY_MAPPED_IO_PORT_SPACE => RegionKind::Mmio,
+            efi::RUNTIME_SERVICES_CODE => RegionKind::RuntimeCode,
+            efi::RUNTIME_SERVICES_DATA => RegionKind::RuntimeData,
+            _ => RegionKind::Reserved,
+        };
+        MemoryRegion {
+            start: desc.physical_start as usize,
+            size: desc.number_of_pages as usize * 4096,
+            kind,
+            attrs: RegionAttrs::from_efi(desc.attribute),
+        }
+    }
+
+    fn push(&mut self, region: MemoryRegion) {
+        if self.len == MAX_REGIONS {
+            println!(
+                "MEM MAP FULL: dropped 0x{:016X} (0x{:X} bytes, {})",
+                region.start,
+                region.size,
+                region.kind.name()
+            );
+            return;
+        }
+        self.regions[self.len] = region;
+        self.len += 1;
+    }
+
+    /// Gives `start..start + size` the given kind, cutting up whatever was there.  Keeps the map sorted.
+    fn overlay(&mut self, start: usize, size: usize, kind: RegionKind) {
+        if size == 0 {
+            return;
+        }
+        let end = start + size;
+        let mut attrs = RegionAttrs::WB;
+
+        let mut i = 0;
+        while i < self.len {
+            let r = self.regions[i];
+            if r.end() <= start || r.start >= end {
+                i += 1;
+                continue;
+            }
+            attrs = r.attrs;
+
+            // Keep the parts sticking out on either side.
+            let left = MemoryRegion {
+                size: start.saturating_sub(r.start),
+                ..r
+            };
+            let right = MemoryRegion {
+                start: end,
+                size: r.end().saturating_sub(end),
+                ..r
+            };
+            self.remove(i);
+            if right.size > 0 {
+                self.insert(i, right);
+            }
+            if left.size > 0 {
+                self.insert(i, left);
+                i += 1;
+            }
+        }
+
+        let at = self.regions[..self.len].partition_point(|r| r.start < start);
+        self.insert(
+            at,
+            MemoryRegion {
+                start,
+                size,
+                kind,
+                attrs,
+            },
+        );
+    }
+
+    fn insert(&mut self, at: usize, region: MemoryRegion) {
+        if self.len == MAX_REGIONS {
+            self.len -= 1;
+            let dropped = self.regions[self.len];
+            println!(
+                "MEM MAP FULL: dropped 0x{:016X} (0x{:X} bytes, {})",
+                dropped.start,
+                dropped.size,
+                dropped.kind.name()
+            );
+        }
+        self.regions.copy_within(at..self.len, at + 1);
+        self.regions[at] = region;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, at: usize) {
+        self.regions.copy_within(at + 1..self.len, at);
+        self.len -= 1;
+    }
+
+    /// Merges touching neighbours that have the same kind and attributes.
+    fn merge(&mut self) {
+        let mut out = 0;
+        for i in 0..self.len {
+            let r = self.regions[i];
+            if out > 0 {
+                let last = &mut self.regions[out - 1];
+                if last.end() == r.start && last.kind == r.kind && last.attrs == r.attrs {
+                    last.size += r.size;
+                    continue;
+                }
+            }
+            self.regions[out] = r;
+            out += 1;
+        }
+        self.len = out;
+    }
+}
+
+/// Prints how much memory there is of each kind.  Short enough to fit on the screen during boot.
+pub fn report() {
+    let regions = regions();
+    println!("MEMORY MAP: {} regions", regions.len());
+    for kind in RegionKind::ALL {
+        let (count, bytes) = regions_of(kind).fold((0, 0), |(c, b), r| (c + 1, b + r.size));
+        if count > 0 {
+            println!(
+                "  {:<13} {:>4} regions {:>10} KiB",
+                kind.name(),
+                count,
+                bytes / 1024
+            );
+        }
+    }
+}
+
+/// Writes every region, one per line.  Only the self-tests print the whole map so far.
+#[cfg(feature = "selftest")]
+pub fn dump(out: &mut dyn Write) -> fmt::Result {
+    for r in regions() {
+        writeln!(
+            out,
+            "0x{:016X}-0x{:016X} {:<13} {}",
+            r.start,
+            r.end(),
+            r.kind.name(),
+            r.attrs
+        )?;
+    }
+    Ok(())
+}
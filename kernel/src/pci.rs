@@ -0,0 +1,545 @@
+//! **HyperText Markup PCI**
+//!
+//! Finds every PCI function on every bus and describes it: IDs, class, BARs (with their sizes), capabilities, MSI and
+//! MSI-X.  Configuration space is read through ECAM when the ACPI MCFG table lists it, and through ports 0xCF8/0xCFC
+//! (configuration mechanism #1, which only reaches segment 0 and the first 256 bytes of each function) otherwise.
+//!
+//! Drivers look their devices up with `find_by_id` or `find_by_class` once `init` has run.  There are no drivers yet,
+//! so only the self-tests do.
+
+use crate::{sync::SpinLock, vmm};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+const VENDOR_NONE: u16 = 0xFFFF;
+
+const REG_ID: u16 = 0x00;
+const REG_COMMAND: u16 = 0x04;
+const REG_CLASS: u16 = 0x08;
+const REG_HEADER: u16 = 0x0C;
+const REG_BAR0: u16 = 0x10;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT: u16 = 0x3C;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+
+/// Where a function sits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// An ECAM window from the MCFG table: 4 KiB of configuration space per function, 1 MiB per bus.
+struct Ecam {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /// Where bus `start_bus` is mapped.
+    virt: usize,
+}
+
+/// How configuration space is reached.
+enum Access {
+    Ecam(Vec<Ecam>),
+    /// Selecting a register and accessing it are two steps, hence the lock.
+    Legacy(SpinLock<()>),
+}
+impl Access {
+    /// The mapped address of `offset` in the configuration space of `addr`, through ECAM.
+    fn ecam(windows: &[Ecam], addr: Address, offset: u16) -> Option<usize> {
+        let window = windows
+            .iter()
+            .find(|w| w.segment == addr.segment && (w.start_bus..=w.end_bus).contains(&addr.bus))?;
+        Some(
+            window.virt
+                + (((addr.bus - window.start_bus) as usize) << 20
+                    | (addr.device as usize) << 15
+                    | (addr.function as usize) << 12
+                    | offset as usize),
+        )
+    }
+
+    fn read(&self, addr: Address, offset: u16) -> u32 {
+        let offset = offset & !0b11;
+        match self {
+            Self::Ecam(windows) => match Self::ecam(windows, addr, offset) {
+                // SAFETY: inside a mapped ECAM window.
+                Some(reg) => unsafe { (reg as *const u32).read_volatile() },
+                None => u32::MAX,
+            },
+            Self::Legacy(lock) => {
+                if addr.segment != 0 || offset >= 0x100 {
+                    return u32::MAX;
+                }
+                let _guard = lock.lock();
+                // SAFETY: the configuration address and data ports.
+                unsafe {
+                    Port::<u32>::new(0xCF8).write(legacy_address(addr, offset));
+                    Port::<u32>::new(0xCFC).read()
+                }
+            }
+        }
+    }
+
+    fn write(&self, addr: Address, offset: u16, value: u32) {
+        let offset = offset & !0b11;
+        match self {
+            Self::Ecam(windows) => {
+                if let Some(reg) = Self::ecam(windows, addr, offset) {
+                    // SAFETY: inside a mapped ECAM window.
+                    unsafe { (reg as *mut u32).write_volatile(value) }
+                }
+            }
+            Self::Legacy(lock) => {
+                if addr.segment != 0 || offset >= 0x100 {
+                    return;
+                }
+                let _guard = lock.lock();
+                // SAFETY: same as above.
+                unsafe {
+                    Port::<u32>::new(0xCF8).write(legacy_address(addr, offset));
+                    Port::<u32>::new(0xCFC).write(value);
+                }
+            }
+        }
+    }
+}
+
+const fn legacy_address(addr: Address, offset: u16) -> u32 {
+    1 << 31
+        | (addr.bus as u32) << 16
+        | (addr.device as u32) << 11
+        | (addr.function as u32) << 8
+        | offset as u32
+}
+
+static ACCESS: Once<Access> = Once::new();
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+fn access() -> &'static Access {
+    ACCESS.get().expect("PCI not initialized")
+}
+
+/// Reads the 32-bit configuration register at `offset` (rounded down to 4 bytes) of `addr`.
+pub fn read32(addr: Address, offset: u16) -> u32 {
+    access().read(addr, offset)
+}
+
+pub fn write32(addr: Address, offset: u16, value: u32) {
+    access().write(addr, offset, value)
+}
+
+pub fn read16(addr: Address, offset: u16) -> u16 {
+    (read32(addr, offset) >> ((offset & 0b10) * 8)) as u16
+}
+
+pub fn write16(addr: Address, offset: u16, value: u16) {
+    // The status register shares the command register's dword, and writing its error bits back clears them.
+    if offset == REG_COMMAND {
+        return write32(addr, offset, value as u32);
+    }
+    let shift = (offset & 0b10) * 8;
+    let old = read32(addr, offset) & !(0xFFFF << shift);
+    write32(addr, offset, old | (value as u32) << shift);
+}
+
+pub fn read8(addr: Address, offset: u16) -> u8 {
+    (read32(addr, offset) >> ((offset & 0b11) * 8)) as u8
+}
+
+/// A base address register, sized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Takes up this BAR and the next.
+        is_64: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+#[cfg(feature = "selftest")]
+impl Bar {
+    pub const fn size(&self) -> u64 {
+        match *self {
+            Self::Memory { size, .. } => size,
+            Self::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+/// The MSI capability.
+#[derive(Debug, Clone, Copy)]
+pub struct Msi {
+    /// Where the capability sits in configuration space.
+    pub offset: u16,
+    pub is_64: bool,
+    pub per_vector_masking: bool,
+    /// How many vectors the function can ask for.
+    pub max_vectors: u8,
+}
+
+/// The MSI-X capability.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiX {
+    /// Where the capability sits in configuration space.
+    pub offset: u16,
+    pub table_size: u16,
+    /// BAR holding the vector table, and the table's offset into it.
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// BAR holding the pending bit array, and the array's offset into it.
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+/// A PCI function.
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Layout of the rest of the header (0 for devices, 1 for PCI-to-PCI bridges, 2 for CardBus bridges).
+    pub header_type: u8,
+    pub interrupt_line: u8,
+    /// Legacy interrupt pin (1 to 4 for INTA# to INTD#, 0 for none).
+    pub interrupt_pin: u8,
+    /// The second half of a 64-bit BAR is None.
+    pub bars: [Option<Bar>; 6],
+    /// (ID, offset) of each capability, in list order.
+    pub capabilities: Vec<(u8, u16)>,
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+}
+impl PciDevice {
+    /// Reads the function at `addr`, if there is one.
+    fn probe(addr: Address) -> Option<Self> {
+        let id = read32(addr, REG_ID);
+        if id as u16 == VENDOR_NONE {
+            return None;
+        }
+        let class = read32(addr, REG_CLASS);
+        let header_type = read8(addr, REG_HEADER + 2) & 0x7F;
+        let interrupt = read32(addr, REG_INTERRUPT);
+
+        let mut dev = Self {
+            address: addr,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            msi: None,
+            msix: None,
+        };
+        dev.read_bars();
+        dev.read_capabilities();
+        Some(dev)
+    }
+
+    /// Sizes every BAR by writing all ones and reading back which bits stuck, with decoding off meanwhile so the
+    /// device doesn't answer at the bogus address.
+    fn read_bars(&mut self) {
+        let count = match self.header_type {
+            0 => 6,
+            1 => 2,
+            _ => return,
+        };
+        let addr = self.address;
+        let command = read16(addr, REG_COMMAND);
+        write16(addr, REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let mut i = 0;
+        while i < count {
+            let reg = REG_BAR0 + i as u16 * 4;
+            let low = read32(addr, reg);
+            write32(addr, reg, u32::MAX);
+            let low_mask = read32(addr, reg);
+            write32(addr, reg, low);
+
+            if low & 1 != 0 {
+                let mask = low_mask & !0b11;
+                if mask != 0 {
+                    self.bars[i] = Some(Bar::Io {
+                        port: low & !0b11,
+                        // Only the low 16 bits decode on most machines.
+                        size: (!(mask | 0xFFFF_0000)).wrapping_add(1),
+                    });
+                }
+                i += 1;
+                continue;
+            }
+
+            let is_64 = (low >> 1) & 0b11 == 0b10 && i + 1 < count;
+            let (mut base, mut mask) = ((low & !0xF) as u64, (low_mask & !0xF) as u64);
+            if is_64 {
+                let high = read32(addr, reg + 4);
+                write32(addr, reg + 4, u32::MAX);
+                let high_mask = read32(addr, reg + 4);
+                write32(addr, reg + 4, high);
+                base |= (high as u64) << 32;
+                mask |= (high_mask as u64) << 32;
+            } else if mask != 0 {
+                mask |= 0xFFFF_FFFF_0000_0000;
+            }
+            if mask != 0 {
+                self.bars[i] = Some(Bar::Memory {
+                    addr: base,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: low & (1 << 3) != 0,
+                    is_64,
+                });
+            }
+            i += if is_64 { 2 } else { 1 };
+        }
+
+        write16(addr, REG_COMMAND, command);
+    }
+
+    fn read_capabilities(&mut self) {
+        let addr = self.address;
+        if read16(addr, REG_COMMAND + 2) & STATUS_CAPABILITIES == 0 || self.header_type == 2 {
+            return;
+        }
+
+        let mut offset = (read8(addr, REG_CAPABILITIES) & !0b11) as u16;
+        // A list longer than configuration space can hold means it loops.
+        while offset >= 0x40 && self.capabilities.len() < 48 {
+            let header = read32(addr, offset);
+            let id = header as u8;
+            let control = (header >> 16) as u16;
+            self.capabilities.push((id, offset));
+            match id {
+                CAP_MSI => {
+                    self.msi = Some(Msi {
+                        offset,
+                        is_64: control & (1 << 7) != 0,
+                        per_vector_masking: control & (1 << 8) != 0,
+                        max_vectors: 1 << ((control >> 1) & 0b111).min(5),
+                    })
+                }
+                CAP_MSIX => {
+                    let table = read32(addr, offset + 4);
+                    let pba = read32(addr, offset + 8);
+                    self.msix = Some(MsiX {
+                        offset,
+                        table_size: (control & 0x7FF) + 1,
+                        table_bar: (table & 0b111) as u8,
+                        table_offset: table & !0b111,
+                        pba_bar: (pba & 0b111) as u8,
+                        pba_offset: pba & !0b111,
+                    })
+                }
+                _ => {}
+            }
+            offset = ((header >> 8) as u8 & !0b11) as u16;
+        }
+    }
+
+    /// What the class code says this is.
+    pub const fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "storage controller",
+            (0x02, _) => "network controller",
+            (0x03, _) => "display controller",
+            (0x04, _) => "multimedia controller",
+            (0x05, _) => "memory controller",
+            (0x06, 0x00) => "host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI-to-PCI bridge",
+            (0x06, _) => "bridge",
+            (0x07, _) => "communication controller",
+            (0x08, _) => "system peripheral",
+            (0x09, _) => "input device controller",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "serial bus controller",
+            (0x0D, _) => "wireless controller",
+            _ => "unknown device",
+        }
+    }
+}
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} (rev {:02x}) [{:02x}{:02x}{:02x}] {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.revision,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            self.class_name()
+        )?;
+        if (1..=4).contains(&self.interrupt_pin) {
+            write!(f, ", INT{}#", (b'A' + self.interrupt_pin - 1) as char)?;
+            // 0xFF is "unknown or not connected".
+            if self.interrupt_line != 0xFF {
+                write!(f, " (IRQ {})", self.interrupt_line)?;
+            }
+        }
+        if let Some(msix) = self.msix {
+            write!(
+                f,
+                ", MSI-X at 0x{:02X} ({} vectors, table BAR{}+0x{:X}, PBA BAR{}+0x{:X})",
+                msix.offset,
+                msix.table_size,
+                msix.table_bar,
+                msix.table_offset,
+                msix.pba_bar,
+                msix.pba_offset
+            )?;
+        } else if let Some(msi) = self.msi {
+            write!(
+                f,
+                ", MSI at 0x{:02X} ({} vectors",
+                msi.offset, msi.max_vectors
+            )?;
+            if msi.is_64 {
+                f.write_str(", 64-bit")?;
+            }
+            if msi.per_vector_masking {
+                f.write_str(", maskable")?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the ECAM windows out of the MCFG table at (virtual address) `mcfg` and maps them.
+fn map_ecam(mcfg: usize) -> Result<Vec<Ecam>, &'static str> {
+    // SAFETY: given a valid MCFG; after the header and 8 reserved bytes come 16-byte entries.
+    let length = unsafe { ((mcfg + 4) as *const u32).read_unaligned() } as usize;
+    let mut windows = Vec::new();
+    for entry in (raw_acpi::SDT_HEADER_SIZE + 8..length.saturating_sub(15)).step_by(16) {
+        let (base, segment, start_bus, end_bus) = unsafe {
+            let ptr = mcfg + entry;
+            (
+                (ptr as *const u64).read_unaligned() as usize,
+                ((ptr + 8) as *const u16).read_unaligned(),
+                ((ptr + 10) as *const u8).read(),
+                ((ptr + 11) as *const u8).read(),
+            )
+        };
+        if end_bus < start_bus {
+            continue;
+        }
+        let size = (end_bus - start_bus) as usize + 1;
+        windows.push(Ecam {
+            segment,
+            start_bus,
+            end_bus,
+            virt: vmm::map_mmio(base + ((start_bus as usize) << 20), size << 20)?,
+        });
+    }
+    if windows.is_empty() {
+        return Err("MCFG lists no ECAM windows");
+    }
+    Ok(windows)
+}
+
+/// Finds every PCI function, through ECAM if `mcfg` points at the ACPI MCFG table and ports otherwise.
+pub fn init(mcfg: Option<usize>) -> Result<&'static [PciDevice], &'static str> {
+    if DEVICES.is_completed() {
+        return Err("PCI already initialized");
+    }
+    let access = match mcfg.map(map_ecam) {
+        Some(Ok(windows)) => Access::Ecam(windows),
+        _ => Access::Legacy(SpinLock::new(())),
+    };
+    let segments: Vec<(u16, u8, u8)> = match ACCESS.call_once(|| access) {
+        Access::Ecam(windows) => windows
+            .iter()
+            .map(|w| (w.segment, w.start_bus, w.end_bus))
+            .collect(),
+        Access::Legacy(_) => alloc::vec![(0, 0, 255)],
+    };
+
+    let mut devices = Vec::new();
+    for (segment, start_bus, end_bus) in segments {
+        for bus in start_bus..=end_bus {
+            for device in 0..32 {
+                let addr = |function| Address {
+                    segment,
+                    bus,
+                    device,
+                    function,
+                };
+                let Some(first) = PciDevice::probe(addr(0)) else {
+                    continue;
+                };
+                let multifunction = read8(addr(0), REG_HEADER + 2) & 0x80 != 0;
+                devices.push(first);
+                if multifunction {
+                    devices.extend((1..8).filter_map(|f| PciDevice::probe(addr(f))));
+                }
+            }
+        }
+    }
+    Ok(DEVICES.call_once(|| devices))
+}
+
+/// Every function found (empty before `init`).
+#[cfg(feature = "selftest")]
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], |d| d.as_slice())
+}
+
+/// Whether configuration space is reached through ECAM (as opposed to ports).
+pub fn uses_ecam() -> bool {
+    matches!(ACCESS.get(), Some(Access::Ecam(_)))
+}
+
+#[cfg(feature = "selftest")]
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |d| d.vendor_id == vendor_id && d.device_id == device_id)
+}
+
+/// Functions of the given class and subclass (any subclass, if None).
+#[cfg(feature = "selftest")]
+pub fn find_by_class(class: u8, subclass: Option<u8>) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |d| d.class == class && subclass.is_none_or(|s| d.subclass == s))
+}
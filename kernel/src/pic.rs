@@ -0,0 +1,135 @@
+//! **HyperText Markup PIC**
+//!
+//! The two chained 8259 PICs of the PC/AT, for machines without APICs.  `init` moves their 16 IRQs to
+//! `base..base + 16`, clear of the CPU exceptions, with every line masked; `unmask` lets single lines through.
+//!
+//! Machines with APICs still have the PICs (if the MADT says `PCAT_COMPAT`), and even fully masked they can raise a
+//! spurious IRQ 7 or 15, so they get remapped there too (see `spurious`).
+
+use crate::{
+    port_io::{inb, outb},
+    sync::SpinLock,
+};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// ICW1: initialize, cascaded, ICW4 follows.
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/// The master IRQ the slave is wired to.
+const CASCADE_IRQ: u8 = 2;
+
+/// Vector of IRQ 0; 0 until `init`.
+static BASE: AtomicU8 = AtomicU8::new(0);
+/// Which lines are masked, IRQ 0 in bit 0.
+static MASK: SpinLock<u16> = SpinLock::new(0xFFFF);
+
+/// Gives the PICs a moment between commands; older ones need it.
+fn io_wait() {
+    outb(0x80, 0);
+}
+
+/// Remaps both PICs to raise IRQ `n` as vector `base + n` (`base` a multiple of 8), all masked.
+pub fn init(base: u8) -> Result<(), &'static str> {
+    if !base.is_multiple_of(8) || !(32..=0xF0).contains(&base) {
+        return Err("PIC base vector must be a multiple of 8 from 32 up");
+    }
+    let mut mask = MASK.lock();
+
+    outb(MASTER_COMMAND, ICW1_INIT);
+    io_wait();
+    outb(SLAVE_COMMAND, ICW1_INIT);
+    io_wait();
+    outb(MASTER_DATA, base);
+    io_wait();
+    outb(SLAVE_DATA, base + 8);
+    io_wait();
+    // ICW3: which master line has the slave, and the slave's identity on it.
+    outb(MASTER_DATA, 1 << CASCADE_IRQ);
+    io_wait();
+    outb(SLAVE_DATA, CASCADE_IRQ);
+    io_wait();
+    outb(MASTER_DATA, ICW4_8086);
+    io_wait();
+    outb(SLAVE_DATA, ICW4_8086);
+    io_wait();
+
+    *mask = 0xFFFF;
+    write_mask(*mask);
+    BASE.store(base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Vector of IRQ 0, once `init` has run.
+pub fn base() -> Option<u8> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(base),
+    }
+}
+
+fn write_mask(mask: u16) {
+    outb(MASTER_DATA, mask as u8);
+    outb(SLAVE_DATA, (mask >> 8) as u8);
+}
+
+/// Lets `irq` through.
+pub fn unmask(irq: u8) -> Result<(), &'static str> {
+    if irq >= 16 {
+        return Err("the PICs only have IRQs 0-15");
+    }
+    let mut mask = MASK.lock();
+    *mask &= !(1 << irq);
+    // The slave's IRQs only get through the master's cascade line.
+    if irq >= 8 {
+        *mask &= !(1 << CASCADE_IRQ);
+    }
+    write_mask(*mask);
+    Ok(())
+}
+
+/// Stops `irq` getting through.
+pub fn mask(irq: u8) -> Result<(), &'static str> {
+    if irq >= 16 {
+        return Err("the PICs only have IRQs 0-15");
+    }
+    let mut mask = MASK.lock();
+    *mask |= 1 << irq;
+    if *mask & 0xFF00 == 0xFF00 {
+        *mask |= 1 << CASCADE_IRQ;
+    }
+    write_mask(*mask);
+    Ok(())
+}
+
+/// Signals the end of `irq`.  The slave's IRQs need both PICs told.
+pub fn eoi(irq: u8) {
+    if irq >= 8 {
+        outb(SLAVE_COMMAND, OCW2_EOI);
+    }
+    outb(MASTER_COMMAND, OCW2_EOI);
+}
+
+/// Whether `irq` (7 or 15) is spurious: raised as a line dropped again before the CPU took it, so never really in
+/// service.  A spurious IRQ mustn't get an EOI, except that the master saw a real one on its cascade line for a
+/// spurious IRQ 15, which this sends.
+pub fn spurious(irq: u8) -> bool {
+    let (command, line) = if irq >= 8 {
+        (SLAVE_COMMAND, irq - 8)
+    } else {
+        (MASTER_COMMAND, irq)
+    };
+    outb(command, OCW3_READ_ISR);
+    let in_service = inb(command) & (1 << line) != 0;
+    if !in_service && irq >= 8 {
+        outb(MASTER_COMMAND, OCW2_EOI);
+    }
+    !in_service
+}
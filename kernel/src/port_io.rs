@@ -0,0 +1,22 @@
+//! Port I/O, for the legacy devices both x86 architectures share.  Inline assembly rather than the `x86_64` crate, so
+//! it works on 32-bit too.
+//!
+//! These are safe to call, but only meant for ports a driver owns; poking at random ports can do anything.
+
+#[inline]
+pub fn inb(port: u16) -> u8 {
+    let value: u8;
+    // SAFETY: see above.
+    unsafe {
+        core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+#[inline]
+pub fn outb(port: u16, value: u8) {
+    // SAFETY: see above.
+    unsafe {
+        core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
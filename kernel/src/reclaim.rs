@@ -0,0 +1,179 @@
+//! **HyperText Markup Memory Reclaim**
+//!
+//! Some memory is only free once the kernel is done with what the firmware left in it.  `init` pulls those ranges
+//! out of the memory map before the frame allocator sees it, and `reclaim` hands them over one stage at a time.
+//!
+//! Runtime-services regions are never reclaimed.  On x86_64 they are kept here, with their attributes, for whoever has
+//! to map or relocate them.
+
+#[cfg(target_arch = "x86_64")]
+use crate::memmap::RegionAttrs;
+use crate::{
+    frame_alloc::{FRAME_SIZE, FRAMES},
+    memmap::{self, RegionKind},
+    sync::SpinLock,
+};
+use htmos_mem_region::{MemoryGlue, endt};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    /// Boot-services code and data.  Safe once the kernel runs on its own page tables.
+    BootServices,
+    /// ACPI tables.  Safe once they've been parsed or copied into kernel-owned memory.
+    Acpi,
+}
+
+/// A UEFI runtime-services region (or any region marked `MEMORY_RUNTIME`), which the firmware keeps using after boot.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct RuntimeRegion {
+    pub phys: usize,
+    pub pages: usize,
+    /// Holds code (`RUNTIME_SERVICES_CODE`) rather than data.
+    pub code: bool,
+    pub attrs: RegionAttrs,
+}
+
+struct Pending {
+    boot_services: ([(usize, usize); 256], usize),
+    acpi: ([(usize, usize); 256], usize),
+    /// Which stages `reclaim` has run for, by `Stage`.
+    reclaimed: [bool; 2],
+    #[cfg(target_arch = "x86_64")]
+    runtime: [RuntimeRegion; 64],
+    #[cfg(target_arch = "x86_64")]
+    runtime_count: usize,
+}
+
+static PENDING: SpinLock<Pending> = SpinLock::new(Pending {
+    boot_services: ([(0, 0); 256], 0),
+    acpi: ([(0, 0); 256], 0),
+    reclaimed: [false; 2],
+    #[cfg(target_arch = "x86_64")]
+    runtime: [RuntimeRegion {
+        phys: 0,
+        pages: 0,
+        code: false,
+        attrs: RegionAttrs::empty(),
+    }; 64],
+    #[cfg(target_arch = "x86_64")]
+    runtime_count: 0,
+});
+
+/// Keeps only the parts of `list` that are also in `mmap`, so nothing `get_mmap` ripped out is ever handed back.
+fn intersect(
+    list: &([(usize, usize); 256], usize),
+    mmap: &([(usize, usize); 256], usize),
+) -> ([(usize, usize); 256], usize) {
+    let mut ret = ([(0, 0); 256], 0);
+    for &(s, l) in list.sections() {
+        for &(ms, ml) in mmap.sections() {
+            let (start, end) = (s.max(ms), endt((s, l)).min(endt((ms, ml))));
+            if start < end {
+                ret.glue_section(start, end - start);
+            }
+        }
+    }
+    ret
+}
+
+/// Moves every range that only becomes free later out of `mmap`, and (on x86_64) records the runtime-services
+/// regions.
+///
+/// Has to run before `frame_alloc::init`, with the map `get_mmap` gave.
+pub fn init(mmap: &mut ([(usize, usize); 256], usize)) {
+    let mut pending = PENDING.lock();
+    let mut boot_services = ([(0, 0); 256], 0);
+    let mut acpi = ([(0, 0); 256], 0);
+
+    for r in memmap::regions() {
+        match r.kind {
+            RegionKind::BootServices => {
+                boot_services.glue_section(r.start, r.size);
+            }
+            RegionKind::AcpiReclaim => {
+                acpi.glue_section(r.start, r.size);
+            }
+            #[cfg(target_arch = "x86_64")]
+            k if k == RegionKind::RuntimeCode
+                || k == RegionKind::RuntimeData
+                || r.attrs.contains(RegionAttrs::RUNTIME) =>
+            {
+                if pending.runtime_count == pending.runtime.len() {
+                    panic!("too many runtime-services regions");
+                }
+                let idx = pending.runtime_count;
+                pending.runtime[idx] = RuntimeRegion {
+                    phys: r.start,
+                    pages: r.size.div_ceil(FRAME_SIZE),
+                    code: k == RegionKind::RuntimeCode,
+                    attrs: r.attrs,
+                };
+                pending.runtime_count += 1;
+            }
+            _ => {}
+        }
+    }
+
+    pending.boot_services = intersect(&boot_services, mmap);
+    pending.acpi = intersect(&acpi, mmap);
+    for &(s, l) in pending
+        .boot_services
+        .sections()
+        .iter()
+        .chain(pending.acpi.sections())
+    {
+        mmap.rip_section(s, l);
+    }
+}
+
+/// Highest address of anything still waiting to be reclaimed, so the frame allocator can cover it from the start.
+pub fn top() -> usize {
+    let pending = PENDING.lock();
+    pending
+        .boot_services
+        .sections()
+        .iter()
+        .chain(pending.acpi.sections())
+        .map(|&s| endt(s))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Hands a stage's memory to the frame allocator.  Returns the number of bytes given back (0 the second time).
+pub fn reclaim(stage: Stage) -> usize {
+    let mut pending = PENDING.lock();
+    let list = match stage {
+        Stage::BootServices => &mut pending.boot_services,
+        Stage::Acpi => &mut pending.acpi,
+    };
+
+    let mut frames = FRAMES.lock();
+    let mut ret = 0;
+    for &(s, l) in list.sections() {
+        // Partial frames at the edges were never handed out; they still aren't.
+        let first = s.div_ceil(FRAME_SIZE);
+        let last = (s + l) / FRAME_SIZE;
+        if last > first {
+            frames.release(first * FRAME_SIZE, last - first);
+            ret += (last - first) * FRAME_SIZE;
+        }
+    }
+    *list = ([(0, 0); 256], 0);
+    pending.reclaimed[stage as usize] = true;
+    ret
+}
+
+/// Whether `reclaim` has run for `stage`, and what was in its memory may be anything now.
+pub fn reclaimed(stage: Stage) -> bool {
+    PENDING.lock().reclaimed[stage as usize]
+}
+
+/// Calls `f` for every runtime-services region.
+#[cfg(target_arch = "x86_64")]
+pub fn for_each_runtime_region(mut f: impl FnMut(&RuntimeRegion)) {
+    let pending = PENDING.lock();
+    for r in &pending.runtime[..pending.runtime_count] {
+        f(r);
+    }
+}
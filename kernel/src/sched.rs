@@ -0,0 +1,432 @@
+//! **HyperText Markup Scheduler**
+//!
+//! Kernel threads, and the scheduler that shares the BSP between them.  Every thread has a stack of its own, and
+//! switching threads (`arch::switch_context`) saves the registers it needs there, so a thread picks up right where it
+//! left off.
+//!
+//! Threads take turns round-robin, a slice (`SLICE_TICKS`) at a time, but only with others of the same `Priority`:
+//! nothing runs while something more important is ready.  The clock's tick (`time::tick`, which calls `tick` and
+//! `wake_sleepers`) ends slices and wakes sleepers, and every interrupt handler then switches if that made it time to (`preempt`).  When
+//! nothing is ready, the idle thread halts until the next interrupt.
+//!
+//! - `spawn` - starts a thread on a closure; it exits when that returns.
+//! - `yield_now`, `exit` - for the running thread.  `time::sleep` puts it to sleep (`sleep_until`).
+//! - `WaitQueue` - blocks threads until something (often an interrupt handler) wakes them.
+//!
+//! Only the BSP runs threads; the other CPUs stay parked.
+
+use crate::{arch, sync, sync::SpinLock};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+use core::time::Duration;
+
+/// How many ticks a thread runs before the next one of its priority gets a turn.
+const SLICE_TICKS: u32 = 10;
+const STACK_SIZE: usize = 32 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    /// For threads that mostly wait, and have to answer quickly when woken (input, say).
+    High,
+}
+const PRIORITIES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    /// Until `time::now` reaches the given time.
+    #[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+    Sleeping(Duration),
+    /// On a `WaitQueue`.
+    Blocked,
+    Exited,
+}
+
+/// A thread's stack.
+#[cfg(target_arch = "x86_64")]
+struct Stack(crate::vmm::KernelStack);
+#[cfg(target_arch = "x86_64")]
+impl Stack {
+    fn new() -> Result<Self, &'static str> {
+        crate::vmm::KernelStack::new(STACK_SIZE).map(Self)
+    }
+
+    fn top(&self) -> usize {
+        self.0.top()
+    }
+}
+
+/// A thread's stack.  `u128`s, for the 16-byte alignment.
+#[cfg(target_arch = "x86")]
+struct Stack(Box<[u128]>);
+#[cfg(target_arch = "x86")]
+impl Stack {
+    fn new() -> Result<Self, &'static str> {
+        Ok(Self(
+            alloc::vec![0; STACK_SIZE / size_of::<u128>()].into_boxed_slice(),
+        ))
+    }
+
+    fn top(&self) -> usize {
+        self.0.as_ptr_range().end as usize
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    priority: Priority,
+    state: State,
+    /// Where `switch_context` left the stack pointer, while not running.
+    sp: usize,
+    /// None for the boot thread, which keeps the boot stack.
+    _stack: Option<Stack>,
+    /// What `spawn` was given, until the thread starts.
+    entry: Option<Box<dyn FnOnce() + Send>>,
+}
+
+struct Scheduler {
+    threads: Vec<Thread>,
+    /// Ready threads, by priority.
+    ready: [VecDeque<ThreadId>; PRIORITIES],
+    current: ThreadId,
+    idle: ThreadId,
+    /// Ticks left of the current thread's slice.
+    slice: u32,
+    need_resched: bool,
+    /// Threads that exited, for the idle thread to free (they can't free their own stacks).
+    dead: Vec<Thread>,
+    next_id: u64,
+}
+impl Scheduler {
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads
+            .iter_mut()
+            .find(|t| t.id == id)
+            .expect("no such thread")
+    }
+
+    /// Queues `id` to run, and asks for a switch if it's more important than what's running.
+    fn make_ready(&mut self, id: ThreadId) {
+        let current = self.thread_mut(self.current).priority;
+        let thread = self.thread_mut(id);
+        thread.state = State::Ready;
+        let priority = thread.priority;
+        self.ready[priority as usize].push_back(id);
+        if self.current == self.idle || priority > current {
+            self.need_resched = true;
+        }
+    }
+
+    /// Picks the next thread, and returns where to save the current one's stack pointer and the next one's, unless
+    /// it's the same thread.
+    fn switch(&mut self) -> Option<(*mut usize, usize)> {
+        let old = self.current;
+        if self.thread_mut(old).state == State::Running {
+            if old == self.idle {
+                self.thread_mut(old).state = State::Ready;
+            } else {
+                self.make_ready(old);
+            }
+        }
+
+        let next = self
+            .ready
+            .iter_mut()
+            .rev()
+            .find_map(VecDeque::pop_front)
+            .unwrap_or(self.idle);
+        self.current = next;
+        self.slice = SLICE_TICKS;
+        self.need_resched = false;
+        self.thread_mut(next).state = State::Running;
+        if next == old {
+            return None;
+        }
+
+        let new_sp = self.thread_mut(next).sp;
+        let old_sp = if self.thread_mut(old).state == State::Exited {
+            let i = self.threads.iter().position(|t| t.id == old)?;
+            self.dead.push(self.threads.swap_remove(i));
+            &raw mut self.dead.last_mut()?.sp
+        } else {
+            &raw mut self.thread_mut(old).sp
+        };
+        Some((old_sp, new_sp))
+    }
+}
+
+static SCHED: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+/// Makes the code running now the boot thread, and starts the idle thread.  Threads only start switching once the
+/// timer's tick comes through `tick`.
+pub fn init() -> Result<(), &'static str> {
+    let idle_stack = Stack::new()?;
+    let idle_sp = arch::init_thread_stack(idle_stack.top(), idle_loop);
+
+    let mut sched = SCHED.lock();
+    if sched.is_some() {
+        return Err("scheduler already running");
+    }
+    let (boot, idle) = (ThreadId(0), ThreadId(1));
+    *sched = Some(Scheduler {
+        threads: alloc::vec![
+            Thread {
+                id: boot,
+                name: "boot",
+                priority: Priority::Normal,
+                state: State::Running,
+                sp: 0,
+                _stack: None,
+                entry: None,
+            },
+            Thread {
+                id: idle,
+                name: "idle",
+                priority: Priority::Low,
+                state: State::Ready,
+                sp: idle_sp,
+                _stack: Some(idle_stack),
+                entry: None,
+            },
+        ],
+        ready: Default::default(),
+        current: boot,
+        idle,
+        slice: SLICE_TICKS,
+        need_resched: false,
+        dead: Vec::new(),
+        next_id: 2,
+    });
+    Ok(())
+}
+
+/// Starts a thread running `f`.
+pub fn spawn(
+    name: &'static str,
+    priority: Priority,
+    f: impl FnOnce() + Send + 'static,
+) -> Result<ThreadId, &'static str> {
+    let stack = Stack::new()?;
+    let sp = arch::init_thread_stack(stack.top(), thread_start);
+
+    let mut guard = SCHED.lock();
+    let sched = guard.as_mut().ok_or("scheduler not running")?;
+    let id = ThreadId(sched.next_id);
+    sched.next_id += 1;
+    sched.threads.push(Thread {
+        id,
+        name,
+        priority,
+        state: State::Blocked,
+        sp,
+        _stack: Some(stack),
+        entry: Some(Box::new(f)),
+    });
+    sched.make_ready(id);
+    drop(guard);
+    // Straight to it, if it's more important.
+    preempt();
+    Ok(id)
+}
+
+/// The running thread; None until `init`.
+pub fn current() -> Option<ThreadId> {
+    SCHED.lock().as_ref().map(|s| s.current)
+}
+
+/// The name `spawn` gave the running thread.
+pub fn current_name() -> Option<&'static str> {
+    SCHED.lock().as_mut().map(|s| s.thread_mut(s.current).name)
+}
+
+/// Switches to the next thread, if there's any other to switch to.
+fn schedule() {
+    let irq = sync::irq_save();
+    let switch = SCHED.lock().as_mut().and_then(Scheduler::switch);
+    if let Some((old_sp, new_sp)) = switch {
+        // SAFETY: `new_sp` is where the next thread's `switch_context` (or `init_thread_stack`) left it.  `old_sp`
+        // points into the scheduler's threads, which nothing can touch before it's written: interrupts are off, and
+        // only the BSP schedules.
+        unsafe { arch::switch_context(old_sp, new_sp) };
+    }
+    sync::irq_restore(irq);
+}
+
+/// Takes the running thread off the CPU until something makes it ready again.
+fn block(state: State) {
+    let irq = sync::irq_save();
+    if let Some(sched) = SCHED.lock().as_mut() {
+        let current = sched.current;
+        sched.thread_mut(current).state = state;
+    }
+    schedule();
+    sync::irq_restore(irq);
+}
+
+/// Lets the other threads of the running one's priority (or higher) have a turn.  Only the self-tests give way yet.
+#[cfg(feature = "selftest")]
+pub fn yield_now() {
+    schedule();
+}
+
+/// Takes the running thread off the CPU until the first tick at or after `deadline` (in `time::now` terms).  Returns
+/// false, straight away, if there are no threads yet.
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+pub fn sleep_until(deadline: Duration) -> bool {
+    if current().is_none() {
+        return false;
+    }
+    block(State::Sleeping(deadline));
+    true
+}
+
+/// Ends the running thread.  Its stack is freed later, by the idle thread.
+pub fn exit() -> ! {
+    // Never turned back on: this thread is done.
+    sync::irq_save();
+    if let Some(sched) = SCHED.lock().as_mut() {
+        let current = sched.current;
+        if current != sched.idle {
+            sched.thread_mut(current).state = State::Exited;
+        }
+    }
+    schedule();
+    unreachable!("an exited thread ran again");
+}
+
+/// Makes `id` ready, if it's blocked.
+fn wake(id: ThreadId) {
+    if let Some(sched) = SCHED.lock().as_mut()
+        && sched
+            .threads
+            .iter()
+            .any(|t| t.id == id && t.state == State::Blocked)
+    {
+        sched.make_ready(id);
+    }
+}
+
+/// Wakes sleepers that are due at `now`.  For `time::tick`.
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+pub fn wake_sleepers(now: Duration) {
+    let mut sched = SCHED.lock();
+    let Some(sched) = sched.as_mut() else {
+        return;
+    };
+
+    for i in 0..sched.threads.len() {
+        if matches!(sched.threads[i].state, State::Sleeping(deadline) if deadline <= now) {
+            let id = sched.threads[i].id;
+            sched.make_ready(id);
+        }
+    }
+}
+
+/// Ends the running thread's slice once it's used up.  For `time::tick`.
+pub fn tick() {
+    let mut sched = SCHED.lock();
+    let Some(sched) = sched.as_mut() else {
+        return;
+    };
+
+    if sched.current != sched.idle {
+        sched.slice = sched.slice.saturating_sub(1);
+        if sched.slice == 0 {
+            sched.need_resched = true;
+        }
+    }
+}
+
+/// Switches threads if the tick ended a slice or an interrupt woke something more important.  For the end of
+/// interrupt handlers, once the interrupt is acknowledged.
+pub fn preempt() {
+    if SCHED.lock().as_ref().is_some_and(|s| s.need_resched) {
+        schedule();
+    }
+}
+
+/// Frees the threads that exited.
+fn reap() {
+    let dead = SCHED.lock().as_mut().map(|s| core::mem::take(&mut s.dead));
+    // Outside the lock: freeing a stack unmaps it, which can wait on other CPUs.
+    drop(dead);
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = SCHED.lock().as_mut().and_then(|s| {
+        let current = s.current;
+        s.thread_mut(current).entry.take()
+    });
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+extern "C" fn idle_loop() -> ! {
+    loop {
+        reap();
+        crate::halt();
+    }
+}
+
+/// Threads waiting for something.  Whoever makes it happen wakes them; `wait_until` checks the condition again after
+/// every wakeup, so waking too many is harmless.
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<ThreadId>>,
+}
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the running thread until `condition` holds.
+    pub fn wait_until(&self, condition: impl Fn() -> bool) {
+        loop {
+            // Checking and queueing with interrupts off, so an interrupt handler can't wake the queue in between.
+            let irq = sync::irq_save();
+            if condition() {
+                sync::irq_restore(irq);
+                return;
+            }
+            match current() {
+                Some(id) => {
+                    self.waiters.lock().push_back(id);
+                    block(State::Blocked);
+                }
+                // No threads yet; only an interrupt can change anything.
+                None if irq => {
+                    sync::irq_restore(irq);
+                    crate::halt();
+                    continue;
+                }
+                None => core::hint::spin_loop(),
+            }
+            sync::irq_restore(irq);
+        }
+    }
+
+    /// Wakes the longest-waiting thread.  Returns whether there was one.
+    #[cfg(feature = "selftest")]
+    pub fn wake_one(&self) -> bool {
+        let id = self.waiters.lock().pop_front();
+        id.map(wake).is_some()
+    }
+
+    /// Wakes every waiting thread.  Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        let count = waiters.len();
+        waiters.into_iter().for_each(wake);
+        count
+    }
+}
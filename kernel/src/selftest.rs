@@ -0,0 +1,462 @@
+//! **HyperText Markup Self-Test**
+//!
+//! Checks, at the end of boot, that what was brought up works.  A failing check panics.
+//!
+//! Only built with the `selftest` feature, which is off by default: `build-scripts/*.sh --features selftest`.
+
+use crate::{
+    HTMAS, boot_info::boot_info, efi_rt, executor, frame_alloc, kiss, memmap, println, sched, sync,
+    time,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::{vmm, x86_64_stuff};
+use core::arch::global_asm;
+use r_efi::efi;
+use widestring::u16cstr;
+
+/// Runs every test, on the boot thread, once everything they test is up.
+pub fn run() {
+    memmap_test();
+    alloc_test();
+    alloc_align_test();
+    alloc_trace_test();
+    frame_alloc_test();
+    #[cfg(target_arch = "x86_64")]
+    {
+        stack_guard_test();
+        exception_hook_test();
+    }
+    user_mode_test();
+    timer_test();
+    #[cfg(target_arch = "x86_64")]
+    pci_test();
+    efi_rt_test();
+    sched_test();
+    executor_test();
+}
+
+fn memmap_test() {
+    let regions = memmap::regions();
+    // Sorted, with nothing empty and no byte in two regions.
+    assert!(regions.iter().all(|r| r.size != 0));
+    assert!(regions.windows(2).all(|w| w[0].end() <= w[1].start));
+    memmap::dump(&mut kiss::Console).unwrap();
+
+    println!("memory map test passed");
+}
+
+fn alloc_test() {
+    let mut v = alloc::vec::Vec::<u32>::new();
+    assert!(v.len() == 0 && v.capacity() == 0);
+
+    v.push(0xAA55AA55);
+    assert!(v[0] == 0xAA55AA55);
+
+    v.push(0x12345678);
+    assert!(v[0] == 0xAA55AA55);
+    assert!(v[1] == 0x12345678);
+
+    v.push(0x2468ABCD);
+    assert!(v[0] == 0xAA55AA55);
+    assert!(v[1] == 0x12345678);
+    assert!(v[2] == 0x2468ABCD);
+
+    v.remove(0);
+    assert!(v[0] == 0x12345678);
+    assert!(v[1] == 0x2468ABCD);
+
+    v.reverse();
+    assert!(v[0] == 0x2468ABCD);
+    assert!(v[1] == 0x12345678);
+
+    for _ in 0..100 {
+        v.push(1);
+    }
+    assert!(v.len() == 102);
+
+    v.remove(0);
+    v.remove(0);
+    for i in 0..100 {
+        assert!(v[i] == 1);
+    }
+
+    println!("vec test passed");
+}
+
+fn alloc_align_test() {
+    use alloc::{alloc::Layout, boxed::Box};
+    use core::alloc::GlobalAlloc;
+
+    let is_aligned =
+        |ptr: *mut u8, align: usize| !ptr.is_null() && (ptr as usize).is_multiple_of(align);
+
+    let in_use = HTMAS.stats().in_use;
+
+    // Every slab class and the page fallback, for every alignment up to 16 KiB.
+    for size_shift in 0..=14 {
+        for align_shift in 0..=14 {
+            let (size, align) = (1usize << size_shift, 1usize << align_shift);
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let ptr = HTMAS.alloc(layout);
+                assert!(is_aligned(ptr, align), "alloc {size}/{align} -> {ptr:p}");
+                ptr.write_bytes(0xA5, size);
+
+                let zptr = HTMAS.alloc_zeroed(layout);
+                assert!(
+                    is_aligned(zptr, align),
+                    "alloc_zeroed {size}/{align} -> {zptr:p}"
+                );
+                assert!((0..size).all(|i| zptr.add(i).read() == 0));
+
+                // Grow across slab classes and into pages, then shrink back down.
+                let mut rptr = ptr;
+                let mut rlayout = layout;
+                for new_size in [size * 3, size * 40, 3 * 4096 + 1, size.max(2) / 2] {
+                    rptr = HTMAS.realloc(rptr, rlayout, new_size);
+                    assert!(
+                        is_aligned(rptr, align),
+                        "realloc {size}/{align} to {new_size} -> {rptr:p}"
+                    );
+                    let kept = rlayout.size().min(new_size);
+                    assert!((0..kept.min(size)).all(|i| rptr.add(i).read() == 0xA5));
+                    rlayout = Layout::from_size_align(new_size, align).unwrap();
+                }
+
+                HTMAS.dealloc(rptr, rlayout);
+                HTMAS.dealloc(zptr, layout);
+            }
+        }
+    }
+
+    assert!(
+        HTMAS.stats().in_use == in_use,
+        "alloc alignment test leaked"
+    );
+
+    #[repr(align(4096))]
+    struct PageAligned([u8; 4096]);
+    #[repr(align(64))]
+    struct CacheAligned(u8);
+
+    let page = Box::new(PageAligned([0; 4096]));
+    assert!((&*page as *const _ as usize).is_multiple_of(4096) && page.0[4095] == 0);
+    let lines = alloc::vec![CacheAligned(0), CacheAligned(1), CacheAligned(2)];
+    assert!(
+        lines
+            .iter()
+            .all(|l| (l as *const _ as usize).is_multiple_of(64) && l.0 < 3)
+    );
+
+    println!("alloc alignment test passed");
+}
+
+fn alloc_trace_test() {
+    use alloc::boxed::Box;
+
+    HTMAS.set_tracing(true);
+    let before = HTMAS.stats();
+    let a = Box::new(0u64);
+    let b = alloc::vec![0u8; 3 * frame_alloc::FRAME_SIZE];
+    let after = HTMAS.stats();
+    assert!(after.live() == before.live() + 2);
+    assert!(after.in_use == before.in_use + 8 + 3 * frame_alloc::FRAME_SIZE);
+    drop(a);
+    HTMAS.dump_live(&mut kiss::Console).unwrap();
+    drop(b);
+    HTMAS.dump_trace(&mut kiss::Console).unwrap();
+    HTMAS.set_tracing(false);
+    assert!(HTMAS.stats().in_use == before.in_use);
+
+    println!("{}", HTMAS.stats());
+    println!("alloc trace test passed");
+}
+
+fn frame_alloc_test() {
+    use frame_alloc::{FRAME_SIZE, HUGE_FRAME_SIZE};
+
+    // Freeing a frame makes it the next one handed out, unless it's been reserved since.
+    let frame = frame_alloc::alloc_frame().unwrap();
+    frame_alloc::free_frame(frame);
+    frame_alloc::FRAMES.lock().reserve(frame, FRAME_SIZE);
+    let other = frame_alloc::alloc_frame().unwrap();
+    assert!(other != frame);
+    frame_alloc::free_frame(other);
+    frame_alloc::free_frame(frame);
+
+    // Not every machine has 2 MiB free in one piece.
+    if let Some(huge) = frame_alloc::alloc_huge_frame() {
+        assert!(huge.is_multiple_of(HUGE_FRAME_SIZE));
+        frame_alloc::free_frames(huge, HUGE_FRAME_SIZE / FRAME_SIZE);
+    }
+
+    println!("frame allocator test passed");
+}
+
+#[cfg(target_arch = "x86_64")]
+fn stack_guard_test() {
+    let stack = vmm::KernelStack::new(vmm::DEFAULT_STACK_SIZE).unwrap();
+    let guard = stack.top() - stack.size() - 1;
+    assert!(vmm::translate(stack.top() - 1).is_some());
+    assert!(vmm::translate(guard).is_none() && vmm::is_guard_page(guard));
+    drop(stack);
+    assert!(!vmm::is_guard_page(guard));
+
+    println!("stack guard test passed");
+}
+
+#[cfg(target_arch = "x86_64")]
+fn exception_hook_test() {
+    // Steps over the `ud2` below, and nothing else.
+    fn skip_ud2(frame: &mut x86_64_stuff::TrapFrame) -> bool {
+        if frame.vector == 6 && unsafe { (frame.rip as *const [u8; 2]).read() } == [0x0F, 0x0B] {
+            frame.rip += 2;
+            true
+        } else {
+            false
+        }
+    }
+
+    let hook: x86_64_stuff::RecoveryHook = skip_ud2;
+    x86_64_stuff::register_recovery_hook(hook).unwrap();
+    unsafe { core::arch::asm!("ud2") };
+    x86_64_stuff::unregister_recovery_hook(hook);
+
+    println!("exception hook test passed");
+}
+
+// A program that says hello, then exits with what it got writing from a pointer that isn't its own.
+#[cfg(target_arch = "x86_64")]
+global_asm!(
+    r#"
+.section .rodata.user_test, "a"
+.global user_test_start
+.global user_test_end
+user_test_start:
+    mov eax, 0
+    lea rdi, [rip + user_test_message]
+    mov esi, USER_TEST_MESSAGE_LEN
+    syscall
+    mov eax, 0
+    xor edi, edi
+    mov esi, 1
+    syscall
+    mov rdi, rax
+    mov eax, 3
+    syscall
+    ud2
+user_test_message:
+    .ascii "USER: hello from ring 3\n"
+user_test_end:
+.set USER_TEST_MESSAGE_LEN, user_test_end - user_test_message
+"#
+);
+#[cfg(target_arch = "x86")]
+global_asm!(
+    r#"
+.section .rodata.user_test, "a"
+.global user_test_start
+.global user_test_end
+user_test_start:
+    call user_test_here
+user_test_here:
+    pop ebx
+    add ebx, USER_TEST_MESSAGE_OFFSET
+    mov eax, 0
+    mov ecx, USER_TEST_MESSAGE_LEN
+    int 0x80
+    mov eax, 0
+    xor ebx, ebx
+    mov ecx, 1
+    int 0x80
+    mov ebx, eax
+    mov eax, 3
+    int 0x80
+    ud2
+user_test_message:
+    .ascii "USER: hello from ring 3\n"
+user_test_end:
+.set USER_TEST_MESSAGE_OFFSET, user_test_message - user_test_here
+.set USER_TEST_MESSAGE_LEN, user_test_end - user_test_message
+"#
+);
+
+fn user_mode_test() {
+    use crate::api::htmos::{self, Error, Exit};
+
+    unsafe extern "C" {
+        static user_test_start: u8;
+        static user_test_end: u8;
+    }
+    let image = unsafe {
+        let start = &raw const user_test_start;
+        core::slice::from_raw_parts(
+            start,
+            (&raw const user_test_end).offset_from(start) as usize,
+        )
+    };
+    assert!(htmos::run(image) == Ok(Exit::Status(Error::BadPointer as isize)));
+    // ud2
+    assert!(matches!(
+        htmos::run(&[0x0F, 0x0B]),
+        Ok(Exit::Fault { vector: 6, .. })
+    ));
+
+    println!("user mode test passed");
+}
+
+fn efi_rt_test() {
+    // Made up for this test.
+    const GUID: efi::Guid = efi::Guid::from_fields(
+        0x6874_6D6C,
+        0x7365,
+        0x6C66,
+        0x74,
+        0x65,
+        &[0x73, 0x74, 0x76, 0x61, 0x72, 0x73],
+    );
+    // Volatile, so nothing is left behind in flash.
+    const ATTRIBUTES: u32 = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+
+    if boot_info().boot_mode != 1 {
+        return;
+    }
+    let name = u16cstr!("HtmSelfTest");
+    // Not all firmware lets variables be made after boot services are gone.
+    if let Err(e) = efi_rt::set_variable(name, &GUID, ATTRIBUTES, b"htm") {
+        println!("firmware variable test skipped: {e:?}");
+        return;
+    }
+    let mut buf = [0u8; 8];
+    assert!(efi_rt::get_variable(name, &GUID, &mut buf) == Ok((3, ATTRIBUTES)));
+    assert!(buf[..3] == *b"htm");
+    efi_rt::set_variable(name, &GUID, ATTRIBUTES, &[]).unwrap();
+    assert!(efi_rt::get_variable(name, &GUID, &mut buf) == Err(efi::Status::NOT_FOUND));
+
+    println!("firmware variable test passed");
+}
+
+fn sched_test() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    static DONE: sched::WaitQueue = sched::WaitQueue::new();
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    static ORDER: sync::SpinLock<alloc::vec::Vec<usize>> =
+        sync::SpinLock::new(alloc::vec::Vec::new());
+
+    let start = time::now();
+    for i in 0..4 {
+        let priority = if i == 0 {
+            sched::Priority::High
+        } else {
+            sched::Priority::Normal
+        };
+        sched::spawn("test", priority, move || {
+            ORDER.lock().push(i);
+            // The others get in before this one sleeps, and still in the order they were spawned.
+            sched::yield_now();
+            time::sleep(Duration::from_millis(5));
+            FINISHED.fetch_add(1, Ordering::Relaxed);
+            // Only the boot thread waits.
+            DONE.wake_one();
+        })
+        .unwrap();
+    }
+    DONE.wait_until(|| FINISHED.load(Ordering::Relaxed) == 4);
+    // The high-priority thread ran the moment it was spawned; the rest in turn.
+    assert!(*ORDER.lock() == [0, 1, 2, 3]);
+    assert!(time::now() - start >= Duration::from_millis(5));
+
+    println!("scheduler test passed");
+}
+
+fn executor_test() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+    use executor::Stream;
+
+    static DONE: sched::WaitQueue = sched::WaitQueue::new();
+    static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Countdown(usize);
+    impl Stream for Countdown {
+        type Item = usize;
+
+        fn poll_next(
+            mut self: core::pin::Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<Option<usize>> {
+            // Pending every other time, so the task has to be woken to get on.
+            if self.0 % 2 == 1 {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                return core::task::Poll::Pending;
+            }
+            core::task::Poll::Ready(self.0.checked_sub(2).inspect(|&n| self.0 = n))
+        }
+    }
+
+    let start = time::now();
+    executor::spawn(async {
+        let mut countdown = Countdown(7);
+        while countdown.next().await.is_some() {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+        }
+        executor::sleep(Duration::from_millis(5)).await;
+        STEPS.fetch_add(1, Ordering::Relaxed);
+        DONE.wake_all();
+    });
+    DONE.wait_until(|| STEPS.load(Ordering::Relaxed) == 4);
+    assert!(time::now() - start >= Duration::from_millis(5));
+
+    println!("executor test passed");
+}
+
+fn timer_test() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn count() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let (start, ticks) = (time::now(), time::ticks());
+    time::add_timer(Duration::from_millis(5), None, count);
+    let periodic = time::add_timer(
+        Duration::from_millis(2),
+        Some(Duration::from_millis(2)),
+        count,
+    );
+    time::sleep(Duration::from_millis(20));
+    assert!(time::cancel_timer(periodic));
+    assert!(time::now() - start >= Duration::from_millis(20));
+    assert!(time::ticks() > ticks);
+    assert!(FIRED.load(Ordering::Relaxed) >= 2);
+
+    println!("timer test passed");
+}
+
+#[cfg(target_arch = "x86_64")]
+fn pci_test() {
+    use crate::pci;
+
+    // Every PC has a host bridge, and lookups by ID find what lookups by class do.
+    let bridge = pci::find_by_class(0x06, Some(0x00)).next().unwrap();
+    assert!(
+        pci::find_by_id(bridge.vendor_id, bridge.device_id).any(|d| d.address == bridge.address)
+    );
+    for dev in pci::devices() {
+        for bar in dev.bars.iter().flatten() {
+            assert!(
+                bar.size().is_power_of_two(),
+                "{} has a BAR of 0x{:X} bytes",
+                dev.address,
+                bar.size()
+            );
+        }
+    }
+
+    println!("pci test passed");
+}
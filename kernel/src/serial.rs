@@ -0,0 +1,194 @@
+//! **HyperText Markup Serial**
+//!
+//! Driver for the 16550 UART on COM1 or COM2, as a second console: `init` sets the port up and makes it the serial
+//! console, which `kiss` then copies everything it prints to (`write_str`).  Typing on the other end comes back out of
+//! `read_byte`; with `enable_rx_interrupt`, the interrupt handler (`interrupt`) queues it as it arrives, and until then
+//! `read_byte` polls.
+//!
+//! The FIFOs are used if the UART has them (a 16550A or later); the original 8250 and 16450 work a byte at a time.
+
+use crate::{
+    port_io::{inb, outb},
+    sync::{ByteRing, SpinLock},
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Once;
+
+/// The UART's clock divided by 16; the fastest baud rate there is.
+const BASE_BAUD: u32 = 115_200;
+
+// Register offsets.  With DLAB set, 0 and 1 are the divisor instead.
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const FCR: u16 = 2;
+const IIR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+
+const IER_RX: u8 = 1 << 0;
+/// Enable and clear both FIFOs, interrupt at 14 bytes.
+const FCR_ENABLE: u8 = 0xC7;
+const IIR_FIFO: u8 = 0xC0;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+/// DTR, RTS, and OUT2 (which gates the interrupt line).
+const MCR_NORMAL: u8 = 0x0B;
+const MCR_LOOPBACK: u8 = 0x1E;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// How long to wait for room to send, in status polls.  Past that, output is dropped rather than hanging the kernel.
+const TIMEOUT: usize = 100_000;
+
+/// The legacy serial ports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+}
+impl ComPort {
+    const fn base(self) -> u16 {
+        match self {
+            Self::Com1 => 0x3F8,
+            Self::Com2 => 0x2F8,
+        }
+    }
+
+    /// The ISA IRQ the port interrupts on.
+    pub const fn irq(self) -> u8 {
+        match self {
+            Self::Com1 => 4,
+            Self::Com2 => 3,
+        }
+    }
+}
+
+pub struct Uart {
+    pub port: ComPort,
+    pub baud: u32,
+    /// How many bytes can be sent at once: 16 with FIFOs, 1 without.
+    pub fifo: usize,
+}
+impl Uart {
+    /// Sets `port` up for `baud` baud, 8 data bits, no parity, 1 stop bit, after checking there's a UART there.
+    pub fn new(port: ComPort, baud: u32) -> Result<Self, &'static str> {
+        if !BASE_BAUD.is_multiple_of(baud) {
+            return Err("baud rate doesn't divide 115200");
+        }
+        let divisor = (BASE_BAUD / baud) as u16;
+        let base = port.base();
+
+        outb(base + IER, 0);
+        outb(base + LCR, LCR_DLAB);
+        outb(base + DATA, divisor as u8);
+        outb(base + IER, (divisor >> 8) as u8);
+        outb(base + LCR, LCR_8N1);
+        outb(base + FCR, FCR_ENABLE);
+
+        // In loopback mode, what's sent comes straight back; no UART, or a broken one, won't manage that.
+        outb(base + MCR, MCR_LOOPBACK);
+        outb(base + DATA, 0xAE);
+        if inb(base + DATA) != 0xAE {
+            return Err("no UART on this port");
+        }
+        outb(base + MCR, MCR_NORMAL);
+
+        let fifo = if inb(base + IIR) & IIR_FIFO == IIR_FIFO {
+            16
+        } else {
+            1
+        };
+        Ok(Self { port, baud, fifo })
+    }
+
+    pub fn write(&self, bytes: &[u8]) {
+        let base = self.port.base();
+        for chunk in bytes.chunks(self.fifo) {
+            // An empty holding register means an empty FIFO, so a whole chunk fits.
+            if !(0..TIMEOUT).any(|_| inb(base + LSR) & LSR_THR_EMPTY != 0) {
+                return;
+            }
+            for &byte in chunk {
+                outb(base + DATA, byte);
+            }
+        }
+    }
+
+    fn try_read(&self) -> Option<u8> {
+        let base = self.port.base();
+        (inb(base + LSR) & LSR_DATA_READY != 0).then(|| inb(base + DATA))
+    }
+}
+impl fmt::Display for Uart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {} baud, {}",
+            self.port,
+            self.baud,
+            if self.fifo > 1 { "16550A" } else { "no FIFO" }
+        )
+    }
+}
+
+static CONSOLE: Once<Uart> = Once::new();
+/// What the interrupt handler received and nobody read yet.
+static RECEIVED: ByteRing<256> = ByteRing::new();
+/// Whether the interrupt handler is filling `RECEIVED`; if not, `read_byte` polls the UART itself.
+static RX_INTERRUPT: AtomicBool = AtomicBool::new(false);
+/// Makes sure only one reader pops from `RECEIVED` at a time.
+static READER: SpinLock<()> = SpinLock::new(());
+
+/// Sets up the serial console on `port`.
+pub fn init(port: ComPort, baud: u32) -> Result<&'static Uart, &'static str> {
+    if CONSOLE.is_completed() {
+        return Err("serial console already initialized");
+    }
+    let uart = Uart::new(port, baud)?;
+    Ok(CONSOLE.call_once(|| uart))
+}
+
+pub fn console() -> Option<&'static Uart> {
+    CONSOLE.get()
+}
+
+/// Sends `s` to the serial console, if there is one.
+pub fn write_str(s: &str) {
+    if let Some(uart) = CONSOLE.get() {
+        uart.write(s.as_bytes());
+    }
+}
+
+/// Has the serial console interrupt when something arrives.  Routing the interrupt (`ComPort::irq`) to something
+/// calling `interrupt` is up to the caller.
+pub fn enable_rx_interrupt() {
+    if let Some(uart) = CONSOLE.get() {
+        RX_INTERRUPT.store(true, Ordering::Release);
+        outb(uart.port.base() + IER, IER_RX);
+    }
+}
+
+/// Queues whatever the serial console received.  For its interrupt handler.
+pub fn interrupt() {
+    if let Some(uart) = CONSOLE.get() {
+        // With FIFOs, one interrupt can mean many bytes.
+        while let Some(byte) = uart.try_read() {
+            RECEIVED.push(byte);
+        }
+    }
+}
+
+/// The oldest byte received on the serial console and not yet read.
+pub fn read_byte() -> Option<u8> {
+    let uart = CONSOLE.get()?;
+    let _reader = READER.lock();
+    if RX_INTERRUPT.load(Ordering::Acquire) {
+        RECEIVED.pop()
+    } else {
+        uart.try_read()
+    }
+}
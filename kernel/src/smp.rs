@@ -0,0 +1,326 @@
+//! **HyperText Markup SMP**
+//!
+//! Brings up the application processors (APs): every CPU the MADT lists as enabled, besides the bootstrap processor
+//! (BSP) running `init`.  Each AP is started with INIT-SIPI-SIPI at a real-mode trampoline (see
+//! `asm_entry_stub/x86_64_ap_trampoline.s`), one at a time, and gets its own stack, GDT, TSS and double fault stack.
+//! Once up, it enables its local APIC and idles until an IPI needs it.
+//!
+//! Every CPU finds its `PerCpu` through the GS base.  CPUs are numbered in the order they came up, the BSP being 0.
+
+use crate::{
+    apic::{self, Ipi},
+    frame_alloc, println,
+    sync::SpinLock,
+    time,
+    vmm::{self, DEFAULT_STACK_SIZE, KernelStack, PAGE_SIZE, PageTableFlags},
+};
+use alloc::boxed::Box;
+use core::{
+    arch::global_asm,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    VirtAddr,
+    instructions::tlb,
+    registers::{
+        control::{Cr3, Cr4},
+        model_specific::GsBase,
+    },
+};
+
+/// How many CPUs the kernel can run on.
+pub const MAX_CPUS: usize = 64;
+/// Above this many pages, a TLB shootdown flushes everything instead of page by page.
+const SHOOTDOWN_MAX_PAGES: usize = 32;
+
+global_asm!(include_str!("./asm_entry_stub/x86_64_ap_trampoline.s"));
+
+unsafe extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// What the trampoline reads at `ap_trampoline_data`.
+#[repr(C)]
+struct TrampolineData {
+    pml4: u64,
+    stack: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// Everything that belongs to one CPU.
+pub struct PerCpu {
+    pub index: usize,
+    pub apic_id: u32,
+    /// Stack the CPU runs on and the one it takes double faults on.  The BSP has static ones from the linker instead.
+    stacks: Option<(KernelStack, KernelStack)>,
+}
+
+/// Every CPU that's up, by index (null for an index never given out, or given to a CPU that didn't start).
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// The BSP's CR4, so every AP runs with the same features.
+static BSP_CR4: AtomicU64 = AtomicU64::new(0);
+/// Vector of the TLB shootdown IPI.
+static SHOOTDOWN_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// The CPU running this (None before `init`).
+pub fn current() -> Option<&'static PerCpu> {
+    // SAFETY: the GS base is only ever set to a leaked `PerCpu`.
+    unsafe { (GsBase::read().as_u64() as *const PerCpu).as_ref() }
+}
+
+/// Index of the CPU running this (0 before `init`).
+pub fn cpu_index() -> usize {
+    current().map_or(0, |c| c.index)
+}
+
+/// How many CPUs are up (0 before `init`).
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Every CPU that's up.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter().filter_map(|c| {
+        // SAFETY: only ever set to a leaked `PerCpu`.
+        unsafe { c.load(Ordering::Acquire).as_ref() }
+    })
+}
+
+/// Marks `cpu` as the one running this, and as up.
+fn register(cpu: &'static PerCpu) {
+    GsBase::write(VirtAddr::new(cpu as *const PerCpu as u64));
+    CPUS[cpu.index].store(cpu as *const PerCpu as *mut PerCpu, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Sets up the BSP's per-CPU data and starts every other enabled CPU, with TLB shootdowns on `shootdown_vector`.
+/// Returns how many CPUs are up.
+///
+/// Needs the interrupt controllers (`apic::init`) and the clock (`time::init`), which times the startup sequence.
+pub fn init(shootdown_vector: u8) -> Result<usize, &'static str> {
+    if cpu_count() != 0 {
+        return Err("SMP already initialized");
+    }
+    let model = apic::model().ok_or("interrupt controllers not set up")?;
+    SHOOTDOWN_VECTOR.store(shootdown_vector, Ordering::Relaxed);
+    BSP_CR4.store(Cr4::read_raw(), Ordering::Relaxed);
+
+    let bsp_id = apic::lapic_id();
+    register(Box::leak(Box::new(PerCpu {
+        index: 0,
+        apic_id: bsp_id,
+        stacks: None,
+    })));
+
+    // Online-capable CPUs are for hot-plugging, and are left alone.  In xAPIC mode, IDs past 254 can't be reached.
+    let reachable = |id: u32| id < 0xFF || model.lapic == apic::LocalApic::X2Apic;
+    let aps = model
+        .cpus
+        .iter()
+        .filter(|c| c.enabled && c.apic_id != bsp_id && reachable(c.apic_id))
+        .map(|c| c.apic_id);
+    if aps.clone().next().is_none() {
+        return Ok(1);
+    }
+
+    // The startup IPI can only point at a page below 1 MiB; the frame allocator keeps one aside for this.
+    // SAFETY: given from the assembly stub.
+    let (start, data, end) = unsafe {
+        (
+            &ap_trampoline as *const u8 as usize,
+            &ap_trampoline_data as *const u8 as usize,
+            &ap_trampoline_end as *const u8 as usize,
+        )
+    };
+    if end - start > PAGE_SIZE {
+        return Err("the AP trampoline doesn't fit in a page");
+    }
+    let page =
+        frame_alloc::alloc_low_frame().ok_or("no free page below 1 MiB for the AP trampoline")?;
+    let pml4 = Cr3::read().0.start_address().as_u64();
+    if pml4 > u32::MAX as u64 {
+        frame_alloc::free_frame(page);
+        return Err("kernel PML4 is above 4 GiB, out of the AP trampoline's reach");
+    }
+    // SAFETY: the frame is ours, and big enough.
+    unsafe {
+        ptr::copy_nonoverlapping(
+            start as *const u8,
+            vmm::phys_to_virt(page) as *mut u8,
+            end - start,
+        );
+    }
+    // Turning paging on mustn't pull the trampoline out from under the AP.
+    vmm::map(page, page, PAGE_SIZE, PageTableFlags::WRITABLE)?;
+
+    let data = (vmm::phys_to_virt(page) + data - start) as *mut TrampolineData;
+    // Every AP gets an index of its own, whether it starts or not: one that timed out may still come up late.
+    for (index, apic_id) in (1..).zip(aps) {
+        if index == MAX_CPUS {
+            println!("SMP: more than {MAX_CPUS} CPUs, ignoring the rest");
+            break;
+        }
+        if let Err(e) = start_ap(apic_id, index, page, pml4, data) {
+            println!("SMP: CPU with APIC ID {apic_id} didn't start: {e}");
+        }
+    }
+
+    vmm::unmap(page, PAGE_SIZE)?;
+    frame_alloc::free_frame(page);
+    Ok(cpu_count())
+}
+
+/// Starts one AP as CPU `index` through the trampoline at (physical) `page`, and waits for it to come up.
+fn start_ap(
+    apic_id: u32,
+    index: usize,
+    page: usize,
+    pml4: u64,
+    data: *mut TrampolineData,
+) -> Result<(), &'static str> {
+    let stack = KernelStack::new(DEFAULT_STACK_SIZE)?;
+    let stack_top = stack.top();
+    let cpu: &'static PerCpu = Box::leak(Box::new(PerCpu {
+        index,
+        apic_id,
+        stacks: Some((stack, KernelStack::new(DEFAULT_STACK_SIZE)?)),
+    }));
+    // SAFETY: the trampoline isn't running; the last AP is up and done with it.
+    unsafe {
+        data.write_volatile(TrampolineData {
+            pml4,
+            stack: stack_top as u64,
+            entry: ap_entry as *const () as u64,
+            arg: cpu as *const PerCpu as u64,
+        });
+    }
+
+    let up = || !CPUS[index].load(Ordering::Acquire).is_null();
+    apic::send_ipi(apic_id, Ipi::Init);
+    time::sleep(Duration::from_millis(10));
+    // The second SIPI is only for CPUs that missed the first.
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, Ipi::Startup((page >> 12) as u8));
+        let deadline = time::now() + Duration::from_millis(1);
+        while !up() && time::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if up() {
+            return Ok(());
+        }
+    }
+    let deadline = time::now() + Duration::from_millis(100);
+    while time::now() < deadline {
+        if up() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    // Its stacks stay leaked; it may still wake up and use them.
+    Err("no response to startup IPIs")
+}
+
+/// Where the trampoline drops an AP, on its own stack.
+extern "sysv64" fn ap_entry(cpu: &'static PerCpu) -> ! {
+    // SAFETY: the same features the BSP runs with, on the same kind of CPU.
+    unsafe { Cr4::write_raw(BSP_CR4.load(Ordering::Relaxed)) };
+    let double_fault_stack = cpu.stacks.as_ref().map_or(0, |(_, df)| df.top());
+    crate::x86_64_stuff::init_ap(double_fault_stack);
+    register(cpu);
+
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Sends interrupt `vector` to CPU `index`.
+pub fn send_ipi(index: usize, vector: u8) -> Result<(), &'static str> {
+    // SAFETY: only ever set to a leaked `PerCpu`.
+    let cpu = CPUS
+        .get(index)
+        .and_then(|c| unsafe { c.load(Ordering::Acquire).as_ref() })
+        .ok_or("no such CPU")?;
+    apic::send_ipi(cpu.apic_id, Ipi::Fixed(vector));
+    Ok(())
+}
+
+/// Serializes shootdowns; the range below is shared.
+static SHOOTDOWN: SpinLock<()> = SpinLock::new(());
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// CPUs (one bit per index) that have yet to flush the range.
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Flushes the shootdown range on this CPU, if it was asked to.
+fn shootdown_requested() {
+    let bit = 1 << cpu_index();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    let start = SHOOTDOWN_START.load(Ordering::Relaxed);
+    let size = SHOOTDOWN_SIZE.load(Ordering::Relaxed);
+    if size.div_ceil(PAGE_SIZE) > SHOOTDOWN_MAX_PAGES {
+        tlb::flush_all();
+    } else {
+        for virt in (start..start + size).step_by(PAGE_SIZE) {
+            tlb::flush(VirtAddr::new(virt as u64));
+        }
+    }
+    SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::AcqRel);
+}
+
+/// Makes every other CPU drop what its TLB holds for `size` bytes at `virt`, and waits until they have.  The caller
+/// flushes its own.
+pub fn tlb_shootdown(virt: usize, size: usize) {
+    let others = cpus().fold(0, |mask, c| mask | 1 << c.index) & !(1 << cpu_index());
+    if others == 0 {
+        return;
+    }
+
+    // While waiting, handle anybody else's shootdown; they may be waiting on us with interrupts off.
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        shootdown_requested();
+        core::hint::spin_loop();
+    };
+    SHOOTDOWN_START.store(virt & !(PAGE_SIZE - 1), Ordering::Relaxed);
+    SHOOTDOWN_SIZE.store(virt % PAGE_SIZE + size, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.store(others, Ordering::Release);
+    // Only the CPUs in `others`; one still starting up has nothing cached and no handler yet.
+    let vector = SHOOTDOWN_VECTOR.load(Ordering::Relaxed);
+    for cpu in cpus().filter(|c| others & 1 << c.index != 0) {
+        send_ipi(cpu.index, vector).expect("registered CPUs stay registered");
+    }
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Handles the TLB shootdown IPI.
+pub fn tlb_shootdown_ipi() {
+    shootdown_requested();
+}
+
+/// Set once `halt_others` has gone out.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Stops every other CPU for good, with an NMI so not even a CPU with interrupts off misses it.  For the panic path.
+pub fn halt_others() {
+    if cpu_count() > 1 && !HALTING.swap(true, Ordering::AcqRel) {
+        apic::broadcast_ipi(Ipi::Nmi);
+    }
+}
+
+/// Whether an NMI means `halt_others` wants this CPU to stop.
+pub fn halting() -> bool {
+    HALTING.load(Ordering::Acquire)
+}
@@ -0,0 +1,230 @@
+//! **HyperText Markup Synchronization**
+//!
+//! Locks for anything an interrupt handler can touch.  Both kinds turn interrupts off while held (and put them back
+//! the way they were afterwards), so a handler can never spin on a lock the code it interrupted is holding.
+//!
+//! - `SpinLock` - test-and-set, for short critical sections with little contention.
+//! - `TicketLock` - first come, first served, so no CPU starves once there is more than one.
+//!
+//! And one thing that needs no lock at all:
+//!
+//! - `ByteRing` - a byte queue between an interrupt handler and a single reader.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+/// Turns interrupts off and returns whether they were on.
+#[inline]
+pub fn irq_save() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let on = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        on
+    }
+    #[cfg(target_arch = "x86")]
+    {
+        let flags: u32;
+        unsafe {
+            core::arch::asm!("pushfd", "pop {}", "cli", out(reg) flags);
+        }
+        flags & (1 << 9) != 0
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    false
+}
+
+/// Whether interrupts are on.
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+#[inline]
+pub fn irq_enabled() -> bool {
+    let on = irq_save();
+    irq_restore(on);
+    on
+}
+
+/// Turns interrupts back on if `irq_save` found them on.
+#[inline]
+pub fn irq_restore(on: bool) {
+    if on {
+        #[cfg(target_arch = "x86_64")]
+        x86_64::instructions::interrupts::enable();
+        #[cfg(target_arch = "x86")]
+        unsafe {
+            core::arch::asm!("sti");
+        }
+    }
+}
+
+/// A spinlock that keeps interrupts off while held.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+// SAFETY: only one guard exists at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irq = irq_save();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        SpinLockGuard { lock: self, irq }
+    }
+
+    /// Takes the lock if nobody holds it, without waiting.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq = irq_save();
+        match self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => Some(SpinLockGuard { lock: self, irq }),
+            Err(_) => {
+                irq_restore(irq);
+                None
+            }
+        }
+    }
+
+    /// Releases the lock no matter who holds it.
+    ///
+    /// # Safety
+    /// Only for when the holder will never run again (e.g. panicking).
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irq: bool,
+}
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: the guard means the lock is held.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard means the lock is held.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        irq_restore(self.irq);
+    }
+}
+
+/// A fair spinlock: whoever asked first gets it first.  Keeps interrupts off while held.
+pub struct TicketLock<T> {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+// SAFETY: only one guard exists at a time.
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let irq = irq_save();
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        TicketLockGuard { lock: self, irq }
+    }
+}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+    irq: bool,
+}
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: the guard means the lock is held.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard means the lock is held.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        irq_restore(self.irq);
+    }
+}
+
+/// A queue of bytes that one interrupt handler can push to and one reader pop from, without either taking a lock.
+/// When full, new bytes are dropped.
+pub struct ByteRing<const N: usize> {
+    bytes: [AtomicU8; N],
+    /// Both only ever count up; the slot is the count modulo `N`.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [const { AtomicU8::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return false;
+        }
+        self.bytes[tail % N].store(byte, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.bytes[head % N].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
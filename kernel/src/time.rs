@@ -0,0 +1,385 @@
+//! **HyperText Markup Time**
+//!
+//! Where the kernel gets the time from.  `init` looks for the clocks the machine has (the HPET, if ACPI lists one,
+//! the PIT otherwise), measures the TSC and local APIC timer against it, and then starts a 1 kHz tick on the local
+//! APIC timer (or, without APICs, on the PIT).  The tick also drives the scheduler (`sched::tick`).
+//!
+//! - `now` - time since `init`, from the best monotonic clock found (see `ClockSource`).
+//! - `sleep` - waits, letting other threads run if there are any, and halting between ticks otherwise.
+//! - `add_timer`/`cancel_timer` - one-shot or periodic callbacks, run from the tick interrupt.  Only the self-tests
+//!   (and `executor::sleep`, for them) set any yet.
+
+use crate::{
+    apic,
+    port_io::{inb, outb},
+    sync::SpinLock,
+    vmm,
+};
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, _rdtsc};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, _rdtsc};
+#[cfg(feature = "selftest")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, time::Duration};
+use spin::Once;
+
+/// How often the tick fires.
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_HZ: u64 = 1_193_182;
+/// How long each calibration window is.
+const CALIBRATION_MS: u64 = 10;
+
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xF0;
+const HPET_64BIT: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1 << 0;
+
+/// Where `now` reads the time from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The time stamp counter.  Only picked if it's invariant, i.e. ticks at the same rate in every power state.
+    Tsc,
+    /// The HPET's main counter.
+    Hpet,
+    /// Counting ticks; only millisecond resolution.
+    Tick,
+}
+
+/// The HPET's registers, mapped.
+pub struct Hpet {
+    regs: usize,
+    /// Length of one counter tick, in femtoseconds.
+    period_fs: u64,
+}
+impl Hpet {
+    /// Maps and starts the HPET described by the ACPI table at (virtual address) `table`.
+    ///
+    /// Only HPETs with a 64-bit counter are used; a 32-bit one wraps every few minutes.
+    fn new(table: usize) -> Result<Self, &'static str> {
+        // SAFETY: the HPET table holds a generic address structure at 40, with the address itself at 44.
+        let (space, phys) = unsafe {
+            (
+                ((table + 40) as *const u8).read(),
+                ((table + 44) as *const u64).read_unaligned(),
+            )
+        };
+        if space != 0 {
+            return Err("HPET isn't memory-mapped");
+        }
+
+        let hpet = Self {
+            regs: vmm::map_mmio(phys as usize, 0x400)?,
+            period_fs: 0,
+        };
+        let caps = hpet.read(HPET_CAPABILITIES);
+        if caps & HPET_64BIT == 0 {
+            return Err("HPET counter is only 32 bits");
+        }
+        let period_fs = caps >> 32;
+        if period_fs == 0 || period_fs > 100_000_000 {
+            return Err("HPET reports a bogus period");
+        }
+        hpet.write(HPET_CONFIG, hpet.read(HPET_CONFIG) | HPET_ENABLE);
+        Ok(Self { period_fs, ..hpet })
+    }
+
+    fn read(&self, reg: usize) -> u64 {
+        // SAFETY: `regs` maps the HPET's register block.
+        unsafe { ((self.regs + reg) as *const u64).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        // SAFETY: `regs` maps the HPET's register block.
+        unsafe { ((self.regs + reg) as *mut u64).write_volatile(value) }
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(HPET_COUNTER)
+    }
+
+    /// How often the counter ticks.
+    pub const fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+
+    /// Busy-waits `ms` milliseconds.
+    fn wait_ms(&self, ms: u64) {
+        let start = self.counter();
+        let ticks = ms * 1_000_000_000_000 / self.period_fs;
+        while self.counter().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Busy-waits `ms` (at most 54) milliseconds on PIT channel 2.
+///
+/// Channel 2 is the one whose gate software controls (bit 0 of port 0x61), and whose output can be read back (bit 5),
+/// so this needs no interrupt.
+fn pit_wait_ms(ms: u64) {
+    let count = (PIT_HZ * ms / 1000).min(0xFFFF) as u16;
+    // Gate on, speaker off.
+    let old = inb(0x61);
+    outb(0x61, (old & !0x02) | 0x01);
+    // Channel 2, low then high byte, mode 0 (output goes high once the count runs out).
+    outb(0x43, 0b1011_0000);
+    outb(0x42, count as u8);
+    outb(0x42, (count >> 8) as u8);
+    while inb(0x61) & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+    outb(0x61, old);
+}
+
+/// Has PIT channel 0 raise IRQ 0 `TICK_HZ` times a second, for machines without a local APIC timer.
+fn pit_start_periodic() {
+    let count = (PIT_HZ / TICK_HZ) as u16;
+    // Channel 0, low then high byte, mode 2 (rate generator).
+    outb(0x43, 0b0011_0100);
+    outb(0x40, count as u8);
+    outb(0x40, (count >> 8) as u8);
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: every x86_64 CPU has the TSC, and so has every 32-bit one with a CPUID the rest of the kernel needs.
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC keeps the same rate through frequency changes and sleep states.
+fn tsc_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Everything `init` found out about the machine's clocks.
+pub struct Clock {
+    pub source: ClockSource,
+    pub hpet: Option<Hpet>,
+    /// TSC frequency, in Hz.
+    pub tsc_hz: u64,
+    pub tsc_invariant: bool,
+    /// How much the local APIC timer counts down per millisecond (divided by `apic::TIMER_DIVISOR`).  None if the tick
+    /// comes from the PIT.
+    pub lapic_per_ms: Option<u32>,
+    /// Counter values at `init`, so `now` starts at 0.
+    tsc_base: u64,
+    hpet_base: u64,
+}
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}, TSC {}.{:03} MHz{}, ",
+            self.source,
+            self.tsc_hz / 1_000_000,
+            self.tsc_hz / 1000 % 1000,
+            if self.tsc_invariant {
+                " (invariant)"
+            } else {
+                ""
+            }
+        )?;
+        match self.lapic_per_ms {
+            Some(per_ms) => write!(
+                f,
+                "local APIC timer {} kHz",
+                per_ms as u64 * apic::TIMER_DIVISOR as u64
+            )?,
+            None => write!(f, "PIT tick")?,
+        }
+        match &self.hpet {
+            Some(hpet) => write!(f, ", HPET {} Hz", hpet.frequency()),
+            None => write!(f, ", no HPET"),
+        }
+    }
+}
+
+static CLOCK: Once<Clock> = Once::new();
+/// Ticks since the tick was started.  A lock rather than an `AtomicU64`: 32-bit x86 has no 64-bit atomics.
+static TICKS: SpinLock<u64> = SpinLock::new(0);
+
+/// Finds the clocks and calibrates them, then starts the tick on `vector`.  `hpet` is the (virtual) address of the
+/// ACPI HPET table, if there is one.
+///
+/// Needs the local APIC (`apic::init`) and something on `vector` calling `tick`.  Without a local APIC, the tick comes
+/// from the PIT, on IRQ 0; routing that to `vector` is up to the caller.
+pub fn init(hpet: Option<usize>, vector: u8) -> Result<&'static Clock, &'static str> {
+    if CLOCK.is_completed() {
+        return Err("clock already initialized");
+    }
+    // Without an HPET the PIT does, it just can't be read as a clock.
+    let hpet = hpet.and_then(|table| Hpet::new(table).ok());
+
+    let lapic = apic::model().is_some();
+
+    // Nothing may interrupt the measurement.
+    let irq = crate::sync::irq_save();
+    if lapic {
+        apic::timer_start(vector, u32::MAX, false);
+    }
+    let tsc_start = rdtsc();
+    match &hpet {
+        Some(hpet) => hpet.wait_ms(CALIBRATION_MS),
+        None => pit_wait_ms(CALIBRATION_MS),
+    }
+    let tsc_end = rdtsc();
+    let lapic_left = apic::timer_count();
+    apic::timer_stop();
+    crate::sync::irq_restore(irq);
+
+    let tsc_hz = (tsc_end - tsc_start) * 1000 / CALIBRATION_MS;
+    let lapic_per_ms = if lapic {
+        match (u32::MAX - lapic_left) / CALIBRATION_MS as u32 {
+            0 => return Err("local APIC timer didn't count"),
+            per_ms => Some(per_ms),
+        }
+    } else {
+        None
+    };
+
+    let tsc_invariant = tsc_invariant();
+    let source = if tsc_invariant && tsc_hz != 0 {
+        ClockSource::Tsc
+    } else if hpet.is_some() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Tick
+    };
+    let clock = CLOCK.call_once(|| Clock {
+        source,
+        hpet_base: hpet.as_ref().map_or(0, Hpet::counter),
+        hpet,
+        tsc_hz,
+        tsc_invariant,
+        lapic_per_ms,
+        tsc_base: rdtsc(),
+    });
+
+    match lapic_per_ms {
+        Some(per_ms) => apic::timer_start(vector, per_ms * 1000 / TICK_HZ as u32, true),
+        None => pit_start_periodic(),
+    }
+    Ok(clock)
+}
+
+/// Time since `init` (0 before it).
+pub fn now() -> Duration {
+    let Some(clock) = CLOCK.get() else {
+        return Duration::ZERO;
+    };
+    let ns = match (clock.source, &clock.hpet) {
+        (ClockSource::Tsc, _) => {
+            ((rdtsc() - clock.tsc_base) as u128 * 1_000_000_000 / clock.tsc_hz as u128) as u64
+        }
+        (ClockSource::Hpet, Some(hpet)) => hpet.ticks_to_ns(hpet.counter() - clock.hpet_base),
+        _ => ticks() * (1_000_000_000 / TICK_HZ),
+    };
+    Duration::from_nanos(ns)
+}
+
+/// Ticks since `init`.
+pub fn ticks() -> u64 {
+    *TICKS.lock()
+}
+
+/// Waits at least `duration`.  If interrupts are on, other threads run in the meantime (`sched::sleep_until`), or,
+/// before there are any, the CPU halts between ticks; otherwise this spins.  Nothing on 32-bit x86 waits yet, outside
+/// the self-tests.
+#[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+pub fn sleep(duration: Duration) {
+    let deadline = now() + duration;
+    let halt = CLOCK.is_completed() && crate::sync::irq_enabled();
+    while now() < deadline {
+        if !halt {
+            core::hint::spin_loop();
+        } else if !crate::sched::sleep_until(deadline) {
+            crate::halt();
+        }
+    }
+}
+
+/// Names a timer, to cancel it with.
+#[cfg(feature = "selftest")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId(usize);
+
+struct Timer {
+    #[cfg(feature = "selftest")]
+    id: TimerId,
+    deadline: Duration,
+    period: Option<Duration>,
+    callback: Arc<dyn Fn() + Send + Sync>,
+}
+
+static TIMERS: SpinLock<Vec<Timer>> = SpinLock::new(Vec::new());
+#[cfg(feature = "selftest")]
+static NEXT_TIMER: AtomicUsize = AtomicUsize::new(1);
+
+/// Calls `callback` from the tick interrupt once `delay` has passed, and then every `period` if there is one.
+///
+/// Timers are checked once per tick, so they run up to a tick late.  Callbacks run with interrupts off and should be
+/// short; they may add or cancel timers.
+#[cfg(feature = "selftest")]
+pub fn add_timer(
+    delay: Duration,
+    period: Option<Duration>,
+    callback: impl Fn() + Send + Sync + 'static,
+) -> TimerId {
+    let id = TimerId(NEXT_TIMER.fetch_add(1, Ordering::Relaxed));
+    TIMERS.lock().push(Timer {
+        id,
+        deadline: now() + delay,
+        // A zero period would fire on every check forever.
+        period: period.map(|p| p.max(Duration::from_nanos(1_000_000_000 / TICK_HZ))),
+        callback: Arc::new(callback),
+    });
+    id
+}
+
+/// Stops a timer.  Returns whether it was still pending (one-shot timers are gone once they ran).
+#[cfg(feature = "selftest")]
+pub fn cancel_timer(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    match timers.iter().position(|t| t.id == id) {
+        Some(i) => {
+            timers.swap_remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Counts a tick, runs the timers that are due and lets the scheduler wake its sleepers.  Called from the tick
+/// interrupt.
+pub fn tick() {
+    *TICKS.lock() += 1;
+
+    let now = now();
+    // One at a time, and outside the lock, so a callback can add or cancel timers itself.
+    loop {
+        let callback = {
+            let mut timers = TIMERS.lock();
+            let Some(i) = timers.iter().position(|t| t.deadline <= now) else {
+                break;
+            };
+            match timers[i].period {
+                Some(period) => {
+                    timers[i].deadline += period;
+                    timers[i].callback.clone()
+                }
+                None => timers.swap_remove(i).callback,
+            }
+        };
+        callback();
+    }
+    #[cfg(any(target_arch = "x86_64", feature = "selftest"))]
+    crate::sched::wake_sleepers(now);
+    crate::sched::tick();
+}
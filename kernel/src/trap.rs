@@ -0,0 +1,56 @@
+//! **HyperText Markup Traps**
+//!
+//! What the exception handlers of both x86 flavors tell the panic screen about an exception.
+
+use core::fmt;
+
+/// The name and mnemonic of exception `vector`.
+pub const fn name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("DIVIDE ERROR", "#DE"),
+        1 => ("DEBUG", "#DB"),
+        2 => ("NON-MASKABLE INTERRUPT", "NMI"),
+        3 => ("BREAKPOINT", "#BP"),
+        4 => ("OVERFLOW", "#OF"),
+        5 => ("BOUND RANGE EXCEEDED", "#BR"),
+        6 => ("INVALID OPCODE", "#UD"),
+        7 => ("DEVICE NOT AVAILABLE", "#NM"),
+        8 => ("DOUBLE FAULT", "#DF"),
+        9 => ("COPROCESSOR SEGMENT OVERRUN", "#MF"),
+        10 => ("INVALID TSS", "#TS"),
+        11 => ("SEGMENT NOT PRESENT", "#NP"),
+        12 => ("STACK-SEGMENT FAULT", "#SS"),
+        13 => ("GENERAL PROTECTION FAULT", "#GP"),
+        14 => ("PAGE FAULT", "#PF"),
+        16 => ("x87 FLOATING-POINT ERROR", "#MF"),
+        17 => ("ALIGNMENT CHECK", "#AC"),
+        18 => ("MACHINE CHECK", "#MC"),
+        19 => ("SIMD FLOATING-POINT ERROR", "#XM"),
+        20 => ("VIRTUALIZATION EXCEPTION", "#VE"),
+        21 => ("CONTROL PROTECTION EXCEPTION", "#CP"),
+        28 => ("HYPERVISOR INJECTION EXCEPTION", "#HV"),
+        29 => ("VMM COMMUNICATION EXCEPTION", "#VC"),
+        30 => ("SECURITY EXCEPTION", "#SX"),
+        _ => ("RESERVED", "#??"),
+    }
+}
+
+/// Describes a selector error code (#TS, #NP, #SS, #GP).  Doesn't allocate, since the heap may be what faulted.
+pub struct SelectorError(pub u64);
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0;
+        if code == 0 {
+            return f.write_str("no selector");
+        }
+        match (code >> 1) & 0b11 {
+            0b00 => write!(f, "GDT index {}", (code >> 3) & 0x1FFF)?,
+            0b10 => write!(f, "LDT index {}", (code >> 3) & 0x1FFF)?,
+            _ => write!(f, "IDT vector {}", (code >> 3) & 0xFF)?,
+        }
+        if code & 1 != 0 {
+            f.write_str(", external event")?;
+        }
+        Ok(())
+    }
+}
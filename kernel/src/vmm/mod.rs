@@ -0,0 +1,70 @@
+//! **HyperText Markup Virtual Memory Manager**
+//!
+//! Address space layout on x86_64:
+//!
+//! - `0x0000_0000_0000_0000` - Lower half, left for user space (firmware runtime regions are identity-mapped until
+//!   `efi_rt::init` moves them)
+//! - `0xFFFF_8000_0000_0000` - Direct map of all physical memory (uncached where it isn't RAM)
+//! - `0xFFFF_C000_0000_0000` - Kernel stacks, each with an unmapped guard page below
+//! - `0xFFFF_E000_0000_0000` - MMIO window
+//! - `0xFFFF_F000_0000_0000` - UEFI runtime services, at the same offsets as their physical addresses
+//! - `0xFFFF_FFFF_8000_0000` - The kernel image (somewhere in the first 1 GiB, wherever the loader slid it)
+//!
+//! Until `init` runs, the loader's identity mapping is still in use and a physical address is its own virtual address.
+//! Anything holding a physical address has to go through `phys_to_virt` before dereferencing it.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(target_arch = "x86_64")]
+mod paging;
+#[cfg(target_arch = "x86_64")]
+mod stack;
+#[cfg(target_arch = "x86_64")]
+pub use paging::*;
+#[cfg(target_arch = "x86_64")]
+pub use stack::*;
+
+/// Start of the window the kernel image runs in.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_BASE: usize = 0xFFFF_FFFF_8000_0000;
+
+/// Where the kernel image runs, relative to where it was loaded.  The loader may put it anywhere, so the entry stub
+/// works this out and stores it before any Rust runs.
+#[unsafe(export_name = "__kernel_offset")]
+static KERNEL_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub fn kernel_offset() -> usize {
+    KERNEL_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Where physical memory is currently visible (0 while the loader's identity mapping is in use).
+static PHYS_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a pointer-usable address for the given physical address.
+#[inline]
+pub fn phys_to_virt(phys: usize) -> usize {
+    phys + PHYS_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Without paging, MMIO is used where it is.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn map_mmio(phys: usize, _size: usize) -> Result<usize, &'static str> {
+    Ok(phys)
+}
+
+/// Reverses `phys_to_virt`.  Also works for addresses inside the kernel image.
+#[inline]
+pub fn virt_to_phys(virt: usize) -> usize {
+    // Without a window of its own, the kernel image is where it was loaded, in the identity mapping.
+    #[cfg(target_arch = "x86_64")]
+    if virt >= KERNEL_BASE {
+        return virt - kernel_offset();
+    }
+    virt - PHYS_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Physical address of a linker-defined symbol.
+pub fn kernel_phys(sym: &u8) -> usize {
+    sym as *const u8 as usize - kernel_offset()
+}
@@ -0,0 +1,327 @@
+use super::{PHYS_OFFSET, kernel_offset, phys_to_virt};
+use crate::{
+    frame_alloc,
+    memmap::{self, RegionAttrs, RegionKind},
+    sync::SpinLock,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "selftest")]
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags},
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PhysFrame, Size2MiB,
+        Size4KiB, Translate,
+        mapper::{MapToError, UnmapError},
+    },
+};
+
+pub use x86_64::structures::paging::PageTableFlags;
+
+/// Start of the direct map of physical memory.
+pub const PHYS_MAP_BASE: usize = 0xFFFF_8000_0000_0000;
+/// Start of the window `map_mmio` hands out addresses from.
+pub const MMIO_BASE: usize = 0xFFFF_E000_0000_0000;
+/// Where UEFI runtime services live once `efi_rt::init` has called `set_virtual_address_map`.
+pub const RUNTIME_BASE: usize = 0xFFFF_F000_0000_0000;
+pub const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+static MAPPER: SpinLock<Option<OffsetPageTable<'static>>> = SpinLock::new(None);
+static MMIO_NEXT: AtomicUsize = AtomicUsize::new(MMIO_BASE);
+
+/// Hands page table frames to the `x86_64` crate.
+struct Frames;
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        frame_alloc::alloc_frame()
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)))
+    }
+}
+
+const fn map_err<S: PageSize>(e: MapToError<S>) -> &'static str {
+    match e {
+        MapToError::FrameAllocationFailed => "out of frames for page tables",
+        MapToError::ParentEntryHugePage => "address is inside a huge page",
+        MapToError::PageAlreadyMapped(_) => "page already mapped",
+    }
+}
+const fn unmap_err(e: UnmapError) -> &'static str {
+    match e {
+        UnmapError::ParentEntryHugePage => "address is inside a huge page",
+        UnmapError::PageNotMapped => "page not mapped",
+        UnmapError::InvalidFrameAddress(_) => "page table entry holds an invalid address",
+    }
+}
+#[cfg(feature = "selftest")]
+const fn flag_err(e: FlagUpdateError) -> &'static str {
+    match e {
+        FlagUpdateError::PageNotMapped => "page not mapped",
+        FlagUpdateError::ParentEntryHugePage => "address is inside a huge page",
+    }
+}
+
+/// Highest physical address the direct map has to cover: every region of the memory map and the first 4 GiB
+/// (where the local APIC, I/O APIC and most other MMIO lives).
+fn phys_top() -> usize {
+    memmap::regions()
+        .iter()
+        .map(|r| r.end())
+        .fold(4 << 30, usize::max)
+}
+
+/// Whether the direct map caches `start..end`: Some(true) if it's all memory, Some(false) if none of it is (device
+/// memory, reserved ranges and whatever the memory map leaves out), None if it's some of each.
+///
+/// Device memory is left uncached so that it has the same memory type here as where `map_mmio` puts it; two mappings
+/// of a page with different types is undefined.
+fn cacheable(start: usize, end: usize) -> Option<bool> {
+    let memory: usize = memmap::regions()
+        .iter()
+        .filter(|r| !matches!(r.kind, RegionKind::Mmio | RegionKind::Reserved))
+        .map(|r| r.end().min(end).saturating_sub(r.start.max(start)))
+        .sum();
+    match memory {
+        0 => Some(false),
+        m if m == end - start => Some(true),
+        _ => None,
+    }
+}
+
+/// Maps the linker-defined range of the kernel image with the given flags.
+fn map_kernel_section(mapper: &mut OffsetPageTable, start: &u8, end: &u8, flags: PageTableFlags) {
+    let (start, end) = (start as *const u8 as usize, end as *const u8 as usize);
+    for virt in (start..end).step_by(PAGE_SIZE) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt as u64));
+        let frame = PhysFrame::containing_address(PhysAddr::new((virt - kernel_offset()) as u64));
+        // SAFETY: the frame holds this very part of the kernel.
+        unsafe { mapper.map_to(page, frame, flags, &mut Frames) }
+            .map_err(map_err)
+            .unwrap()
+            .ignore();
+    }
+}
+
+/// Builds the kernel's own page tables and switches to them.
+///
+/// The frame allocator has to be initialized first, and nothing may hold on to a physical address used as a pointer
+/// across this call (see `phys_to_virt`).
+pub fn init() {
+    use PageTableFlags as F;
+
+    // SAFETY: only turns features on: NX bits and read-only pages that hold for ring 0 too.
+    unsafe {
+        Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let pml4_phys = frame_alloc::alloc_frame().expect("no frame for the kernel PML4");
+    let pml4 = unsafe { &mut *(phys_to_virt(pml4_phys) as *mut PageTable) };
+    pml4.zero();
+    // SAFETY: physical memory is reachable at the current offset (identity, from the loader).
+    let mut mapper = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(phys_to_virt(0) as u64)) };
+
+    // Direct map, 2 MiB at a time where that doesn't mix memory with anything else, 4 KiB at a time where it does
+    let flags = |cached: bool| {
+        let flags = F::PRESENT | F::WRITABLE | F::NO_EXECUTE;
+        if cached {
+            flags
+        } else {
+            flags | F::NO_CACHE | F::WRITE_THROUGH
+        }
+    };
+    let huge = Size2MiB::SIZE as usize;
+    let top = phys_top().next_multiple_of(huge);
+    for phys in (0..top).step_by(huge) {
+        if let Some(cached) = cacheable(phys, phys + huge) {
+            let page =
+                Page::<Size2MiB>::containing_address(VirtAddr::new((PHYS_MAP_BASE + phys) as u64));
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys as u64));
+            // SAFETY: a fresh address space; nothing else lives up here.
+            unsafe { mapper.map_to(page, frame, flags(cached), &mut Frames) }
+                .map_err(map_err)
+                .unwrap()
+                .ignore();
+            continue;
+        }
+        for phys in (phys..phys + huge).step_by(PAGE_SIZE) {
+            // E820 regions needn't be whole pages; one that's still mixed is left uncached.
+            let cached = cacheable(phys, phys + PAGE_SIZE) == Some(true);
+            let page =
+                Page::<Size4KiB>::containing_address(VirtAddr::new((PHYS_MAP_BASE + phys) as u64));
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys as u64));
+            // SAFETY: as above.
+            unsafe { mapper.map_to(page, frame, flags(cached), &mut Frames) }
+                .map_err(map_err)
+                .unwrap()
+                .ignore();
+        }
+    }
+
+    // Kernel image, each section with its own permissions
+    unsafe {
+        map_kernel_section(&mut mapper, &__text_start, &__text_end, F::PRESENT);
+        map_kernel_section(
+            &mut mapper,
+            &__rodata_start,
+            &__rodata_end,
+            F::PRESENT | F::NO_EXECUTE,
+        );
+        map_kernel_section(
+            &mut mapper,
+            &__data_start,
+            &__data_end,
+            F::PRESENT | F::WRITABLE | F::NO_EXECUTE,
+        );
+        map_kernel_section(
+            &mut mapper,
+            &crate::__stack_start,
+            &crate::__stack_end,
+            F::PRESENT | F::WRITABLE | F::NO_EXECUTE,
+        );
+    }
+
+    // Firmware runtime services still run from their physical addresses, until `efi_rt::init` moves them up.
+    crate::reclaim::for_each_runtime_region(|r| {
+        let mut flags = F::PRESENT;
+        if !r.code || r.attrs.contains(RegionAttrs::NX) {
+            flags |= F::NO_EXECUTE;
+        }
+        if !r.code && !r.attrs.contains(RegionAttrs::RO) {
+            flags |= F::WRITABLE;
+        }
+        if !r.attrs.contains(RegionAttrs::WB) {
+            flags |= F::NO_CACHE | F::WRITE_THROUGH;
+        }
+        for p in 0..r.pages {
+            let addr = r.phys + p * PAGE_SIZE;
+            let frame = PhysFrame::containing_address(PhysAddr::new(addr as u64));
+            for virt in [addr, RUNTIME_BASE + addr] {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt as u64));
+                // SAFETY: the firmware owns these pages; we only make them reachable.
+                unsafe { mapper.map_to(page, frame, flags, &mut Frames) }
+                    .map_err(map_err)
+                    .unwrap()
+                    .ignore();
+            }
+        }
+    });
+
+    // SAFETY: everything the kernel touches from here on is mapped above.
+    unsafe {
+        Cr3::write(
+            PhysFrame::containing_address(PhysAddr::new(pml4_phys as u64)),
+            Cr3Flags::empty(),
+        );
+    }
+    PHYS_OFFSET.store(PHYS_MAP_BASE, Ordering::Relaxed);
+
+    let pml4 = unsafe { &mut *(phys_to_virt(pml4_phys) as *mut PageTable) };
+    *MAPPER.lock() =
+        Some(unsafe { OffsetPageTable::new(pml4, VirtAddr::new(PHYS_MAP_BASE as u64)) });
+}
+
+/// Maps `size` bytes of physical memory at `phys` to `virt`, 4 KiB at a time.
+pub fn map(
+    virt: usize,
+    phys: usize,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), &'static str> {
+    let mut guard = MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .ok_or("virtual memory manager not initialized")?;
+
+    for off in (0..size).step_by(PAGE_SIZE) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((virt + off) as u64));
+        let frame = PhysFrame::containing_address(PhysAddr::new((phys + off) as u64));
+        // SAFETY: it's up to the caller what the frames are used for.
+        unsafe { mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, &mut Frames) }
+            .map_err(map_err)?
+            .flush();
+    }
+    Ok(())
+}
+
+/// Unmaps `size` bytes starting at `virt`.  The frames behind them are not freed.
+pub fn unmap(virt: usize, size: usize) -> Result<(), &'static str> {
+    let mut guard = MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .ok_or("virtual memory manager not initialized")?;
+
+    let mut ret = Ok(());
+    for off in (0..size).step_by(PAGE_SIZE) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((virt + off) as u64));
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(e) => {
+                ret = Err(unmap_err(e));
+                break;
+            }
+        }
+    }
+    drop(guard);
+    // Other CPUs may still have the old entries cached.
+    crate::smp::tlb_shootdown(virt, size);
+    ret
+}
+
+/// Changes the flags of `size` bytes of already mapped memory starting at `virt`.
+#[cfg(feature = "selftest")]
+pub fn protect(virt: usize, size: usize, flags: PageTableFlags) -> Result<(), &'static str> {
+    let mut guard = MAPPER.lock();
+    let mapper = guard
+        .as_mut()
+        .ok_or("virtual memory manager not initialized")?;
+
+    let mut ret = Ok(());
+    for off in (0..size).step_by(PAGE_SIZE) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((virt + off) as u64));
+        // SAFETY: it's up to the caller what the new flags allow.
+        match unsafe { mapper.update_flags(page, flags | PageTableFlags::PRESENT) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                ret = Err(flag_err(e));
+                break;
+            }
+        }
+    }
+    drop(guard);
+    crate::smp::tlb_shootdown(virt, size);
+    ret
+}
+
+/// Returns the physical address behind `virt`, if it is mapped.
+pub fn translate(virt: usize) -> Option<usize> {
+    let guard = MAPPER.lock();
+    let phys = guard.as_ref()?.translate_addr(VirtAddr::new(virt as u64))?;
+    Some(phys.as_u64() as usize)
+}
+
+/// Maps device memory uncached into the MMIO window and returns the virtual address matching `phys`.
+pub fn map_mmio(phys: usize, size: usize) -> Result<usize, &'static str> {
+    use PageTableFlags as F;
+
+    let base = phys & !(PAGE_SIZE - 1);
+    let size = (phys + size - base).next_multiple_of(PAGE_SIZE);
+    let virt = MMIO_NEXT.fetch_add(size, Ordering::Relaxed);
+    map(
+        virt,
+        base,
+        size,
+        F::WRITABLE | F::NO_EXECUTE | F::NO_CACHE | F::WRITE_THROUGH,
+    )?;
+    Ok(virt + (phys - base))
+}
@@ -0,0 +1,100 @@
+//! Kernel stacks, each with an unmapped guard page right below it.
+//!
+//! Running off the end of a stack hits the guard page and faults, and `is_guard_page` lets the fault handlers tell
+//! that apart from any other bad access.
+
+use super::{PAGE_SIZE, PageTableFlags, map, translate, unmap};
+use crate::frame_alloc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Start of the window stacks are allocated from.
+pub const STACK_BASE: usize = 0xFFFF_C000_0000_0000;
+/// Size of a kernel stack when nobody asks for anything else.
+pub const DEFAULT_STACK_SIZE: usize = 32 * 1024;
+
+unsafe extern "C" {
+    static __stack_guard: u8;
+}
+
+/// Next free address in the stack window.  Addresses aren't reused; the window is big enough not to care.
+static STACK_NEXT: AtomicUsize = AtomicUsize::new(STACK_BASE);
+/// Guard pages of every live stack (0 is an empty slot).  No lock, since fault handlers read it.
+static GUARDS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+
+/// A kernel stack.  Unmapped and given back when dropped, so nothing may still be running on it by then.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// Lowest mapped address; the guard page is the page below.
+    bottom: usize,
+    size: usize,
+}
+impl KernelStack {
+    /// Maps a new stack of at least `size` bytes (rounded up to whole pages).
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        let size = size.max(1).next_multiple_of(PAGE_SIZE);
+        let guard = STACK_NEXT.fetch_add(PAGE_SIZE + size, Ordering::Relaxed);
+
+        if !GUARDS.iter().any(|g| {
+            g.compare_exchange(0, guard, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        }) {
+            return Err("too many kernel stacks");
+        }
+
+        // Grow the stack page by page, so dropping it on failure only gives back what was mapped.
+        let mut stack = Self {
+            bottom: guard + PAGE_SIZE,
+            size: 0,
+        };
+        while stack.size < size {
+            let frame = frame_alloc::alloc_frame().ok_or("out of frames for a kernel stack")?;
+            if let Err(e) = map(
+                stack.top(),
+                frame,
+                PAGE_SIZE,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            ) {
+                frame_alloc::free_frame(frame);
+                return Err(e);
+            }
+            stack.size += PAGE_SIZE;
+        }
+        Ok(stack)
+    }
+
+    /// The initial stack pointer: one past the highest byte.
+    pub const fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    /// Only the self-tests look for the guard page below the stack yet.
+    #[cfg(feature = "selftest")]
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+}
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for virt in (self.bottom..self.top()).step_by(PAGE_SIZE) {
+            if let Some(frame) = translate(virt) {
+                let _ = unmap(virt, PAGE_SIZE);
+                frame_alloc::free_frame(frame);
+            }
+        }
+
+        let guard = self.bottom - PAGE_SIZE;
+        for g in &GUARDS {
+            let _ = g.compare_exchange(guard, 0, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether `addr` lies in the guard page of the boot stack or of any live kernel stack.
+pub fn is_guard_page(addr: usize) -> bool {
+    let page = addr & !(PAGE_SIZE - 1);
+    // SAFETY: given from linker.
+    if page == unsafe { &__stack_guard as *const u8 as usize } {
+        return true;
+    }
+    GUARDS.iter().any(|g| g.load(Ordering::Relaxed) == page)
+}
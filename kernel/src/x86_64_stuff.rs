@@ -4,18 +4,17 @@ pub fn hlt_loop() -> ! {
     }
 }
 
-use crate::{print, println};
+use crate::{apic, irq, print, println};
+use core::arch::global_asm;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::port::Port;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+#[cfg(feature = "selftest")]
+pub use exceptions::{RecoveryHook, TrapFrame, register_recovery_hook, unregister_recovery_hook};
 
 // --- CONSTANTS ---
-// Verified via your MADT parsing
-const IOAPIC_BASE: u64 = 0xFEC00000;
-const LAPIC_BASE: u64 = 0xFEE00000;
-const KEYBOARD_VECTOR: u8 = 33;
-const SPURIOUS_VECTOR: u8 = 255;
+// The hardware vectors are in `irq`, shared with 32-bit.
+const TLB_SHOOTDOWN_VECTOR: u8 = 253;
 
 // --- GDT MOD ---
 mod gdt {
@@ -39,29 +38,70 @@ mod gdt {
                 let stack_start = VirtAddr::from_ptr(unsafe { &raw const STACK.0 });
                 stack_start + STACK_SIZE as u64
             };
+            // Where interrupts from ring 3 land; `syscall` uses it too.
+            tss.privilege_stack_table[0] = syscall::trap_stack_top();
             tss
         };
         static ref GDT: (GlobalDescriptorTable, Selectors) = {
             let mut gdt = GlobalDescriptorTable::new();
-            let code_selector = gdt.append(Descriptor::kernel_code_segment());
-            let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-            (gdt, Selectors { code_selector, tss_selector })
+            let selectors = append_segments(&mut gdt, &TSS);
+            (gdt, selectors)
         };
     }
 
-    struct Selectors {
-        code_selector: SegmentSelector,
+    pub struct Selectors {
+        pub code_selector: SegmentSelector,
+        pub data_selector: SegmentSelector,
+        pub user_code_selector: SegmentSelector,
+        pub user_data_selector: SegmentSelector,
         tss_selector: SegmentSelector,
     }
 
+    /// Every CPU's GDT has the same layout, so these go for all of them.
+    pub fn selectors() -> &'static Selectors {
+        &GDT.1
+    }
+
+    /// Fills in a GDT in the order `sysret` needs: user data right before user code.
+    fn append_segments(
+        gdt: &mut GlobalDescriptorTable,
+        tss: &'static TaskStateSegment,
+    ) -> Selectors {
+        Selectors {
+            code_selector: gdt.append(Descriptor::kernel_code_segment()),
+            data_selector: gdt.append(Descriptor::kernel_data_segment()),
+            user_data_selector: gdt.append(Descriptor::user_data_segment()),
+            user_code_selector: gdt.append(Descriptor::user_code_segment()),
+            tss_selector: gdt.append(Descriptor::tss_segment(tss)),
+        }
+    }
+
     pub fn init() {
+        load(&GDT.0, &GDT.1);
+    }
+
+    /// Gives an AP a GDT and TSS of its own, taking double faults on the stack ending at `double_fault_stack`.
+    pub fn init_ap(double_fault_stack: usize) {
+        use alloc::boxed::Box;
+
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::new(double_fault_stack as u64);
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+        let mut gdt = GlobalDescriptorTable::new();
+        let selectors = append_segments(&mut gdt, tss);
+        load(Box::leak(Box::new(gdt)), &selectors);
+    }
+
+    fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
         use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
         use x86_64::instructions::tables::load_tss;
 
-        GDT.0.load();
+        gdt.load();
         unsafe {
-            CS::set_reg(GDT.1.code_selector);
-            load_tss(GDT.1.tss_selector);
+            CS::set_reg(selectors.code_selector);
+            load_tss(selectors.tss_selector);
 
             // CRITICAL: Clear segment registers for Long Mode stability.
             // Some UEFI environments leave garbage here that causes Double Faults on IRQs.
@@ -72,6 +112,217 @@ mod gdt {
     }
 }
 
+// --- EXCEPTIONS MOD ---
+/// Every CPU exception goes through `exception_dispatch` with the full register state, so the panic screen shows what
+/// really went wrong instead of a double fault.  Reserved vectors (15, 22-27 and 31) are left out of the IDT; if one
+/// ever fires, the #GP it causes names it in its error code.
+mod exceptions {
+    use super::*;
+    use crate::{
+        kiss,
+        trap::{SelectorError, name},
+    };
+    use core::{
+        fmt,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use x86_64::{
+        VirtAddr,
+        registers::control::Cr2,
+        structures::idt::{EntryOptions, PageFaultErrorCode},
+    };
+
+    global_asm!(include_str!("./asm_entry_stub/x86_64_exceptions.s"));
+
+    unsafe extern "C" {
+        static exception_stubs: u8;
+    }
+
+    /// Everything the CPU and the entry stub saved, lowest address first.
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    pub struct TrapFrame {
+        pub r15: u64,
+        pub r14: u64,
+        pub r13: u64,
+        pub r12: u64,
+        pub r11: u64,
+        pub r10: u64,
+        pub r9: u64,
+        pub r8: u64,
+        pub rbp: u64,
+        pub rdi: u64,
+        pub rsi: u64,
+        pub rdx: u64,
+        pub rcx: u64,
+        pub rbx: u64,
+        pub rax: u64,
+        pub vector: u64,
+        /// 0 for exceptions that don't push one.
+        pub error_code: u64,
+        pub rip: u64,
+        pub cs: u64,
+        pub rflags: u64,
+        pub rsp: u64,
+        pub ss: u64,
+    }
+    impl fmt::Display for TrapFrame {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let regs = [
+                ("RAX", self.rax),
+                ("RBX", self.rbx),
+                ("RCX", self.rcx),
+                ("RDX", self.rdx),
+                ("RSI", self.rsi),
+                ("RDI", self.rdi),
+                ("RBP", self.rbp),
+                ("RSP", self.rsp),
+                ("R8 ", self.r8),
+                ("R9 ", self.r9),
+                ("R10", self.r10),
+                ("R11", self.r11),
+                ("R12", self.r12),
+                ("R13", self.r13),
+                ("R14", self.r14),
+                ("R15", self.r15),
+                ("RIP", self.rip),
+                ("RFL", self.rflags),
+            ];
+            for (i, (name, value)) in regs.iter().enumerate() {
+                write!(f, "{name}=0x{value:016X}")?;
+                f.write_str(if i % 3 == 2 { "\n" } else { "  " })?;
+            }
+            write!(f, "CS=0x{:04X}  SS=0x{:04X}", self.cs, self.ss)
+        }
+    }
+
+    /// Gets a look at an exception before it's fatal.  Returns true if it dealt with it, say by pointing `frame.rip` at
+    /// a fixup; execution then goes on with whatever `frame` holds.
+    pub type RecoveryHook = fn(&mut TrapFrame) -> bool;
+
+    /// Registered hooks (0 is an empty slot).  No lock, since exception handlers read it.
+    static HOOKS: [AtomicUsize; 16] = [const { AtomicUsize::new(0) }; 16];
+
+    /// Only the self-tests recover from exceptions so far.
+    #[cfg(feature = "selftest")]
+    pub fn register_recovery_hook(hook: RecoveryHook) -> Result<(), &'static str> {
+        if HOOKS.iter().any(|h| {
+            h.compare_exchange(0, hook as usize, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        }) {
+            Ok(())
+        } else {
+            Err("too many recovery hooks")
+        }
+    }
+
+    #[cfg(feature = "selftest")]
+    pub fn unregister_recovery_hook(hook: RecoveryHook) {
+        for h in &HOOKS {
+            let _ = h.compare_exchange(hook as usize, 0, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+
+    /// Entry of `vector`.  The typed entries of `InterruptDescriptorTable` only matter for `set_handler_fn`; every stub
+    /// is entered the same way.
+    fn entry(idt: &mut InterruptDescriptorTable, vector: u8, addr: VirtAddr) -> &mut EntryOptions {
+        // SAFETY: `addr` is the stub for this vector, which leaves a `TrapFrame` and returns with iretq.
+        unsafe {
+            match vector {
+                8 => idt.double_fault.set_handler_addr(addr),
+                10 => idt.invalid_tss.set_handler_addr(addr),
+                11 => idt.segment_not_present.set_handler_addr(addr),
+                12 => idt.stack_segment_fault.set_handler_addr(addr),
+                13 => idt.general_protection_fault.set_handler_addr(addr),
+                14 => idt.page_fault.set_handler_addr(addr),
+                17 => idt.alignment_check.set_handler_addr(addr),
+                18 => idt.machine_check.set_handler_addr(addr),
+                21 => idt.cp_protection_exception.set_handler_addr(addr),
+                29 => idt.vmm_communication_exception.set_handler_addr(addr),
+                30 => idt.security_exception.set_handler_addr(addr),
+                v => idt[v].set_handler_addr(addr),
+            }
+        }
+    }
+
+    pub fn install(idt: &mut InterruptDescriptorTable) {
+        // SAFETY: given from the assembly stub.
+        let base = unsafe { &exception_stubs as *const u8 as u64 };
+        for vector in (0..32u8).filter(|v| !matches!(v, 15 | 22..=27 | 31)) {
+            let options = entry(idt, vector, VirtAddr::new(base + 16 * vector as u64));
+            match vector {
+                // Runs on its own stack, since a kernel stack overflow is the most likely reason for it.
+                8 => unsafe {
+                    options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+                },
+                // Usable from user space (`int3`, `into`).
+                3 | 4 => {
+                    options.set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // SAFETY: the assembly stub calls this by name directly; don't change the name.
+    #[unsafe(no_mangle)]
+    extern "sysv64" fn exception_dispatch(frame: &mut TrapFrame) {
+        // Another CPU panicked (see `smp::halt_others`).
+        if frame.vector == 2 && crate::smp::halting() {
+            hlt_loop();
+        }
+
+        if frame.vector == 3 {
+            println!("EXCEPTION: BREAKPOINT at 0x{:016X}", frame.rip);
+            return;
+        }
+
+        for hook in &HOOKS {
+            let hook = hook.load(Ordering::Acquire);
+            if hook != 0 {
+                // SAFETY: only `register_recovery_hook` puts anything here.
+                let hook: RecoveryHook = unsafe { core::mem::transmute(hook) };
+                if hook(frame) {
+                    return;
+                }
+            }
+        }
+
+        // A user program's exception ends the program, not the kernel.
+        if frame.cs & 3 == 3 {
+            crate::api::htmos::fault(frame.vector as u8, frame.rip as usize);
+        }
+
+        kiss::set_krnl_err(0x20 + frame.vector as u8);
+        let (name, mnemonic) = name(frame.vector);
+        let cr2 = Cr2::read_raw() as usize;
+
+        // A page fault on a guard page, or a double fault because the page fault couldn't push its frame there.
+        if matches!(frame.vector, 8 | 14) && crate::vmm::is_guard_page(cr2) {
+            panic!(
+                "EXCEPTION: {name} ({mnemonic}), KERNEL STACK OVERFLOW at 0x{cr2:016X}\n{frame}"
+            );
+        }
+
+        match frame.vector {
+            10..=13 => panic!(
+                "EXCEPTION: {name} ({mnemonic})\nError code: 0x{:X} ({})\n{frame}",
+                frame.error_code,
+                SelectorError(frame.error_code)
+            ),
+            14 => panic!(
+                "EXCEPTION: {name} ({mnemonic}) at 0x{cr2:016X}\nError code: {:?}\n{frame}",
+                PageFaultErrorCode::from_bits_truncate(frame.error_code)
+            ),
+            8 | 17 | 21 | 29 | 30 => panic!(
+                "EXCEPTION: {name} ({mnemonic})\nError code: 0x{:X}\n{frame}",
+                frame.error_code
+            ),
+            _ => panic!("EXCEPTION: {name} ({mnemonic})\n{frame}"),
+        }
+    }
+}
+
 // --- INTERRUPTS MOD ---
 mod interrupts {
     use super::*;
@@ -79,20 +330,14 @@ mod interrupts {
     lazy_static! {
         static ref IDT: InterruptDescriptorTable = {
             let mut idt = InterruptDescriptorTable::new();
-            idt.breakpoint.set_handler_fn(breakpoint_handler);
-
-            unsafe {
-                // Set Double Fault to use the IST stack
-                idt.double_fault.set_handler_fn(double_fault_handler)
-                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            exceptions::install(&mut idt);
 
-                // Hardware Handlers
-                idt[KEYBOARD_VECTOR].set_handler_fn(keyboard_interrupt_handler);
-                idt[SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
-
-                // Diagnostic Handlers
-                idt.page_fault.set_handler_fn(page_fault_handler);
+            // Hardware Handlers
+            for (irq, handler) in IRQ_HANDLERS.into_iter().enumerate() {
+                idt[irq::vector(irq as u8)].set_handler_fn(handler);
             }
+            idt[TLB_SHOOTDOWN_VECTOR].set_handler_fn(tlb_shootdown_handler);
+            idt[irq::SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
             idt
         };
     }
@@ -101,208 +346,239 @@ mod interrupts {
         IDT.load();
     }
 
-    extern "x86-interrupt" fn page_fault_handler(sf: InterruptStackFrame, err: PageFaultErrorCode) {
-        use x86_64::registers::control::Cr2;
-        panic!("PAGE FAULT at {:?}\nErr: {:?}\n{:#?}", Cr2::read(), err, sf);
-    }
-
     extern "x86-interrupt" fn spurious_handler(_sf: InterruptStackFrame) {
         // No EOI needed for true spurious interrupts
     }
 
-    extern "x86-interrupt" fn breakpoint_handler(sf: InterruptStackFrame) {
-        println!("EXCEPTION: BREAKPOINT\n{:#?}", sf);
+    /// One handler per IRQ, each passing its IRQ on to `irq::handle`.
+    macro_rules! irq_handlers {
+        ($($irq:literal),*) => {
+            [$({
+                extern "x86-interrupt" fn handler(_sf: InterruptStackFrame) {
+                    irq::handle($irq);
+                }
+                handler as extern "x86-interrupt" fn(InterruptStackFrame)
+            }),*]
+        };
     }
+    const IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); 16] =
+        irq_handlers!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
 
-    extern "x86-interrupt" fn double_fault_handler(sf: InterruptStackFrame, _err: u64) -> ! {
-        panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", sf);
+    extern "x86-interrupt" fn tlb_shootdown_handler(_sf: InterruptStackFrame) {
+        crate::smp::tlb_shootdown_ipi();
+        apic::eoi();
     }
+}
 
-    extern "x86-interrupt" fn keyboard_interrupt_handler(_sf: InterruptStackFrame) {
-        use pc_keyboard::{DecodedKey, HandleControl, PS2Keyboard, ScancodeSet1, layouts};
+// --- SYSCALL MOD ---
+/// `syscall` from ring 3 comes in through `syscall_entry` (see x86_64_syscall.s), on the same stack interrupts from
+/// ring 3 switch to.  Only the BSP runs user code, so there's just the one.
+mod syscall {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use x86_64::{
+        VirtAddr,
+        registers::{
+            model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+            rflags::RFlags,
+        },
+    };
 
-        lazy_static! {
-            static ref KEYBOARD: Mutex<PS2Keyboard<layouts::Us104Key, ScancodeSet1>> =
-                Mutex::new(PS2Keyboard::new(
-                    ScancodeSet1::new(),
-                    layouts::Us104Key,
-                    HandleControl::Ignore
-                ));
-        }
+    global_asm!(include_str!("./asm_entry_stub/x86_64_syscall.s"));
 
-        let mut keyboard = KEYBOARD.lock();
-        let mut port = Port::new(0x60);
-        let scancode: u8 = unsafe { port.read() };
+    unsafe extern "sysv64" {
+        fn syscall_entry();
+        #[cfg(feature = "selftest")]
+        fn user_enter(entry: usize, stack: usize, kernel_rsp: *mut usize, code: u16, data: u16);
+        fn user_leave(kernel_rsp: usize) -> !;
+    }
 
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
-            }
+    const TRAP_STACK_SIZE: usize = 4096 * 5;
+    #[repr(align(16))]
+    struct Stack([u8; TRAP_STACK_SIZE]);
+    static mut TRAP_STACK: Stack = Stack([0; TRAP_STACK_SIZE]);
+
+    /// Where `syscall_entry` switches the stack to.
+    #[unsafe(export_name = "syscall_kernel_rsp")]
+    static KERNEL_RSP: AtomicUsize = AtomicUsize::new(0);
+    /// The kernel's stack pointer while user code runs, for `leave_user` to go back to.
+    static KERNEL_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+    pub fn trap_stack_top() -> VirtAddr {
+        VirtAddr::from_ptr(unsafe { &raw const TRAP_STACK.0 }) + TRAP_STACK_SIZE as u64
+    }
+
+    /// What `syscall_entry` saved, lowest address first.  The call's number comes in RAX, and its result goes out
+    /// there.
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    struct SyscallFrame {
+        r9: u64,
+        r8: u64,
+        r10: u64,
+        rdx: u64,
+        rsi: u64,
+        rdi: u64,
+        rax: u64,
+        rflags: u64,
+        rip: u64,
+        rsp: u64,
+    }
+
+    // SAFETY: the assembly stub calls this by name directly; don't change the name.
+    #[unsafe(no_mangle)]
+    extern "sysv64" fn syscall_dispatch(frame: &mut SyscallFrame) {
+        let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8];
+        frame.rax =
+            crate::api::htmos::dispatch(frame.rax as usize, args.map(|a| a as usize)) as u64;
+        // `sysretq` to a non-canonical RIP faults in ring 0, on the user's stack.  Only a `syscall` in the last bytes
+        // of the lower half returns there; it's a #GP for the program instead.
+        if frame.rip >= 1 << 47 {
+            crate::api::htmos::fault(13, frame.rip as usize);
+        }
+    }
+
+    pub fn init() {
+        let selectors = gdt::selectors();
+        KERNEL_RSP.store(trap_stack_top().as_u64() as usize, Ordering::Relaxed);
+        // SAFETY: `syscall_entry` is ready for whatever ring 3 throws at it.
+        unsafe {
+            Efer::update(|f| f.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
         }
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.code_selector,
+            selectors.data_selector,
+        )
+        .unwrap();
+        LStar::write(VirtAddr::new(
+            syscall_entry as unsafe extern "sysv64" fn() as usize as u64,
+        ));
+        // Interrupts stay off until `syscall_entry` is on the kernel stack.
+        SFMask::write(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::ALIGNMENT_CHECK,
+        );
+    }
 
-        // Send EOI to Local APIC
+    #[cfg(feature = "selftest")]
+    pub fn enter_user(entry: usize, stack: usize) {
+        let selectors = gdt::selectors();
+        // SAFETY: the caller mapped `entry` and `stack` for ring 3; `user_leave` comes back here.
         unsafe {
-            let eoi_ptr = (LAPIC_BASE + 0xB0) as *mut u32;
-            eoi_ptr.write_volatile(0);
+            user_enter(
+                entry,
+                stack,
+                KERNEL_CONTEXT.as_ptr(),
+                selectors.user_code_selector.0,
+                selectors.user_data_selector.0,
+            );
         }
     }
+
+    pub fn leave_user() -> ! {
+        // SAFETY: `enter_user` saved this, and the user code it ran is never going back.
+        unsafe { user_leave(KERNEL_CONTEXT.load(Ordering::Relaxed)) }
+    }
 }
 
-// --- APIC HELPERS ---
-unsafe fn io_apic_write(reg: u32, value: u32) {
-    let ioregsel = IOAPIC_BASE as *mut u32;
-    let iowin = (IOAPIC_BASE + 0x10) as *mut u32;
-    unsafe {
-        ioregsel.write_volatile(reg);
-        iowin.write_volatile(value);
+// --- THREADS MOD ---
+/// Switching kernel threads (see x86_64_switch.s), for `sched`.
+mod threads {
+    use super::*;
+
+    global_asm!(include_str!("./asm_entry_stub/x86_64_switch.s"));
+
+    unsafe extern "sysv64" {
+        fn context_switch(old_sp: *mut usize, new_sp: usize);
+    }
+
+    /// What `context_switch` pops: R15, R14, R13, R12, RBX, RBP, RFLAGS and the address it returns to.  Then a
+    /// return address of 0 for `entry`, which never returns, leaving RSP as a call would.
+    pub fn init_thread_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+        let frame = [0, 0, 0, 0, 0, 0, 0x202, entry as usize, 0];
+        let sp = (top & !0xF) - size_of_val(&frame);
+        // SAFETY: the caller gave us the stack ending at `top`, and nothing runs on it yet.
+        unsafe { (sp as *mut [usize; 9]).write(frame) };
+        sp
+    }
+
+    pub unsafe fn switch_context(old_sp: *mut usize, new_sp: usize) {
+        // SAFETY: up to the caller.
+        unsafe { context_switch(old_sp, new_sp) }
     }
 }
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
+}
 
-    unsafe {
-        // 1. Completely mask the legacy PICs
-        Port::<u8>::new(0x21).write(0xFF);
-        Port::<u8>::new(0xA1).write(0xFF);
-
-        // 2. Enable Local APIC and set Spurious Vector
-        let svr_ptr = (LAPIC_BASE + 0xF0) as *mut u32;
-        svr_ptr.write_volatile(0x100 | (SPURIOUS_VECTOR as u32));
-
-        // 3. Configure IO-APIC for Keyboard (IRQ 1)
-        // Redirection Entry 1 (Registers 0x12/0x13)
-        // Set bit 13 (Polarity: Active Low) because your MADT said so!
-        let low_bits = (1 << 13) | (KEYBOARD_VECTOR as u32);
-        let high_bits = 0 << 24; // Destination: CPU 0
-        io_apic_write(0x12, low_bits);
-        io_apic_write(0x13, high_bits);
+/// Runs ring-3 code from `entry`, on the stack ending at `stack`, until something calls `leave_user`.  BSP only.
+#[cfg(feature = "selftest")]
+pub fn enter_user(entry: usize, stack: usize) {
+    syscall::enter_user(entry, stack);
+}
 
-        // 4. PS/2 Keyboard Command: Enable Scanning
-        let mut cmd_port = Port::<u8>::new(0x64);
-        let mut data_port = Port::<u8>::new(0x60);
+/// Goes back to whoever called `enter_user`, from a system call or an exception that came from user code.
+pub fn leave_user() -> ! {
+    syscall::leave_user()
+}
 
-        while (cmd_port.read() & 0x02) != 0 {}
-        cmd_port.write(0xAE); // Enable Keyboard Port
+/// Lays out a new thread's stack, ending at `top`, for `switch_context` to start it at `entry` with interrupts on.
+/// Returns its stack pointer.
+pub fn init_thread_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+    threads::init_thread_stack(top, entry)
+}
 
-        while (cmd_port.read() & 0x02) != 0 {}
-        data_port.write(0xF4); // Command: Enable Scanning
+/// Saves the running thread's stack pointer to `old_sp` and carries on with the thread `new_sp` belongs to, until
+/// something switches back.
+///
+/// # Safety
+/// `new_sp` has to come from `switch_context` or `init_thread_stack`, on a stack still there, and `old_sp` has to be
+/// valid until this thread runs again.
+pub unsafe fn switch_context(old_sp: *mut usize, new_sp: usize) {
+    // SAFETY: passed on to the caller.
+    unsafe { threads::switch_context(old_sp, new_sp) }
+}
 
-        // Flush buffer to clear the ACK from 0xF4
-        while (cmd_port.read() & 0x01) != 0 {
-            data_port.read();
-        }
-    }
+/// Gives an AP what `init` and `init_apic` gave the BSP: its own GDT and TSS, the IDT and its local APIC.
+pub fn init_ap(double_fault_stack: usize) {
+    gdt::init_ap(double_fault_stack);
+    interrupts::init_idt();
+    apic::init_local(irq::SPURIOUS_VECTOR);
+}
 
+/// Sets up the interrupt controllers (the APICs from the MADT at (virtual address) `madt`, or the PICs without them),
+/// sets up the PS/2 keyboard and mouse, routes their and the serial console's interrupts, and turns interrupts on.
+pub fn init_interrupts(madt: Option<usize>) -> Result<(), &'static str> {
+    irq::init_devices(madt)?;
     x86_64::instructions::interrupts::enable();
-    println!("APIC Initialized. Polarity: Active Low. Vector: 33.");
+    Ok(())
 }
 
-use raw_acpi::madt::{
-    interrupt_source_override::{
-        InterruptSourceOverride, InterruptSourceOverridePolarity,
-        InterruptSourceOverrideTriggerMode,
-    },
-    ioapic::IOAPIC,
-    proc_local_apic::ProcessorLocalAPIC,
-};
-
-pub fn init_madt(madt: usize) {
-    let mut sz_rem = unsafe {
-        let madt = &*(madt as *const raw_acpi::madt::MADT);
-        let ctrl_addr = madt.local_interrupt_controller_address;
-        let pcat_compat = madt.flags;
-        println!("INTERRUPT CONTROLLER ADDRESS: 0x{:08X}", ctrl_addr);
-        println!(
-            "PC-AT-compatible dual-8259 setup: {}",
-            pcat_compat.pcat_compat()
-        );
-
-        madt.header.length as usize - 44
-    };
-
-    let mut ptr = madt + 44;
-
-    while sz_rem > 0 {
-        let length = unsafe { (ptr as *const u8).offset(1).read_volatile() };
-        match unsafe { (ptr as *const u8).read_volatile() } {
-            0x00 => {
-                assert!(length == 8);
-                let info = unsafe { *(ptr as *const ProcessorLocalAPIC) };
-                let acpi_processor_uid = info.acpi_processor_uid;
-                let apic_id = info.acpi_id;
-                let flags = info.flags;
-
-                print!(
-                    "Processor Local APIC Info: APIC ID 0x{apic_id:02X} --- {}",
-                    if flags.enabled() {
-                        "ENABLED"
-                    } else {
-                        "DISABLED"
-                    }
-                );
-                //println!("  ACPI Processor UID: 0x{acpi_processor_uid:02X}");
-                //println!("  APIC ID: 0x{apic_id:02X}");
-                //print!("  Enabled: {}", flags.enabled());
-                if !flags.enabled() {
-                    print!(" (Online Capable: {})", flags.online_capable());
-                }
-                println!();
-            }
-            0x01 => {
-                assert!(length == 12);
-                let info = unsafe { *(ptr as *const IOAPIC) };
-                let io_apic_id = info.io_apic_id;
-                let io_apic_addr = info.io_apic_address;
-                let gsib = info.global_system_interrupt_base;
-
-                println!(
-                    "I/O APIC Info: ID 0x{io_apic_id:02X} AT ADDR 0x{io_apic_addr:08X} --- GLOBAL SYSTEM INTERRUPT BASE: 0x{gsib:08X}"
-                );
-            }
-            0x02 => {
-                assert!(length == 10);
-                let info = unsafe { *(ptr as *const InterruptSourceOverride) };
-                let bus = info.bus;
-                let source = info.source;
-                let gsi = info.global_system_interrupt;
-                let flags = info.flags;
-
-                print!(
-                    "InterruptSourceOverride: Bus 0x{bus:02X}, Source 0x{source:02X} --- Global System Interrupt: 0x{gsi:08X} ("
-                );
-                match flags.polarity() {
-                    InterruptSourceOverridePolarity::Conform => {
-                        print!("Polarity: Conform, ");
-                    }
-                    InterruptSourceOverridePolarity::ActiveHigh => {
-                        print!("Polarity: Active High, ");
-                    }
-                    InterruptSourceOverridePolarity::ActiveLow => {
-                        print!("Polarity: Active Low, ");
-                    }
-                }
-                match flags.trigger_mode() {
-                    InterruptSourceOverrideTriggerMode::Conform => {
-                        print!("Trigger Mode: Conform)");
-                    }
-                    InterruptSourceOverrideTriggerMode::EdgeTriggered => {
-                        print!("Trigger Mode: Edge)");
-                    }
-                    InterruptSourceOverrideTriggerMode::LevelTriggered => {
-                        print!("Trigger Mode: Level)");
-                    }
-                }
-                println!();
-            }
-            _ => {}
-        }
+/// Calibrates the clocks (against the HPET, if `hpet` points at its ACPI table) and starts the tick.
+pub fn init_timer(hpet: Option<usize>) -> Result<(), &'static str> {
+    let clock = crate::time::init(hpet, irq::vector(irq::TIMER_IRQ))?;
+    // Without a local APIC timer, the tick is the PIT's IRQ 0.
+    if clock.lapic_per_ms.is_none() {
+        irq::route(irq::TIMER_IRQ, irq::vector(irq::TIMER_IRQ))?;
+    }
+    println!("CLOCK: {clock}");
+    Ok(())
+}
 
-        ptr += length as usize;
-        sz_rem -= length as usize;
+/// Starts every other CPU.
+pub fn init_smp() -> Result<(), &'static str> {
+    let count = crate::smp::init(TLB_SHOOTDOWN_VECTOR)?;
+    print!("SMP: {count} CPU(s) online, APIC IDs");
+    for cpu in crate::smp::cpus() {
+        print!(" {}", cpu.apic_id);
     }
+    println!();
+    Ok(())
 }
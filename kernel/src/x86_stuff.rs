@@ -0,0 +1,511 @@
+use crate::{irq, kiss, println};
+use core::{
+    arch::{asm, global_asm},
+    fmt,
+};
+
+// --- CONSTANTS ---
+// The hardware vectors are in `irq`, shared with 64-bit.
+const KERNEL_CODE: u16 = 0x08;
+const KERNEL_DATA: u16 = 0x10;
+const TSS_SELECTOR: u16 = 0x18;
+const DOUBLE_FAULT_TSS_SELECTOR: u16 = 0x20;
+#[cfg(feature = "selftest")]
+const USER_CODE: u16 = 0x28 | 3;
+#[cfg(feature = "selftest")]
+const USER_DATA: u16 = 0x30 | 3;
+/// Where user code makes system calls (`int 0x80`).
+const SYSCALL_VECTOR: u8 = 0x80;
+
+/// What `lgdt` and `lidt` take.
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u32,
+}
+
+// --- GDT MOD ---
+/// A flat GDT (code and data over all 4 GiB, for ring 0 and ring 3) with two TSSs: the one the CPU saves into when it
+/// switches tasks (and takes the ring-0 stack from), and the double-fault task's.  A double fault goes through a task
+/// gate rather than an interrupt gate, since a kernel stack overflow is the most likely reason for it, and only a task
+/// switch gets a 32-bit CPU onto a good stack.
+mod gdt {
+    use super::*;
+
+    /// The 32-bit task state segment.  Segment selectors take up the low half of their field.
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    pub struct TaskStateSegment {
+        pub link: u32,
+        pub esp0: u32,
+        pub ss0: u32,
+        pub esp1: u32,
+        pub ss1: u32,
+        pub esp2: u32,
+        pub ss2: u32,
+        pub cr3: u32,
+        pub eip: u32,
+        pub eflags: u32,
+        pub eax: u32,
+        pub ecx: u32,
+        pub edx: u32,
+        pub ebx: u32,
+        pub esp: u32,
+        pub ebp: u32,
+        pub esi: u32,
+        pub edi: u32,
+        pub es: u32,
+        pub cs: u32,
+        pub ss: u32,
+        pub ds: u32,
+        pub fs: u32,
+        pub gs: u32,
+        pub ldt: u32,
+        pub trap: u16,
+        pub iomap_base: u16,
+    }
+    impl TaskStateSegment {
+        const fn new() -> Self {
+            Self {
+                link: 0,
+                esp0: 0,
+                ss0: 0,
+                esp1: 0,
+                ss1: 0,
+                esp2: 0,
+                ss2: 0,
+                cr3: 0,
+                eip: 0,
+                eflags: 0,
+                eax: 0,
+                ecx: 0,
+                edx: 0,
+                ebx: 0,
+                esp: 0,
+                ebp: 0,
+                esi: 0,
+                edi: 0,
+                es: 0,
+                cs: 0,
+                ss: 0,
+                ds: 0,
+                fs: 0,
+                gs: 0,
+                ldt: 0,
+                trap: 0,
+                // Past the end of the segment: no I/O permission bitmap, so no ports for ring 3.
+                iomap_base: size_of::<Self>() as u16,
+            }
+        }
+    }
+
+    pub static mut TSS: TaskStateSegment = TaskStateSegment::new();
+    static mut DOUBLE_FAULT_TSS: TaskStateSegment = TaskStateSegment::new();
+    static mut GDT: [u64; 7] = [
+        0,
+        descriptor(0, 0xFFFFF, 0x9A, 0xC),
+        descriptor(0, 0xFFFFF, 0x92, 0xC),
+        // The TSSs, filled in by `init` (their addresses aren't constant).
+        0,
+        0,
+        descriptor(0, 0xFFFFF, 0xFA, 0xC),
+        descriptor(0, 0xFFFFF, 0xF2, 0xC),
+    ];
+
+    /// Encodes a segment descriptor: `access` is the present bit, DPL and type; `flags` the granularity and size bits.
+    const fn descriptor(base: u32, limit: u32, access: u8, flags: u8) -> u64 {
+        let (base, limit) = (base as u64, limit as u64);
+        (limit & 0xFFFF)
+            | (base & 0xFF_FFFF) << 16
+            | (access as u64) << 40
+            | (limit >> 16 & 0xF) << 48
+            | (flags as u64 & 0xF) << 52
+            | (base >> 24) << 56
+    }
+
+    fn tss_descriptor(tss: *const TaskStateSegment) -> u64 {
+        // Available 32-bit TSS, byte granular.
+        descriptor(
+            tss as u32,
+            size_of::<TaskStateSegment>() as u32 - 1,
+            0x89,
+            0,
+        )
+    }
+
+    pub fn init() {
+        const STACK_SIZE: usize = 4096 * 5;
+        // Aligned to 16 bytes, like any other stack `rustc` runs on.
+        #[repr(align(16))]
+        struct Stack([u8; STACK_SIZE]);
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
+        static mut TRAP_STACK: Stack = Stack([0; STACK_SIZE]);
+
+        // SAFETY: only the BSP runs this, once, before interrupts are on; nothing else has the TSSs or GDT yet.
+        unsafe {
+            let tss = &raw mut TSS;
+            // Where interrupts from ring 3 land.
+            (*tss).esp0 = (&raw const TRAP_STACK.0) as u32 + STACK_SIZE as u32;
+            (*tss).ss0 = KERNEL_DATA as u32;
+
+            let cr3: u32;
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            let df = &raw mut DOUBLE_FAULT_TSS;
+            (*df).cr3 = cr3;
+            (*df).eip = interrupts::double_fault_task as extern "C" fn() -> ! as usize as u32;
+            // The CPU pushes the error code on entry, which leaves ESP where a call would: 4 bytes under alignment.
+            (*df).esp = (&raw const STACK.0) as u32 + STACK_SIZE as u32;
+            (*df).eflags = 0x2;
+            (*df).cs = KERNEL_CODE as u32;
+            (*df).ss = KERNEL_DATA as u32;
+            (*df).ds = KERNEL_DATA as u32;
+            (*df).es = KERNEL_DATA as u32;
+            (*df).fs = KERNEL_DATA as u32;
+            (*df).gs = KERNEL_DATA as u32;
+
+            let gdt = &raw mut GDT;
+            (*gdt)[(TSS_SELECTOR >> 3) as usize] = tss_descriptor(tss);
+            (*gdt)[(DOUBLE_FAULT_TSS_SELECTOR >> 3) as usize] = tss_descriptor(df);
+
+            let pointer = DescriptorTablePointer {
+                limit: size_of::<[u64; 7]>() as u16 - 1,
+                base: gdt as u32,
+            };
+            asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+            // Only a far jump or return reloads CS.
+            asm!(
+                "push {code}",
+                "lea {tmp}, [2f]",
+                "push {tmp}",
+                "retf",
+                "2:",
+                "mov ds, {data:x}",
+                "mov es, {data:x}",
+                "mov fs, {data:x}",
+                "mov gs, {data:x}",
+                "mov ss, {data:x}",
+                code = const KERNEL_CODE,
+                data = in(reg) KERNEL_DATA as u32,
+                tmp = out(reg) _,
+                options(preserves_flags),
+            );
+            asm!("ltr {:x}", in(reg) TSS_SELECTOR, options(nostack, preserves_flags));
+        }
+    }
+}
+
+// --- INTERRUPTS MOD ---
+/// Every exception and IRQ goes through `interrupt_dispatch` with the full register state, so the panic screen shows
+/// what really went wrong.  Reserved vectors (15, 22-27 and 31) are left out of the IDT; if one ever fires, the #GP it
+/// causes names it in its error code.
+mod interrupts {
+    use super::*;
+    use crate::trap::{SelectorError, name};
+
+    global_asm!(include_str!("./asm_entry_stub/x86_exceptions.s"));
+
+    unsafe extern "C" {
+        static interrupt_stubs: u8;
+        static spurious_stub: u8;
+        static syscall_stub: u8;
+    }
+
+    /// Everything the CPU and the entry stub saved, lowest address first.  From ring 3, the CPU also saved the user
+    /// stack (ESP, SS) after it.
+    #[derive(Debug, Clone, Copy)]
+    #[repr(C)]
+    pub struct TrapFrame {
+        pub es: u32,
+        pub ds: u32,
+        pub edi: u32,
+        pub esi: u32,
+        pub ebp: u32,
+        /// What `pushad` saved as ESP; `esp` has the interrupted code's.
+        pub esp_dummy: u32,
+        pub ebx: u32,
+        pub edx: u32,
+        pub ecx: u32,
+        pub eax: u32,
+        pub vector: u32,
+        /// 0 for exceptions that don't push one.
+        pub error_code: u32,
+        pub eip: u32,
+        pub cs: u32,
+        pub eflags: u32,
+    }
+    impl TrapFrame {
+        /// The interrupted code's ESP.  Without a privilege change, the CPU doesn't save it, since it's just past the
+        /// frame.
+        pub fn esp(&self) -> u32 {
+            let end = (self as *const Self).wrapping_add(1) as *const u32;
+            if self.cs & 3 == 3 {
+                // SAFETY: from ring 3, the CPU pushed ESP there.
+                unsafe { end.read() }
+            } else {
+                end as u32
+            }
+        }
+    }
+    impl fmt::Display for TrapFrame {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let regs = [
+                ("EAX", self.eax),
+                ("EBX", self.ebx),
+                ("ECX", self.ecx),
+                ("EDX", self.edx),
+                ("ESI", self.esi),
+                ("EDI", self.edi),
+                ("EBP", self.ebp),
+                ("ESP", self.esp()),
+                ("EIP", self.eip),
+                ("EFL", self.eflags),
+            ];
+            for (i, (name, value)) in regs.iter().enumerate() {
+                write!(f, "{name}=0x{value:08X}")?;
+                f.write_str(if i % 3 == 2 { "\n" } else { "  " })?;
+            }
+            write!(f, "CS=0x{:04X}", self.cs)
+        }
+    }
+
+    static mut IDT: [u64; 256] = [0; 256];
+
+    /// Encodes a gate descriptor: `kind` is the present bit, DPL and gate type.
+    const fn gate(offset: u32, selector: u16, kind: u8) -> u64 {
+        let offset = offset as u64;
+        (offset & 0xFFFF) | (selector as u64) << 16 | (kind as u64) << 40 | (offset >> 16) << 48
+    }
+
+    pub fn init_idt() {
+        const INTERRUPT_GATE: u8 = 0x8E;
+        const TRAP_GATE: u8 = 0x8F;
+        const TASK_GATE: u8 = 0x85;
+        const USER: u8 = 3 << 5;
+
+        // SAFETY: given from the assembly stub.
+        let base = unsafe { &interrupt_stubs as *const u8 as u32 };
+        // SAFETY: only the BSP runs this, once, before interrupts are on.
+        unsafe {
+            let idt = &raw mut IDT;
+            for vector in (0..irq::vector(16)).filter(|v| !matches!(v, 8 | 15 | 22..=27 | 31)) {
+                let kind = match vector {
+                    // Usable from user space (`int3`, `into`).
+                    3 | 4 => INTERRUPT_GATE | USER,
+                    _ => INTERRUPT_GATE,
+                };
+                (*idt)[vector as usize] = gate(base + 16 * vector as u32, KERNEL_CODE, kind);
+            }
+            (*idt)[8] = gate(0, DOUBLE_FAULT_TSS_SELECTOR, TASK_GATE);
+            (*idt)[irq::SPURIOUS_VECTOR as usize] = gate(
+                &spurious_stub as *const u8 as u32,
+                KERNEL_CODE,
+                INTERRUPT_GATE,
+            );
+            // A trap gate, so system calls run with interrupts on.
+            (*idt)[SYSCALL_VECTOR as usize] = gate(
+                &syscall_stub as *const u8 as u32,
+                KERNEL_CODE,
+                TRAP_GATE | USER,
+            );
+
+            let pointer = DescriptorTablePointer {
+                limit: size_of::<[u64; 256]>() as u16 - 1,
+                base: idt as u32,
+            };
+            asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+        }
+    }
+
+    // SAFETY: the assembly stub calls this by name directly; don't change the name.
+    #[unsafe(no_mangle)]
+    extern "cdecl" fn interrupt_dispatch(frame: &mut TrapFrame) {
+        match frame.vector {
+            3 => println!("EXCEPTION: BREAKPOINT at 0x{:08X}", frame.eip),
+            0..32 => exception(frame),
+            // The call's number comes in EAX, and its result goes out there.
+            v if v == SYSCALL_VECTOR as u32 => {
+                let args = [frame.ebx, frame.ecx, frame.edx, frame.esi, frame.edi];
+                frame.eax =
+                    crate::api::htmos::dispatch(frame.eax as usize, args.map(|a| a as usize))
+                        as u32;
+            }
+            vector => irq::handle((vector - irq::PIC_BASE as u32) as u8),
+        }
+    }
+
+    fn exception(frame: &TrapFrame) -> ! {
+        // A user program's exception ends the program, not the kernel.
+        if frame.cs & 3 == 3 {
+            crate::api::htmos::fault(frame.vector as u8, frame.eip as usize);
+        }
+
+        kiss::set_krnl_err(0x20 + frame.vector as u8);
+        let (name, mnemonic) = name(frame.vector as u64);
+
+        match frame.vector {
+            10..=13 => panic!(
+                "EXCEPTION: {name} ({mnemonic})\nError code: 0x{:X} ({})\n{frame}",
+                frame.error_code,
+                SelectorError(frame.error_code as u64)
+            ),
+            14 => {
+                let cr2: u32;
+                // SAFETY: reading CR2 has no side effects.
+                unsafe {
+                    asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags))
+                };
+                panic!(
+                    "EXCEPTION: {name} ({mnemonic}) at 0x{cr2:08X}\nError code: 0x{:X}\n{frame}",
+                    frame.error_code
+                )
+            }
+            17 | 21 | 29 | 30 => panic!(
+                "EXCEPTION: {name} ({mnemonic})\nError code: 0x{:X}\n{frame}",
+                frame.error_code
+            ),
+            _ => panic!("EXCEPTION: {name} ({mnemonic})\n{frame}"),
+        }
+    }
+
+    /// Where the double-fault task starts, on its own stack.  The task switch saved what faulted in the main TSS.
+    pub extern "C" fn double_fault_task() -> ! {
+        kiss::set_krnl_err(0x28);
+        // SAFETY: the faulting task can't run again; nothing else touches its TSS.
+        let tss = unsafe { (&raw const gdt::TSS).read() };
+        panic!(
+            "EXCEPTION: DOUBLE FAULT (#DF)\n\
+             EIP=0x{:08X}  ESP=0x{:08X}  EFL=0x{:08X}\n\
+             EAX=0x{:08X}  EBX=0x{:08X}  ECX=0x{:08X}\n\
+             EDX=0x{:08X}  ESI=0x{:08X}  EDI=0x{:08X}\n\
+             EBP=0x{:08X}",
+            tss.eip,
+            tss.esp,
+            tss.eflags,
+            tss.eax,
+            tss.ebx,
+            tss.ecx,
+            tss.edx,
+            tss.esi,
+            tss.edi,
+            tss.ebp
+        );
+    }
+}
+
+// --- USER MOD ---
+/// Into ring 3 and back (see x86_syscall.s).  Only the BSP runs user code.
+mod user {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    global_asm!(include_str!("./asm_entry_stub/x86_syscall.s"));
+
+    unsafe extern "cdecl" {
+        #[cfg(feature = "selftest")]
+        fn user_enter(entry: usize, stack: usize, kernel_esp: *mut usize, code: u32, data: u32);
+        fn user_leave(kernel_esp: usize) -> !;
+    }
+
+    /// The kernel's stack pointer while user code runs, for `leave_user` to go back to.
+    static KERNEL_CONTEXT: AtomicUsize = AtomicUsize::new(0);
+
+    #[cfg(feature = "selftest")]
+    pub fn enter_user(entry: usize, stack: usize) {
+        // SAFETY: the caller gave `entry` and `stack` to ring 3; `user_leave` comes back here.
+        unsafe {
+            user_enter(
+                entry,
+                stack,
+                KERNEL_CONTEXT.as_ptr(),
+                USER_CODE as u32,
+                USER_DATA as u32,
+            );
+        }
+    }
+
+    pub fn leave_user() -> ! {
+        // SAFETY: `enter_user` saved this, and the user code it ran is never going back.
+        unsafe { user_leave(KERNEL_CONTEXT.load(Ordering::Relaxed)) }
+    }
+}
+
+// --- THREADS MOD ---
+/// Switching kernel threads (see x86_switch.s), for `sched`.
+mod threads {
+    use super::*;
+
+    global_asm!(include_str!("./asm_entry_stub/x86_switch.s"));
+
+    unsafe extern "cdecl" {
+        fn context_switch(old_sp: *mut usize, new_sp: usize);
+    }
+
+    /// What `context_switch` pops: EDI, ESI, EBX, EBP, EFLAGS and the address it returns to.  Then a return address of
+    /// 0 for `entry`, which never returns, leaving ESP as a call would.
+    pub fn init_thread_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+        let frame = [0, 0, 0, 0, 0x202, entry as usize, 0];
+        let sp = (top & !0xF) - size_of_val(&frame);
+        // SAFETY: the caller gave us the stack ending at `top`, and nothing runs on it yet.
+        unsafe { (sp as *mut [usize; 7]).write(frame) };
+        sp
+    }
+
+    pub unsafe fn switch_context(old_sp: *mut usize, new_sp: usize) {
+        // SAFETY: up to the caller.
+        unsafe { context_switch(old_sp, new_sp) }
+    }
+}
+
+pub fn init() {
+    gdt::init();
+    interrupts::init_idt();
+}
+
+/// Runs ring-3 code from `entry`, on the stack ending at `stack`, until something calls `leave_user`.  BSP only.
+#[cfg(feature = "selftest")]
+pub fn enter_user(entry: usize, stack: usize) {
+    user::enter_user(entry, stack);
+}
+
+/// Goes back to whoever called `enter_user`, from a system call or an exception that came from user code.
+pub fn leave_user() -> ! {
+    user::leave_user()
+}
+
+/// Lays out a new thread's stack, ending at `top`, for `switch_context` to start it at `entry` with interrupts on.
+/// Returns its stack pointer.
+pub fn init_thread_stack(top: usize, entry: extern "C" fn() -> !) -> usize {
+    threads::init_thread_stack(top, entry)
+}
+
+/// Saves the running thread's stack pointer to `old_sp` and carries on with the thread `new_sp` belongs to, until
+/// something switches back.
+///
+/// # Safety
+/// `new_sp` has to come from `switch_context` or `init_thread_stack`, on a stack still there, and `old_sp` has to be
+/// valid until this thread runs again.
+pub unsafe fn switch_context(old_sp: *mut usize, new_sp: usize) {
+    // SAFETY: passed on to the caller.
+    unsafe { threads::switch_context(old_sp, new_sp) }
+}
+
+/// Sets up the interrupt controllers (the APICs from the MADT at `madt`, or the PICs without them), sets up the PS/2
+/// keyboard and mouse, routes their and the serial console's interrupts, and turns interrupts on.
+pub fn init_interrupts(madt: Option<usize>) -> Result<(), &'static str> {
+    irq::init_devices(madt)?;
+    // SAFETY: the IDT is loaded, and every routed vector has a handler.
+    unsafe { asm!("sti", options(nomem, nostack)) };
+    Ok(())
+}
+
+/// Calibrates the clocks and starts the tick.
+pub fn init_timer() -> Result<(), &'static str> {
+    // No HPET: its counter is 64 bits, which 32-bit code can't read in one go.
+    let clock = crate::time::init(None, irq::vector(irq::TIMER_IRQ))?;
+    // Without a local APIC timer, the tick is the PIT's IRQ 0.
+    if clock.lapic_per_ms.is_none() {
+        irq::route(irq::TIMER_IRQ, irq::vector(irq::TIMER_IRQ))?;
+    }
+    println!("CLOCK: {clock}");
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! **HyperText Markup Memory Regions**
+//!
+//! Memory sections are `(start, size)` tuples kept in a fixed-size array next to the number of sections in use,
+//! the same shape the kernel's `get_mmap` hands out.  This lives outside of the kernel so it can be tested on the host.
+
+#![no_std]
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MemoryPattern {
+    /// No overlapping
+    Separate,
+    /// Size changes, start point remains to original section
+    StartBranch,
+    /// Size changes, start point changes to given section
+    EndBranch,
+    /// Original section overlaps entire given section
+    NoChange,
+    /// Given section overlaps entire original section
+    Overwrite,
+}
+
+pub const fn end(start: usize, size: usize) -> usize {
+    start + size
+}
+pub const fn endt(tuple: (usize, usize)) -> usize {
+    tuple.0 + tuple.1
+}
+
+/// Start branch means first (original) set starts first.
+pub const fn cmp_mem_sct(
+    original_start: usize,
+    original_size: usize,
+    given_start: usize,
+    given_size: usize,
+) -> MemoryPattern {
+    if original_start == given_start && original_size == given_size {
+        return MemoryPattern::NoChange;
+    }
+
+    let original_end = end(original_start, original_size);
+    let given_end = end(given_start, given_size);
+
+    if original_end < given_start || given_end < original_start {
+        MemoryPattern::Separate
+    } else if given_start == original_end {
+        MemoryPattern::StartBranch
+    } else if original_start == given_end {
+        MemoryPattern::EndBranch
+    } else if given_start >= original_start {
+        if given_end > original_end {
+            if original_start == given_start {
+                MemoryPattern::Overwrite
+            } else {
+                MemoryPattern::StartBranch
+            }
+        } else {
+            MemoryPattern::NoChange
+        }
+    } else if given_end <= original_end {
+        if original_end == given_end {
+            MemoryPattern::Overwrite
+        } else {
+            MemoryPattern::EndBranch
+        }
+    } else {
+        MemoryPattern::Overwrite
+    }
+}
+
+/// A fixed-capacity list of memory sections.
+///
+/// After any call, the sections in use are sorted by start address and none of them are empty, overlapping or touching.
+///
+/// The editing functions return false when the list runs out of room.  Neither of them will ever report memory that
+/// wasn't given: `glue_section` leaves the list as it was, and `rip_section` still removes the whole range but drops
+/// the smaller leftover of the section it had to split.
+pub trait MemoryGlue {
+    /// Adds the given section, merging it with every section it overlaps or touches.
+    fn glue_section(&mut self, start: usize, size: usize) -> bool;
+    /// Removes the given section, shrinking or splitting every section it overlaps.
+    fn rip_section(&mut self, start: usize, size: usize) -> bool;
+    /// Sorts the list and merges overlapping or touching sections.  Returns true if changes happened.
+    fn organize(&mut self) -> bool;
+    /// The sections currently in use.
+    fn sections(&self) -> &[(usize, usize)];
+}
+impl<const T: usize> MemoryGlue for ([(usize, usize); T], usize) {
+    fn glue_section(&mut self, start: usize, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        self.organize();
+
+        let (arr, sz) = self;
+        let given_end = start.saturating_add(size);
+
+        // Every section in lo..hi overlaps or touches the given one.
+        let lo = arr[..*sz].partition_point(|&s| endt(s) < start);
+        let hi = arr[..*sz].partition_point(|&(s, _)| s <= given_end);
+
+        if lo == hi {
+            if *sz == T {
+                return false;
+            }
+            arr.copy_within(lo..*sz, lo + 1);
+            arr[lo] = (start, given_end - start);
+            *sz += 1;
+            return true;
+        }
+
+        let new_start = arr[lo].0.min(start);
+        let new_end = endt(arr[hi - 1]).max(given_end);
+        arr[lo] = (new_start, new_end - new_start);
+        replace_range(arr, sz, lo + 1, hi, &[]);
+        true
+    }
+    fn rip_section(&mut self, start: usize, size: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        self.organize();
+
+        let (arr, sz) = self;
+        let given_end = start.saturating_add(size);
+
+        // Every section in lo..hi shares at least one byte with the given one.
+        let lo = arr[..*sz].partition_point(|&s| endt(s) <= start);
+        let hi = arr[..*sz].partition_point(|&(s, _)| s < given_end);
+        if lo == hi {
+            return true;
+        }
+
+        let left = (arr[lo].0, start.saturating_sub(arr[lo].0));
+        let right = (given_end, endt(arr[hi - 1]).saturating_sub(given_end));
+
+        let mut ok = true;
+        let mut keep = [(0, 0); 2];
+        let mut n = 0;
+        for piece in [left, right] {
+            if piece.1 > 0 {
+                keep[n] = piece;
+                n += 1;
+            }
+        }
+        // Splitting a single section needs a new slot.
+        if n == 2 && hi - lo == 1 && *sz == T {
+            ok = false;
+            if left.1 < right.1 {
+                keep[0] = right;
+            }
+            n = 1;
+        }
+
+        replace_range(arr, sz, lo, hi, &keep[..n]);
+        ok
+    }
+    fn organize(&mut self) -> bool {
+        let (arr, sz) = self;
+        let mut ret = false;
+
+        if !arr[..*sz].is_sorted_by(|(v1, _), (v2, _)| v1 <= v2) {
+            arr[..*sz].sort_unstable_by_key(|&(v1, _)| v1);
+            ret = true;
+        }
+
+        let mut out = 0;
+        for i in 0..*sz {
+            let cur = arr[i];
+            if cur.1 == 0 {
+                ret = true;
+            } else if out > 0 && endt(arr[out - 1]) >= cur.0 {
+                let merged_end = endt(arr[out - 1]).max(endt(cur));
+                arr[out - 1].1 = merged_end - arr[out - 1].0;
+                ret = true;
+            } else {
+                arr[out] = cur;
+                out += 1;
+            }
+        }
+        arr[out..*sz].fill((0, 0));
+        *sz = out;
+
+        ret
+    }
+    fn sections(&self) -> &[(usize, usize)] {
+        &self.0[..self.1]
+    }
+}
+
+/// Replaces `arr[from..to]` with `with`, shifting the tail.  The caller makes sure everything fits.
+fn replace_range(
+    arr: &mut [(usize, usize)],
+    sz: &mut usize,
+    from: usize,
+    to: usize,
+    with: &[(usize, usize)],
+) {
+    let new_sz = *sz - (to - from) + with.len();
+    arr.copy_within(to..*sz, from + with.len());
+    arr[from..from + with.len()].copy_from_slice(with);
+    if new_sz < *sz {
+        arr[new_sz..*sz].fill((0, 0));
+    }
+    *sz = new_sz;
+}
@@ -0,0 +1,120 @@
+//! Checks the memory section list against a byte-per-address reference model.
+
+use htmos_mem_region::{MemoryGlue, MemoryPattern, cmp_mem_sct};
+use proptest::prelude::*;
+
+const SPACE: usize = 512;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Glue(usize, usize),
+    Rip(usize, usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    (any::<bool>(), 0..SPACE, 0..SPACE / 4).prop_map(|(glue, start, size)| {
+        let size = size.min(SPACE - start);
+        if glue {
+            Op::Glue(start, size)
+        } else {
+            Op::Rip(start, size)
+        }
+    })
+}
+
+fn covered<const T: usize>(m: &([(usize, usize); T], usize)) -> Vec<bool> {
+    let mut v = vec![false; SPACE];
+    for &(s, l) in m.sections() {
+        v[s..s + l].fill(true);
+    }
+    v
+}
+
+fn assert_normalized<const T: usize>(m: &([(usize, usize); T], usize)) {
+    let s = m.sections();
+    assert!(s.iter().all(|&(_, l)| l > 0), "empty section in {s:?}");
+    assert!(
+        s.windows(2).all(|w| w[0].0 + w[0].1 < w[1].0),
+        "unsorted, overlapping or touching sections in {s:?}"
+    );
+    assert!(
+        m.0[m.1..].iter().all(|&x| x == (0, 0)),
+        "stale tail entries"
+    );
+}
+
+fn run<const T: usize>(ops: &[Op]) {
+    let mut m: ([(usize, usize); T], usize) = ([(0, 0); T], 0);
+    let mut model = vec![false; SPACE];
+
+    for op in ops {
+        match *op {
+            Op::Glue(s, l) => {
+                if m.glue_section(s, l) {
+                    model[s..s + l].fill(true);
+                } else {
+                    // Only refused when a brand new section was needed and the list was full.
+                    assert_eq!(m.1, T);
+                }
+            }
+            Op::Rip(s, l) => {
+                model[s..s + l].fill(false);
+                if !m.rip_section(s, l) {
+                    // Lost a leftover piece: the map may only under-report.
+                    let got = covered(&m);
+                    assert!(got.iter().zip(&model).all(|(&g, &w)| !g || w));
+                    model = got;
+                }
+            }
+        }
+        assert_normalized(&m);
+        assert_eq!(covered(&m), model, "after {op:?}");
+    }
+}
+
+fn reference_pattern(os: usize, ol: usize, gs: usize, gl: usize) -> MemoryPattern {
+    let (oe, ge) = (os + ol, gs + gl);
+    if (os, ol) == (gs, gl) || (gs >= os && ge <= oe) {
+        MemoryPattern::NoChange
+    } else if oe < gs || ge < os {
+        MemoryPattern::Separate
+    } else if gs <= os && ge >= oe {
+        MemoryPattern::Overwrite
+    } else if gs > os {
+        MemoryPattern::StartBranch
+    } else {
+        MemoryPattern::EndBranch
+    }
+}
+
+proptest! {
+    #[test]
+    fn matches_model_unbounded(ops in prop::collection::vec(op(), 0..64)) {
+        run::<256>(&ops);
+    }
+
+    #[test]
+    fn matches_model_at_capacity(ops in prop::collection::vec(op(), 0..64)) {
+        run::<4>(&ops);
+    }
+
+    #[test]
+    fn organize_is_idempotent(raw in prop::collection::vec((0..SPACE, 0..SPACE / 4), 0..16)) {
+        let mut m = ([(0, 0); 16], raw.len());
+        let mut model = vec![false; SPACE];
+        for (i, &(s, l)) in raw.iter().enumerate() {
+            let l = l.min(SPACE - s);
+            m.0[i] = (s, l);
+            model[s..s + l].fill(true);
+        }
+        m.organize();
+        assert_normalized(&m);
+        prop_assert_eq!(covered(&m), model);
+        prop_assert!(!m.organize());
+    }
+
+    #[test]
+    fn cmp_matches_reference(os in 0..SPACE, ol in 1..SPACE, gs in 0..SPACE, gl in 1..SPACE) {
+        prop_assert_eq!(cmp_mem_sct(os, ol, gs, gl), reference_pattern(os, ol, gs, gl));
+    }
+}
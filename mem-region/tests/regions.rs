@@ -0,0 +1,121 @@
+use htmos_mem_region::{MemoryGlue, MemoryPattern, cmp_mem_sct};
+
+type Map = ([(usize, usize); 256], usize);
+
+fn map(sections: &[(usize, usize)]) -> Map {
+    let mut m: Map = ([(0, 0); 256], 0);
+    for &(s, l) in sections {
+        assert!(m.glue_section(s, l));
+    }
+    m
+}
+
+#[test]
+fn cmp_patterns() {
+    assert_eq!(cmp_mem_sct(0, 10, 20, 10), MemoryPattern::Separate);
+    assert_eq!(cmp_mem_sct(0, 10, 10, 10), MemoryPattern::StartBranch);
+    assert_eq!(cmp_mem_sct(0, 10, 5, 10), MemoryPattern::StartBranch);
+    assert_eq!(cmp_mem_sct(10, 10, 0, 10), MemoryPattern::EndBranch);
+    assert_eq!(cmp_mem_sct(10, 10, 5, 10), MemoryPattern::EndBranch);
+    assert_eq!(cmp_mem_sct(0, 10, 2, 5), MemoryPattern::NoChange);
+    assert_eq!(cmp_mem_sct(0, 10, 0, 10), MemoryPattern::NoChange);
+    assert_eq!(cmp_mem_sct(2, 5, 0, 10), MemoryPattern::Overwrite);
+    assert_eq!(cmp_mem_sct(0, 5, 0, 10), MemoryPattern::Overwrite);
+    assert_eq!(cmp_mem_sct(5, 5, 0, 10), MemoryPattern::Overwrite);
+}
+
+#[test]
+fn glue_merges_overlapping_and_touching() {
+    let m = map(&[(0x1000, 0x1000), (0x2000, 0x1000)]);
+    assert_eq!(m.sections(), &[(0x1000, 0x2000)]);
+
+    let m = map(&[(0x1000, 0x1000), (0x5000, 0x1000), (0x3000, 0x1000)]);
+    assert_eq!(
+        m.sections(),
+        &[(0x1000, 0x1000), (0x3000, 0x1000), (0x5000, 0x1000)]
+    );
+
+    // The old StartBranch arithmetic divided the gap by 4096 here.
+    let m = map(&[(0x1000, 0x1000), (0x9000, 0x1000), (0x1800, 0x2000)]);
+    assert_eq!(m.sections(), &[(0x1000, 0x2800), (0x9000, 0x1000)]);
+
+    // One section bridging several.
+    let m = map(&[(0, 0x10), (0x20, 0x10), (0x40, 0x10), (0x8, 0x40)]);
+    assert_eq!(m.sections(), &[(0, 0x50)]);
+}
+
+#[test]
+fn glue_ignores_empty_and_contained() {
+    let m = map(&[(0x1000, 0x1000), (0x1200, 0x100), (0x4000, 0)]);
+    assert_eq!(m.sections(), &[(0x1000, 0x1000)]);
+}
+
+#[test]
+fn rip_shrinks_and_splits() {
+    let mut m = map(&[(0x1000, 0x3000)]);
+    assert!(m.rip_section(0x1000, 0x1000));
+    assert_eq!(m.sections(), &[(0x2000, 0x2000)]);
+
+    assert!(m.rip_section(0x3800, 0x1000));
+    assert_eq!(m.sections(), &[(0x2000, 0x1800)]);
+
+    assert!(m.rip_section(0x2800, 0x100));
+    assert_eq!(m.sections(), &[(0x2000, 0x800), (0x2900, 0xF00)]);
+
+    assert!(m.rip_section(0x2000, 0x800));
+    assert_eq!(m.sections(), &[(0x2900, 0xF00)]);
+
+    assert!(m.rip_section(0x9000, 0x1000));
+    assert_eq!(m.sections(), &[(0x2900, 0xF00)]);
+}
+
+#[test]
+fn rip_across_several_sections() {
+    let mut m = map(&[(0, 0x10), (0x20, 0x10), (0x40, 0x10), (0x60, 0x10)]);
+    assert!(m.rip_section(0x8, 0x40));
+    assert_eq!(m.sections(), &[(0, 0x8), (0x48, 0x8), (0x60, 0x10)]);
+
+    assert!(m.rip_section(0, 0x100));
+    assert_eq!(m.sections(), &[]);
+}
+
+#[test]
+fn organize_cleans_up_raw_input() {
+    let mut m: Map = ([(0, 0); 256], 5);
+    m.0[..5].copy_from_slice(&[
+        (0x30, 0x10),
+        (0x0, 0x10),
+        (0x10, 0x8),
+        (0x50, 0),
+        (0x34, 0x20),
+    ]);
+    assert!(m.organize());
+    assert_eq!(m.sections(), &[(0x0, 0x18), (0x30, 0x24)]);
+    assert!(m.0[2..].iter().all(|&s| s == (0, 0)));
+    assert!(!m.organize());
+}
+
+#[test]
+fn capacity_limit() {
+    let mut m: Map = ([(0, 0); 256], 0);
+    for i in 0..256 {
+        assert!(m.glue_section(i * 0x20, 0x10));
+    }
+    assert_eq!(m.1, 256);
+
+    // No room for another separate section: nothing changes.
+    let before = m;
+    assert!(!m.glue_section(0x10000, 0x10));
+    assert_eq!(m, before);
+
+    // Merging still works when full.
+    assert!(m.glue_section(0x10, 0x10));
+    assert_eq!(m.1, 255);
+    assert!(m.glue_section(0x10000, 0x10));
+    assert_eq!(m.1, 256);
+
+    // Splitting when full keeps the bigger piece and still removes the range.
+    assert!(!m.rip_section(0x4, 0x4));
+    assert_eq!(m.sections()[0], (0x8, 0x28));
+    assert_eq!(m.1, 256);
+}